@@ -5,9 +5,12 @@ mod lexer;
 mod parser;
 mod token;
 
+use std::rc::Rc;
+
 use super::error;
 use super::Instr;
 use super::Result;
+use super::MULTRET;
 
 use token::Token;
 use token::TokenType;
@@ -16,10 +19,22 @@ use token::TokenType;
 pub(super) struct Chunk {
     pub(super) code: Vec<Instr>,
     pub(super) number_literals: Vec<f64>,
-    pub(super) string_literals: Vec<String>,
+    pub(super) integer_literals: Vec<i64>,
+    pub(super) string_literals: Vec<Vec<u8>>,
     pub(super) num_params: u8,
+    pub(super) is_vararg: bool,
     pub(super) num_locals: u8,
-    pub(super) nested: Vec<Chunk>,
+    pub(super) nested: Vec<Rc<Chunk>>,
+    pub(super) upvalues: Vec<UpvalueDesc>,
+}
+
+/// Describes where a closure finds one of its upvalues when it is created.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum UpvalueDesc {
+    /// A local variable of the enclosing function, and its slot.
+    Local(u8),
+    /// One of the enclosing function's own upvalues, and its index.
+    Upvalue(u8),
 }
 
 pub(super) fn parse_str(source: impl AsRef<str>) -> Result<Chunk> {
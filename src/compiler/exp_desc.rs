@@ -1,6 +1,9 @@
 #[derive(Debug)]
 pub(super) enum ExpDesc {
     Prefix(PrefixExp),
+    /// The `...` expression. The `VarArg` instruction has already been
+    /// emitted, and is the last instruction so far.
+    VarArg,
     Other,
 }
 
@@ -10,8 +13,9 @@ pub(super) enum ExpDesc {
 pub(super) enum PrefixExp {
     /// One of the variants of `PlaceExp`
     Place(PlaceExp),
-    /// A function call, and the number of arguments
-    FunctionCall(u8),
+    /// A function call. The `Call` instruction has already been emitted, and
+    /// is the last instruction so far.
+    FunctionCall,
     /// An expression wrapped in parentheses
     Parenthesized,
 }
@@ -22,6 +26,9 @@ pub(super) enum PrefixExp {
 pub(super) enum PlaceExp {
     /// A local variable, and its index in the list of locals
     Local(u8),
+    /// A local variable of an enclosing function, and its index in the list
+    /// of upvalues
+    Upvalue(u8),
     /// A global variable, and its index in the list of string literals
     Global(u8),
     /// A table index, with `[` and `]`
@@ -6,6 +6,7 @@ use super::Result;
 use super::Token;
 use super::TokenType::{self, *};
 
+use std::borrow::Cow;
 use std::iter::Peekable;
 use std::slice::SliceIndex;
 use std::str::CharIndices;
@@ -16,6 +17,8 @@ use std::str::CharIndices;
 pub(super) struct TokenStream<'a> {
     lexer: Lexer<'a>,
     lookahead: Option<Token>,
+    /// The token after `lookahead`, only filled by `peek_type2`.
+    lookahead2: Option<Token>,
 }
 
 /// A `Lexer` handles the raw conversion of characters to tokens.
@@ -35,13 +38,17 @@ impl<'a> TokenStream<'a> {
         TokenStream {
             lexer: Lexer::new(source),
             lookahead: None,
+            lookahead2: None,
         }
     }
 
     /// Returns the next `Token`.
     pub(super) fn next(&mut self) -> Result<Token> {
         match self.lookahead.take() {
-            Some(token) => Ok(token),
+            Some(token) => {
+                self.lookahead = self.lookahead2.take();
+                Ok(token)
+            }
             None => self.lexer.next_token(),
         }
     }
@@ -59,6 +66,15 @@ impl<'a> TokenStream<'a> {
         Ok(self.peek()?.typ)
     }
 
+    /// Returns the type of the token after the next one.
+    pub(super) fn peek_type2(&mut self) -> Result<TokenType> {
+        self.peek()?;
+        if self.lookahead2.is_none() {
+            self.lookahead2 = Some(self.lexer.next_token()?);
+        }
+        Ok(self.lookahead2.as_ref().unwrap().typ)
+    }
+
     /// Returns whether the next token is of the given type.
     pub(super) fn check_type(&mut self, expected_type: TokenType) -> Result<bool> {
         Ok(self.peek_type()? == expected_type)
@@ -88,7 +104,7 @@ impl<'a> TokenStream<'a> {
     }
 
     /// Returns a substring from the source code.
-    pub(super) fn from_src(&self, index: impl SliceIndex<str, Output = str>) -> &'a str {
+    pub(super) fn source_slice(&self, index: impl SliceIndex<str, Output = str>) -> &'a str {
         &self.lexer.source[index]
     }
 }
@@ -113,12 +129,26 @@ impl Lexer<'_> {
             let tok_type = match first_char {
                 '+' => Plus,
                 '*' => Star,
-                '/' => Slash,
+                '/' => {
+                    if self.try_next('/') {
+                        DoubleSlash
+                    } else {
+                        Slash
+                    }
+                }
                 '%' => Mod,
                 '^' => Caret,
                 '#' => Hash,
+                '&' => Ampersand,
+                '|' => Pipe,
                 ';' => Semi,
-                ':' => Colon,
+                ':' => {
+                    if self.try_next(':') {
+                        DoubleColon
+                    } else {
+                        Colon
+                    }
+                }
                 ',' => Comma,
                 '(' => LParen,
                 ')' => RParen,
@@ -140,13 +170,13 @@ impl Lexer<'_> {
 
                 '\'' => self.lex_string(true, tok_start)?,
                 '\"' => self.lex_string(false, tok_start)?,
-                '[' => {
-                    if let Some('=') | Some('[') = self.peek_char() {
-                        panic!("Long strings are not supported yet.");
-                    } else {
-                        LSquare
+                '[' => match self.long_bracket_level() {
+                    Some(level) => self.lex_long_string(level)?,
+                    None if self.peek_char() == Some('=') => {
+                        return Err(self.error(SyntaxError::InvalidLongStringDelimiter));
                     }
-                }
+                    None => LSquare,
+                },
 
                 _ if first_char.is_ascii_digit() => self.lex_full_number(tok_start, first_char)?,
 
@@ -170,7 +200,15 @@ impl Lexer<'_> {
 
     /// Skips over the characters in a comment.
     fn comment(&mut self) -> Result<Token> {
-        // TODO multi-line comments
+        if self.try_next('[') {
+            if let Some(level) = self.long_bracket_level() {
+                return if self.skip_long_bracket(level) {
+                    self.next_token()
+                } else {
+                    Err(self.error(SyntaxError::UnclosedLongComment))
+                };
+            }
+        }
         while let Some(c) = self.next_char() {
             if c == '\n' {
                 return self.next_token();
@@ -227,7 +265,12 @@ impl Lexer<'_> {
 
     /// Constructs an error of the given kind at the current position.
     fn error(&self, kind: SyntaxError) -> Error {
-        let (line_num, column) = self.line_and_col(self.pos);
+        self.error_at(kind, self.pos)
+    }
+
+    /// Constructs an error of the given kind at the given position.
+    fn error_at(&self, kind: SyntaxError, pos: usize) -> Error {
+        let (line_num, column) = self.line_and_col(pos);
         Error::new(kind, line_num, column)
     }
 
@@ -271,9 +314,11 @@ impl Lexer<'_> {
         } else {
             match first_char {
                 '=' => Ok(Assign),
+                '<' if self.try_next('<') => Ok(ShiftLeft),
+                '>' if self.try_next('>') => Ok(ShiftRight),
                 '<' => Ok(Less),
                 '>' => Ok(Greater),
-                '~' => Err(self.error(SyntaxError::InvalidCharacter)),
+                '~' => Ok(Tilde),
                 _ => panic!("peek_equals was called with first_char = {}", first_char),
             }
         }
@@ -281,15 +326,31 @@ impl Lexer<'_> {
 
     /// Tokenizes a 'short' literal string, AKA a string denoted by single or
     /// double quotes and not by two square brackets.
-    fn lex_string(&mut self, is_single_quotes: bool, _tok_start: usize) -> Result<TokenType> {
+    fn lex_string(&mut self, is_single_quotes: bool, tok_start: usize) -> Result<TokenType> {
         while let Some(c) = self.next_char() {
             if (is_single_quotes && c == '\'') || (!is_single_quotes && c == '\"') {
-                return Ok(LiteralString);
+                // Check the escape sequences now, so that errors have the
+                // right position.
+                let contents = &self.source[tok_start + 1..self.pos - 1];
+                return match unescape(contents) {
+                    Ok(_) => Ok(LiteralString),
+                    Err((kind, offset)) => Err(self.error_at(kind, tok_start + 1 + offset)),
+                };
             } else if c == '\\' {
-                // TODO make backslash-escapes actually work. For now, we just
-                // ignore the next character, which is the correct behavior for
-                // newlines and quotes, but not escapes like '\n'.
-                self.next_char();
+                // Only find where the escape sequence ends. It is checked
+                // once the whole string has been read.
+                match self.next_char() {
+                    Some('z') => {
+                        self.consume_whitespace();
+                    }
+                    Some('\r') => {
+                        self.try_next('\n');
+                    }
+                    Some('\n') => {
+                        self.try_next('\r');
+                    }
+                    _ => (),
+                }
             } else if c == '\n' {
                 return Err(self.error(SyntaxError::UnclosedString));
             }
@@ -298,6 +359,49 @@ impl Lexer<'_> {
         Err(self.error(SyntaxError::UnclosedString))
     }
 
+    /// The lexer just read a `[`. If it is followed by any number of `=` and
+    /// another `[`, they are consumed, and the number of `=` is returned as
+    /// the level of the long bracket.
+    fn long_bracket_level(&mut self) -> Option<usize> {
+        let rest = &self.source[self.pos..];
+        let level = rest.bytes().take_while(|&b| b == b'=').count();
+        if rest[level..].starts_with('[') {
+            for _ in 0..=level {
+                self.next_char();
+            }
+            Some(level)
+        } else {
+            None
+        }
+    }
+
+    /// Skips characters until the closing long bracket of the given level.
+    /// Returns `false` if the end of the source is reached first.
+    fn skip_long_bracket(&mut self, level: usize) -> bool {
+        while let Some(c) = self.next_char() {
+            if c == ']' {
+                let rest = &self.source[self.pos..];
+                let num_equals = rest.bytes().take_while(|&b| b == b'=').count();
+                if num_equals == level && rest[level..].starts_with(']') {
+                    for _ in 0..=level {
+                        self.next_char();
+                    }
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Tokenizes a long string, after its opening long bracket.
+    fn lex_long_string(&mut self, level: usize) -> Result<TokenType> {
+        if self.skip_long_bracket(level) {
+            Ok(LiteralString)
+        } else {
+            Err(self.error(SyntaxError::UnclosedLongString))
+        }
+    }
+
     /// Reads in a number which starts with a digit (as opposed to a decimal point).
     fn lex_full_number(&mut self, tok_start: usize, first_char: char) -> Result<TokenType> {
         // Check for hex values
@@ -414,6 +518,157 @@ impl Lexer<'_> {
     }
 }
 
+/// Converts the contents of a short literal string, without its quotes, into
+/// the string it represents, by replacing every escape sequence. On failure,
+/// returns the error along with the byte offset in `raw` where it occurred.
+pub(super) fn unescape(raw: &str) -> std::result::Result<Vec<u8>, (SyntaxError, usize)> {
+    let mut bytes = Vec::with_capacity(raw.len());
+    let mut chars = raw.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        let escape = match chars.next() {
+            Some((_, escape)) => escape,
+            None => return Err((SyntaxError::InvalidEscape, i)),
+        };
+        match escape {
+            'a' => bytes.push(0x07),
+            'b' => bytes.push(0x08),
+            'f' => bytes.push(0x0c),
+            'n' => bytes.push(b'\n'),
+            'r' => bytes.push(b'\r'),
+            't' => bytes.push(b'\t'),
+            'v' => bytes.push(0x0b),
+            '\\' | '"' | '\'' => bytes.push(escape as u8),
+            '\n' | '\r' => {
+                // "\r\n" and "\n\r" count as a single newline.
+                bytes.push(b'\n');
+                if let Some(&(_, next)) = chars.peek() {
+                    if (next == '\n' || next == '\r') && next != escape {
+                        chars.next();
+                    }
+                }
+            }
+            'z' => {
+                while let Some(&(_, next)) = chars.peek() {
+                    if next.is_ascii_whitespace() {
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+            }
+            'x' => {
+                let mut value = 0;
+                for _ in 0..2 {
+                    match chars.next().and_then(|(_, d)| d.to_digit(16)) {
+                        Some(digit) => value = value * 16 + digit,
+                        None => return Err((SyntaxError::InvalidEscape, i)),
+                    }
+                }
+                bytes.push(value as u8);
+            }
+            'u' => {
+                if !matches!(chars.next(), Some((_, '{'))) {
+                    return Err((SyntaxError::InvalidEscape, i));
+                }
+                let mut value: u32 = 0;
+                let mut num_digits = 0;
+                loop {
+                    match chars.next() {
+                        Some((_, '}')) if num_digits > 0 => break,
+                        Some((_, d)) if d.is_ascii_hexdigit() => {
+                            if value > (0x7FFF_FFFF >> 4) {
+                                return Err((SyntaxError::UnicodeEscapeTooLarge, i));
+                            }
+                            value = value * 16 + d.to_digit(16).unwrap();
+                            num_digits += 1;
+                        }
+                        _ => return Err((SyntaxError::InvalidEscape, i)),
+                    }
+                }
+                encode_utf8(value, &mut bytes);
+            }
+            _ if escape.is_ascii_digit() => {
+                // Up to three decimal digits
+                let mut value = escape.to_digit(10).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|&(_, d)| d.to_digit(10)) {
+                        Some(digit) => {
+                            value = value * 10 + digit;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                if value > u8::MAX as u32 {
+                    return Err((SyntaxError::DecimalEscapeTooLarge, i));
+                }
+                bytes.push(value as u8);
+            }
+            _ => return Err((SyntaxError::InvalidEscape, i)),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Converts the contents of a long literal string, without its brackets, into
+/// the string it represents. A newline right after the opening bracket is
+/// skipped, and every other newline ("\n", "\r", "\r\n" or "\n\r") becomes
+/// "\n".
+pub(super) fn long_string_contents(raw: &str) -> Cow<'_, [u8]> {
+    let mut bytes = raw.as_bytes();
+    if let Some(&first @ (b'\n' | b'\r')) = bytes.first() {
+        bytes = &bytes[1..];
+        if matches!(bytes.first(), Some(&second @ (b'\n' | b'\r')) if second != first) {
+            bytes = &bytes[1..];
+        }
+    }
+    if !bytes.contains(&b'\r') {
+        return Cow::Borrowed(bytes);
+    }
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter().peekable();
+    while let Some(&b) = iter.next() {
+        if b == b'\n' || b == b'\r' {
+            normalized.push(b'\n');
+            // "\r\n" and "\n\r" count as a single newline.
+            if let Some(&&next) = iter.peek() {
+                if (next == b'\n' || next == b'\r') && next != b {
+                    iter.next();
+                }
+            }
+        } else {
+            normalized.push(b);
+        }
+    }
+    Cow::Owned(normalized)
+}
+
+/// Appends the UTF-8 encoding of `value` to `bytes`. Like Lua, this accepts
+/// any value below 2^31, including surrogates, using up to six bytes.
+fn encode_utf8(mut value: u32, bytes: &mut Vec<u8>) {
+    if value < 0x80 {
+        bytes.push(value as u8);
+        return;
+    }
+    let mut buffer = [0; 6];
+    let mut i = buffer.len();
+    // The largest value which still fits in the first byte
+    let mut max_first = 0x3f;
+    while value > max_first {
+        i -= 1;
+        buffer[i] = 0x80 | (value & 0x3f) as u8;
+        value >>= 6;
+        max_first >>= 1;
+    }
+    i -= 1;
+    buffer[i] = ((!max_first << 1) | value) as u8;
+    bytes.extend_from_slice(&buffer[i..]);
+}
+
 /// Checks if a word is a keyword, then returns the appropriate `TokenType`.
 fn keyword_match(s: &str) -> TokenType {
     match s {
@@ -426,6 +681,7 @@ fn keyword_match(s: &str) -> TokenType {
         "false" => False,
         "for" => For,
         "function" => Function,
+        "goto" => Goto,
         "if" => If,
         "in" => In,
         "local" => Local,
@@ -580,4 +836,133 @@ mod tests {
         let linebreaks = &[0, 14, 35, 38];
         check(input, tokens, linebreaks);
     }
+
+    #[test]
+    fn test_lexer12() {
+        let input = "goto a ::a:";
+        let tokens = &[
+            (Goto, 0, 4),
+            (Identifier, 5, 1),
+            (DoubleColon, 7, 2),
+            (Identifier, 9, 1),
+            (Colon, 10, 1),
+        ];
+        check_line(input, tokens);
+    }
+
+    #[test]
+    fn test_lexer13() {
+        let input = "x = [==[\n]]\n]==] --[[ comment\n]] y";
+        let tokens = &[
+            (Identifier, 0, 1),
+            (Assign, 2, 1),
+            (LiteralString, 4, 12),
+            (Identifier, 33, 1),
+        ];
+        let linebreaks = &[0, 9, 12, 30];
+        check(input, tokens, linebreaks);
+
+        let input = "x = [[\r\na\r\nb\n\rc\rd]]";
+        let tokens = &[(Identifier, 0, 1), (Assign, 2, 1), (LiteralString, 4, 15)];
+        let linebreaks = &[0, 8, 11, 13];
+        check(input, tokens, linebreaks);
+        assert_eq!(
+            long_string_contents("\r\na\r\nb\n\rc\rd"),
+            &b"a\nb\nc\nd"[..]
+        );
+        assert_eq!(long_string_contents("\n\r\r\n"), &b"\n"[..]);
+        assert_eq!(long_string_contents("\r\r"), &b"\n"[..]);
+        assert_eq!(long_string_contents("\r\n"), &b""[..]);
+    }
+
+    #[test]
+    fn test_lexer14() {
+        let mut lexer = Lexer::new("[[ unfinished");
+        assert!(lexer.next_token().unwrap_err().is_recoverable());
+        let mut lexer = Lexer::new("--[==[ unfinished ]]");
+        assert!(lexer.next_token().unwrap_err().is_recoverable());
+        let mut lexer = Lexer::new("[=");
+        assert!(lexer.next_token().is_err());
+    }
+
+    #[test]
+    fn test_lexer15() {
+        let input = r#"x = "a\tb\65\x41\u{3B1}\z
+            c" y"#;
+        let tokens = &[
+            (Identifier, 0, 1),
+            (Assign, 2, 1),
+            (LiteralString, 4, 36),
+            (Identifier, 41, 1),
+        ];
+        let linebreaks = &[0, 26];
+        check(input, tokens, linebreaks);
+        assert_eq!(
+            unescape(r"a\tb\65\x41\u{3B1}\z  c").unwrap(),
+            "a\tbAA\u{3B1}c".as_bytes()
+        );
+        assert_eq!(unescape("\\\r\n\\\n\\\n").unwrap(), b"\n\n\n");
+        assert_eq!(
+            unescape(r#"\a\b\f\v\\\"\'\0"#).unwrap(),
+            b"\x07\x08\x0c\x0b\\\"'\0"
+        );
+        // Any byte, and any code point below 2^31, can be written.
+        assert_eq!(unescape(r"\xFF\x80\255").unwrap(), b"\xFF\x80\xFF");
+        assert_eq!(unescape(r"\u{D800}").unwrap(), b"\xED\xA0\x80");
+        assert_eq!(unescape(r"\u{10FFFF}").unwrap(), "\u{10FFFF}".as_bytes());
+        assert_eq!(
+            unescape(r"\u{7FFFFFFF}").unwrap(),
+            b"\xFD\xBF\xBF\xBF\xBF\xBF"
+        );
+        assert!(unescape(r"\u{80000000}").is_err());
+
+        let mut lexer = Lexer::new("x = 1\ny = 'ab\\q'");
+        for _ in 0..5 {
+            lexer.next_token().unwrap();
+        }
+        let err = lexer.next_token().unwrap_err();
+        assert_eq!((err.line_num(), err.column()), (2, 8));
+        assert!(Lexer::new(r"'\256'").next_token().is_err());
+        assert!(Lexer::new(r"'\xA'").next_token().is_err());
+        assert!(Lexer::new(r"'\u{80000000}'").next_token().is_err());
+        assert!(Lexer::new(r"'\u{D800}'").next_token().is_ok());
+        assert!(Lexer::new(r"'\u{}'").next_token().is_err());
+    }
+
+    #[test]
+    fn test_lexer16() {
+        let input = "a // b / c";
+        let tokens = &[
+            (Identifier, 0, 1),
+            (DoubleSlash, 2, 2),
+            (Identifier, 5, 1),
+            (Slash, 7, 1),
+            (Identifier, 9, 1),
+        ];
+        check_line(input, tokens);
+    }
+
+    #[test]
+    fn test_lexer17() {
+        let input = "~a & b | c ~ d << e >> f ~= g <= h";
+        let tokens = &[
+            (Tilde, 0, 1),
+            (Identifier, 1, 1),
+            (Ampersand, 3, 1),
+            (Identifier, 5, 1),
+            (Pipe, 7, 1),
+            (Identifier, 9, 1),
+            (Tilde, 11, 1),
+            (Identifier, 13, 1),
+            (ShiftLeft, 15, 2),
+            (Identifier, 18, 1),
+            (ShiftRight, 20, 2),
+            (Identifier, 23, 1),
+            (NotEqual, 25, 2),
+            (Identifier, 28, 1),
+            (LessEqual, 30, 2),
+            (Identifier, 33, 1),
+        ];
+        check_line(input, tokens);
+    }
 }
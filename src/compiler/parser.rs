@@ -4,18 +4,22 @@ use super::error::SyntaxError;
 use super::exp_desc::ExpDesc;
 use super::exp_desc::PlaceExp;
 use super::exp_desc::PrefixExp;
+use super::lexer::long_string_contents;
+use super::lexer::unescape;
 use super::lexer::TokenStream;
 use super::Chunk;
 use super::Instr;
 use super::Result;
 use super::Token;
 use super::TokenType;
+use super::UpvalueDesc;
+use super::MULTRET;
 
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
 use std::cmp::Ordering;
-use std::mem::swap;
+use std::mem::{replace, take};
+use std::rc::Rc;
 use std::str;
-use std::u8;
 
 /// Tracks the current state, to make parsing easier.
 #[derive(Debug)]
@@ -24,7 +28,93 @@ struct Parser<'a> {
     input: TokenStream<'a>,
     chunk: Chunk,
     nest_level: i32,
-    locals: Vec<(String, i32)>,
+    locals: Vec<LocalVar>,
+    /// The loops of the current function which enclose the current position,
+    /// from the outermost inwards.
+    loops: Vec<LoopDesc>,
+    /// The labels of the current function which are visible at the current
+    /// position.
+    labels: Vec<LabelDesc>,
+    /// The `goto` statements of the current function whose label hasn't
+    /// been found yet.
+    gotos: Vec<GotoDesc>,
+    /// The functions which enclose the one currently being parsed, from the
+    /// outermost inwards.
+    enclosing: Vec<EnclosingFn>,
+}
+
+/// A local variable which is currently in scope.
+#[derive(Debug)]
+struct LocalVar {
+    name: String,
+    nest_level: i32,
+    /// Whether a nested function has captured this variable as an upvalue.
+    captured: bool,
+    attrib: LocalAttrib,
+}
+
+/// The attribute given to a local variable by its declaration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LocalAttrib {
+    /// A normal variable
+    None,
+    /// A `<const>` variable, which can't be assigned to
+    Const,
+    /// A `<close>` variable, which is also constant. Its value's `__close`
+    /// metamethod is called once the variable goes out of scope.
+    Close,
+}
+
+/// The saved state of a function, while a function nested inside it is being
+/// parsed.
+#[derive(Debug)]
+struct EnclosingFn {
+    chunk: Chunk,
+    locals: Vec<LocalVar>,
+    loops: Vec<LoopDesc>,
+    labels: Vec<LabelDesc>,
+    gotos: Vec<GotoDesc>,
+}
+
+/// A loop which is currently being parsed.
+#[derive(Debug)]
+struct LoopDesc {
+    /// The first local slot which belongs to the loop.
+    first_slot: u8,
+    /// The index of the `Jump` instruction of each `break` in the loop.
+    breaks: Vec<usize>,
+    /// Whether one of the loop's locals was captured by a closure, or is a
+    /// to-be-closed variable.
+    needs_close: bool,
+}
+
+/// A label which is visible at the current position.
+#[derive(Debug)]
+struct LabelDesc {
+    name: String,
+    /// The index of the instruction which follows the label.
+    code_index: usize,
+    nest_level: i32,
+    /// The number of locals in scope at the label. A label at the end of a
+    /// block is considered outside the scope of the block's locals.
+    num_locals: u8,
+}
+
+/// A `goto` statement whose label hasn't been found yet.
+#[derive(Debug)]
+struct GotoDesc {
+    name: String,
+    /// The index of the `goto`'s `Jump` instruction.
+    code_index: usize,
+    /// The nest level of the innermost block which is still being parsed.
+    nest_level: i32,
+    /// The number of locals which are still in scope.
+    num_locals: u8,
+    /// Whether the `goto` leaves the scope of a captured or to-be-closed
+    /// local.
+    needs_close: bool,
+    /// The position of the `goto` in the source code, for errors.
+    pos: usize,
 }
 
 /// Parses Lua source code into a `Chunk`.
@@ -34,6 +124,10 @@ pub(super) fn parse_str(source: &str) -> Result<Chunk> {
         chunk: Chunk::default(),
         nest_level: 0,
         locals: Vec::new(),
+        loops: Vec::new(),
+        labels: Vec::new(),
+        gotos: Vec::new(),
+        enclosing: Vec::new(),
     };
     parser.parse_all()
 }
@@ -47,9 +141,19 @@ impl<'a> Parser<'a> {
         if self.locals.len() == u8::MAX as usize {
             Err(self.error(SyntaxError::TooManyLocals))
         } else {
-            self.locals.push((name.to_string(), self.nest_level));
-            if self.locals.len() > self.chunk.num_locals as usize {
-                self.chunk.num_locals += 1;
+            self.locals.push(LocalVar {
+                name: name.to_string(),
+                nest_level: self.nest_level,
+                captured: false,
+                attrib: LocalAttrib::None,
+            });
+            // Parameters have their own slots, so they aren't counted.
+            let num_locals = self
+                .locals
+                .len()
+                .saturating_sub(self.chunk.num_params as usize) as u8;
+            if num_locals > self.chunk.num_locals {
+                self.chunk.num_locals = num_locals;
             }
             Ok(())
         }
@@ -99,11 +203,11 @@ impl<'a> Parser<'a> {
     /// Expects an identifier and returns the id of its string literal.
     fn expect_identifier_id(&mut self) -> Result<u8> {
         let name = self.expect_identifier()?;
-        self.find_or_add_string(name)
+        self.find_or_add_string(name.as_bytes())
     }
 
     /// Stores a literal string and returns its index.
-    fn find_or_add_string(&mut self, string: &str) -> Result<u8> {
+    fn find_or_add_string(&mut self, string: &[u8]) -> Result<u8> {
         find_or_add(&mut self.chunk.string_literals, string)
             .ok_or_else(|| self.error(SyntaxError::TooManyStrings))
     }
@@ -114,43 +218,165 @@ impl<'a> Parser<'a> {
             .ok_or_else(|| self.error(SyntaxError::TooManyNumbers))
     }
 
-    /// Converts a literal string's offsets into a real String.
-    fn get_literal_string_contents(&self, tok: Token) -> &'a str {
-        // Chop off the quotes
+    /// Stores a literal integer and returns its index.
+    fn find_or_add_integer(&mut self, num: i64) -> Result<u8> {
+        find_or_add(&mut self.chunk.integer_literals, &num)
+            .ok_or_else(|| self.error(SyntaxError::TooManyNumbers))
+    }
+
+    /// Converts a literal string's offsets into the bytes of the string.
+    fn get_literal_string_contents(&self, tok: Token) -> Cow<'a, [u8]> {
         let Token { start, len, typ } = tok;
         assert_eq!(typ, TokenType::LiteralString);
         assert!(len >= 2);
-        let range = (start + 1)..(start + len as usize - 1);
-        self.input.from_src(range)
+        let text = self.input.source_slice(tok.range());
+        if let Some(after_bracket) = text.strip_prefix('[') {
+            // Chop off the long brackets
+            let level = after_bracket.bytes().take_while(|&b| b == b'=').count();
+            long_string_contents(&text[level + 2..text.len() - level - 2])
+        } else {
+            // Chop off the quotes
+            let range = (start + 1)..(start + len as usize - 1);
+            let contents = self.input.source_slice(range);
+            if contents.contains('\\') {
+                // The lexer already checked the escape sequences.
+                Cow::Owned(unescape(contents).unwrap())
+            } else {
+                Cow::Borrowed(contents.as_bytes())
+            }
+        }
     }
 
     /// Gets the original source code contained by a token.
     fn get_text(&self, token: Token) -> &'a str {
-        self.input.from_src(token.range())
+        self.input.source_slice(token.range())
     }
 
-    /// Lowers the nesting level by one, discarding any locals from that block.
+    /// Lowers the nesting level by one, discarding any locals and labels from
+    /// that block. If any of those locals were captured by a closure, their
+    /// upvalues are closed, and so are any to-be-closed variables. Unresolved
+    /// `goto`s move out to the enclosing block.
     fn level_down(&mut self) {
-        while let Some((_, lvl)) = self.locals.last() {
-            if *lvl == self.nest_level {
+        let mut needs_close = false;
+        while let Some(local) = self.locals.last() {
+            if local.nest_level == self.nest_level {
+                needs_close |= local.captured || local.attrib == LocalAttrib::Close;
                 self.locals.pop();
             } else {
                 break;
             }
         }
+        if needs_close {
+            self.push(Instr::Close(self.locals.len() as u8));
+        }
+
+        let nest_level = self.nest_level;
+        self.labels.retain(|label| label.nest_level != nest_level);
+        for goto in &mut self.gotos {
+            if goto.nest_level == nest_level {
+                goto.nest_level -= 1;
+                goto.num_locals = self.locals.len() as u8;
+                goto.needs_close |= needs_close;
+            }
+        }
         self.nest_level -= 1;
     }
 
+    /// Starts tracking a loop, so that `break` statements can find it. This
+    /// must be called before any of the loop's locals are added.
+    fn loop_start(&mut self) {
+        self.loops.push(LoopDesc {
+            first_slot: self.locals.len() as u8,
+            breaks: Vec::new(),
+            needs_close: false,
+        });
+    }
+
+    /// Finishes a loop, patching its `break` statements to jump to the
+    /// current position. If any of the loop's locals need to be closed, the
+    /// `break` statements land on a `Close` instruction, since they skip the
+    /// end of the loop's blocks.
+    fn loop_end(&mut self) {
+        let lp = self.loops.pop().unwrap();
+        let target = self.chunk.code.len();
+        if lp.needs_close && !lp.breaks.is_empty() {
+            self.push(Instr::Close(lp.first_slot));
+        }
+        for i in lp.breaks {
+            self.chunk.code[i] = Instr::Jump((target - i - 1) as isize);
+        }
+    }
+
+    /// Turns the function call which was just emitted into a tail call.
+    fn make_tail_call(&mut self) {
+        let instr = self.chunk.code.last_mut().unwrap();
+        *instr = match *instr {
+            Instr::Call(num_args, _) => Instr::TailCall(num_args),
+            Instr::CallMulti(num_args, _) => Instr::TailCallMulti(num_args),
+            i => unreachable!("Tail call but last instruction was {:?}", i),
+        };
+    }
+
     /// Adds an instruction to the output.
     fn push(&mut self, instr: Instr) {
         self.chunk.code.push(instr);
     }
 
+    /// Changes how many values the function call or `...` expression which
+    /// was just emitted will produce.
+    fn set_call_returns(&mut self, num_rets: u8) {
+        match self.chunk.code.last_mut() {
+            Some(Instr::Call(_, rets))
+            | Some(Instr::CallMulti(_, rets))
+            | Some(Instr::VarArg(rets)) => *rets = num_rets,
+            i => unreachable!("Multi-valued expression but last instruction was {:?}", i),
+        }
+    }
+
+    /// If `exp` is the last expression in a list, and it can produce multiple
+    /// values, makes it keep all of its values and returns `true`.
+    fn expand_last_exp(&mut self, exp: &ExpDesc) -> bool {
+        if let ExpDesc::Prefix(PrefixExp::FunctionCall) | ExpDesc::VarArg = exp {
+            self.set_call_returns(MULTRET);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Adjusts the values of an expression list to exactly `num_wanted`
+    /// values, padding with `nil` or discarding values as needed.
+    fn adjust_explist(&mut self, num_exps: u8, last_exp: ExpDesc, num_wanted: u8) -> Result<()> {
+        match num_wanted.cmp(&num_exps) {
+            Ordering::Less => {
+                for _ in num_wanted..num_exps {
+                    self.push(Instr::Pop);
+                }
+            }
+            Ordering::Greater => {
+                if let ExpDesc::Prefix(PrefixExp::FunctionCall) | ExpDesc::VarArg = last_exp {
+                    let num_rets = 1 + num_wanted - num_exps;
+                    if num_rets == MULTRET {
+                        return Err(self.error(SyntaxError::Complexity));
+                    }
+                    self.set_call_returns(num_rets);
+                } else {
+                    for _ in num_exps..num_wanted {
+                        self.push(Instr::PushNil);
+                    }
+                }
+            }
+            Ordering::Equal => (),
+        }
+        Ok(())
+    }
+
     // Actual parsing
 
     /// The main entry point for the parser. This parses the entire input.
     fn parse_all(mut self) -> Result<Chunk> {
-        let c = self.parse_chunk(&[])?;
+        // The main chunk receives its arguments as `...`.
+        let c = self.parse_chunk(&[], true)?;
         let token = self.input.next()?;
         if let TokenType::EndOfFile = token.typ {
             Ok(c)
@@ -159,26 +385,43 @@ impl<'a> Parser<'a> {
         }
     }
 
-    /// Parses a `Chunk`.
-    fn parse_chunk(&mut self, params: &[&str]) -> Result<Chunk> {
-        let mut tmp_chunk = Chunk::default();
-        swap(&mut tmp_chunk, &mut self.chunk);
+    /// Parses a `Chunk`. The chunk gets its own set of locals, but it can
+    /// still capture the locals of the enclosing functions as upvalues.
+    fn parse_chunk(&mut self, params: &[&str], is_vararg: bool) -> Result<Chunk> {
+        let outer = EnclosingFn {
+            chunk: take(&mut self.chunk),
+            locals: take(&mut self.locals),
+            loops: take(&mut self.loops),
+            labels: take(&mut self.labels),
+            gotos: take(&mut self.gotos),
+        };
+        self.enclosing.push(outer);
 
         self.chunk.num_params = params.len() as u8;
+        self.chunk.is_vararg = is_vararg;
         for &param in params {
-            self.locals.push((param.into(), self.nest_level));
+            self.add_local(param)?;
         }
 
         self.parse_statements()?;
         self.push(Instr::Return(0));
+        if let Some(goto) = self.gotos.first() {
+            let kind = SyntaxError::UndefinedLabel(goto.name.clone());
+            return Err(self.error_at(kind, goto.pos));
+        }
 
-        swap(&mut tmp_chunk, &mut self.chunk);
+        let outer = self.enclosing.pop().unwrap();
+        self.locals = outer.locals;
+        self.loops = outer.loops;
+        self.labels = outer.labels;
+        self.gotos = outer.gotos;
+        let new_chunk = replace(&mut self.chunk, outer.chunk);
 
         if option_env!("LUA_DEBUG_PARSER").is_some() {
-            println!("Compiled chunk: {:#?}", &tmp_chunk);
+            println!("Compiled chunk: {:#?}", &new_chunk);
         }
 
-        Ok(tmp_chunk)
+        Ok(new_chunk)
     }
 
     /// Parses 0 or more statements, possibly separated by semicolons.
@@ -193,6 +436,9 @@ impl<'a> Parser<'a> {
                 TokenType::Local => self.parse_locals()?,
                 TokenType::For => self.parse_for()?,
                 TokenType::Function => self.parse_fndecl()?,
+                TokenType::Break => self.parse_break()?,
+                TokenType::Goto => self.parse_goto()?,
+                TokenType::DoubleColon => self.parse_labels()?,
                 TokenType::Semi => {
                     self.input.next()?;
                 }
@@ -202,13 +448,122 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Parses a `break` statement. Its jump is patched when the loop ends.
+    fn parse_break(&mut self) -> Result<()> {
+        let break_token = self.input.next()?;
+        match self.loops.last_mut() {
+            Some(lp) => lp.breaks.push(self.chunk.code.len()),
+            None => return Err(self.error_at(SyntaxError::BreakOutsideLoop, break_token.start)),
+        }
+        self.push(Instr::Jump(0));
+        Ok(())
+    }
+
+    /// Parses a `goto` statement. A backward jump is emitted right away, but a
+    /// forward jump is patched once its label is found.
+    fn parse_goto(&mut self) -> Result<()> {
+        let goto_token = self.input.next()?;
+        let name = self.expect_identifier()?;
+        if let Some(label) = self.labels.iter().rev().find(|label| label.name == name) {
+            let (code_index, num_locals) = (label.code_index, label.num_locals);
+            if self.locals.len() > num_locals as usize {
+                self.push(Instr::Close(num_locals));
+            }
+            let offset = code_index as isize - (self.chunk.code.len() as isize + 1);
+            self.push(Instr::Jump(offset));
+        } else {
+            self.gotos.push(GotoDesc {
+                name: name.to_string(),
+                code_index: self.chunk.code.len(),
+                nest_level: self.nest_level,
+                num_locals: self.locals.len() as u8,
+                needs_close: false,
+                pos: goto_token.start,
+            });
+            self.push(Instr::Jump(0));
+        }
+        Ok(())
+    }
+
+    /// Parses a label, and resolves any pending `goto`s in the same block.
+    /// Any labels which follow it refer to the same position, so they are
+    /// parsed together.
+    fn parse_labels(&mut self) -> Result<()> {
+        let first_label = self.labels.len();
+        let code_index = self.chunk.code.len();
+        // Skip other statements which do nothing, to find out whether the
+        // labels are at the end of the block.
+        loop {
+            match self.input.peek_type()? {
+                TokenType::Semi => {
+                    self.input.next()?;
+                }
+                TokenType::DoubleColon => {
+                    let label_token = self.input.next()?;
+                    let name = self.expect_identifier()?;
+                    self.expect(TokenType::DoubleColon)?;
+                    if self.labels.iter().any(|label| label.name == name) {
+                        let kind = SyntaxError::DuplicateLabel(name.to_string());
+                        return Err(self.error_at(kind, label_token.start));
+                    }
+                    self.labels.push(LabelDesc {
+                        name: name.to_string(),
+                        code_index,
+                        nest_level: self.nest_level,
+                        num_locals: self.locals.len() as u8,
+                    });
+                }
+                _ => break,
+            }
+        }
+        if let TokenType::End | TokenType::Else | TokenType::ElseIf | TokenType::EndOfFile =
+            self.input.peek_type()?
+        {
+            let nest_level = self.nest_level;
+            let outer_locals = self.locals.iter().filter(|l| l.nest_level < nest_level);
+            let num_locals = outer_locals.count() as u8;
+            for label in &mut self.labels[first_label..] {
+                label.num_locals = num_locals;
+            }
+        }
+
+        let mut needs_close = false;
+        for label_index in first_label..self.labels.len() {
+            let label = &self.labels[label_index];
+            let (name, num_locals) = (label.name.clone(), label.num_locals);
+            let mut i = 0;
+            while i < self.gotos.len() {
+                let goto = &self.gotos[i];
+                if goto.name != name || goto.nest_level != self.nest_level {
+                    i += 1;
+                    continue;
+                }
+                let goto = self.gotos.remove(i);
+                if goto.num_locals < num_locals {
+                    let local_name = self.locals[goto.num_locals as usize].name.clone();
+                    let kind = SyntaxError::JumpIntoScope(local_name);
+                    return Err(self.error_at(kind, goto.pos));
+                }
+                needs_close |= goto.needs_close;
+                let offset = code_index - goto.code_index - 1;
+                self.chunk.code[goto.code_index] = Instr::Jump(offset as isize);
+            }
+        }
+        // The `goto`s left the scope of locals which need to be closed, so
+        // they land on a `Close` instruction.
+        if needs_close {
+            self.push(Instr::Close(self.labels[first_label].num_locals));
+        }
+        Ok(())
+    }
+
     /// Parses a function declaration, which is any statement that starts with
     /// the keyword `function`.
     fn parse_fndecl(&mut self) -> Result<()> {
         self.input.next()?; // 'function' keyword
         let name = self.expect_identifier()?;
         match self.input.peek_type()? {
-            TokenType::Dot => self.parse_fndecl_table(name),
+            TokenType::Dot | TokenType::Colon => self.parse_fndecl_table(name),
             _ => self.parse_fndecl_basic(name),
         }
     }
@@ -217,12 +572,14 @@ impl<'a> Parser<'a> {
     /// a local or global variable.
     fn parse_fndecl_basic(&mut self, name: &'a str) -> Result<()> {
         let place_exp = self.parse_prefix_identifier(name)?;
+        self.check_assignable(&place_exp, self.input.pos())?;
         let instr = match place_exp {
             PlaceExp::Local(i) => Instr::SetLocal(i),
+            PlaceExp::Upvalue(i) => Instr::SetUpvalue(i),
             PlaceExp::Global(i) => Instr::SetGlobal(i),
-            _ => unreachable!("place expression was not a local or global variable"),
+            _ => unreachable!("place expression was not a variable"),
         };
-        self.parse_fndef()?;
+        self.parse_fndef(false)?;
         self.push(instr);
         Ok(())
     }
@@ -231,21 +588,29 @@ impl<'a> Parser<'a> {
         // Push the table onto the stack.
         let table_instr = match self.parse_prefix_identifier(table_name)? {
             PlaceExp::Local(i) => Instr::GetLocal(i),
+            PlaceExp::Upvalue(i) => Instr::GetUpvalue(i),
             PlaceExp::Global(i) => Instr::GetGlobal(i),
-            _ => unreachable!("place expression was not a local or global variable"),
+            _ => unreachable!("place expression was not a variable"),
         };
         self.push(table_instr);
 
-        // Parse all the fields. There must be at least one.
-        self.expect(TokenType::Dot)?;
+        // Parse all the fields. There must be at least one. A method name,
+        // after a `:`, is always the last one.
+        let mut is_method = self.input.next()?.typ == TokenType::Colon;
         let mut last_field_id = self.expect_identifier_id()?;
-        while self.input.try_pop(TokenType::Dot)?.is_some() {
+        while !is_method {
+            match self.input.peek_type()? {
+                TokenType::Dot => (),
+                TokenType::Colon => is_method = true,
+                _ => break,
+            }
+            self.input.next()?;
             self.push(Instr::GetField(last_field_id));
             last_field_id = self.expect_identifier_id()?;
         }
 
         // Parse the function params and body.
-        self.parse_fndef()?;
+        self.parse_fndef(is_method)?;
         self.push(Instr::SetField(0, last_field_id));
         Ok(())
     }
@@ -254,8 +619,31 @@ impl<'a> Parser<'a> {
     /// block.
     fn parse_return(&mut self) -> Result<()> {
         self.input.next()?; // 'return' keyword
-        let (n, _) = self.parse_explist()?;
-        self.push(Instr::Return(n));
+        match self.input.peek_type()? {
+            TokenType::End
+            | TokenType::Else
+            | TokenType::ElseIf
+            | TokenType::Until
+            | TokenType::Semi
+            | TokenType::EndOfFile => self.push(Instr::Return(0)),
+            _ => {
+                let (n, last_exp) = self.parse_explist()?;
+                // The to-be-closed variables must be closed after the call,
+                // so it can't be a tail call.
+                let has_tbc = self.locals.iter().any(|l| l.attrib == LocalAttrib::Close);
+                if n == 1
+                    && matches!(last_exp, ExpDesc::Prefix(PrefixExp::FunctionCall))
+                    && !has_tbc
+                {
+                    self.make_tail_call();
+                    self.push(Instr::ReturnMulti(0));
+                } else if self.expand_last_exp(&last_exp) {
+                    self.push(Instr::ReturnMulti(n - 1));
+                } else {
+                    self.push(Instr::Return(n));
+                }
+            }
+        }
         self.input.try_pop(TokenType::Semi)?;
         Ok(())
     }
@@ -267,8 +655,8 @@ impl<'a> Parser<'a> {
                 let tok = self.input.next()?;
                 Err(self.err_unexpected(tok, TokenType::Assign))
             }
-            PrefixExp::FunctionCall(num_args) => {
-                self.push(Instr::Call(num_args, 0));
+            PrefixExp::FunctionCall => {
+                self.set_call_returns(0);
                 Ok(())
             }
             PrefixExp::Place(first_place) => self.parse_assign(first_place),
@@ -282,41 +670,29 @@ impl<'a> Parser<'a> {
             places.push(self.parse_place_exp()?);
         }
 
-        self.expect(TokenType::Assign)?;
-        let num_lvals = places.len() as isize;
-        let (num_rvals, last_exp) = self.parse_explist()?;
-        let num_rvals = num_rvals as isize;
-        let diff = num_lvals - num_rvals;
-        if diff > 0 {
-            if let ExpDesc::Prefix(PrefixExp::FunctionCall(_)) = last_exp {
-                let num_args = match self.chunk.code.pop() {
-                    Some(Instr::Call(args, _)) => args,
-                    i => unreachable!("PrefixExp::FunctionCall but last instruction was {:?}", i),
-                };
-                self.push(Instr::Call(num_args, 1 + diff as u8));
-            } else {
-                for _ in 0..diff {
-                    self.push(Instr::PushNil);
-                }
-            }
-        } else {
-            // discard excess rvals
-            for _ in diff..0 {
-                self.push(Instr::Pop);
-            }
+        let assign_token = self.expect(TokenType::Assign)?;
+        if places.len() > u8::MAX as usize {
+            return Err(self.error_at(SyntaxError::Complexity, assign_token.start));
+        }
+        for place in &places {
+            self.check_assignable(place, assign_token.start)?;
         }
+        let num_lvals = places.len() as u8;
+        let (num_rvals, last_exp) = self.parse_explist()?;
+        self.adjust_explist(num_rvals, last_exp, num_lvals)?;
 
         places.reverse();
         for (i, place_exp) in places.into_iter().enumerate() {
             let instr = match place_exp {
                 PlaceExp::Local(i) => Instr::SetLocal(i),
+                PlaceExp::Upvalue(i) => Instr::SetUpvalue(i),
                 PlaceExp::Global(i) => Instr::SetGlobal(i),
                 PlaceExp::FieldAccess(literal_id) => {
-                    let stack_offset = num_lvals as u8 - i as u8 - 1;
+                    let stack_offset = num_lvals - i as u8 - 1;
                     Instr::SetField(stack_offset, literal_id)
                 }
                 PlaceExp::TableIndex => {
-                    let stack_offset = num_lvals as u8 - i as u8 - 1;
+                    let stack_offset = num_lvals - i as u8 - 1;
                     Instr::SetTable(stack_offset)
                 }
             };
@@ -329,7 +705,7 @@ impl<'a> Parser<'a> {
     /// Parses an expression which can appear on the left side of an assignment.
     fn parse_place_exp(&mut self) -> Result<PlaceExp> {
         match self.parse_prefix_exp()? {
-            PrefixExp::Parenthesized | PrefixExp::FunctionCall(_) => {
+            PrefixExp::Parenthesized | PrefixExp::FunctionCall => {
                 let tok = self.input.next()?;
                 Err(self.err_unexpected(tok, TokenType::Assign))
             }
@@ -337,16 +713,51 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Checks that `place` isn't a `<const>` or `<close>` variable, which
+    /// can't be assigned to. `pos` is the position of the assignment.
+    fn check_assignable(&self, place: &PlaceExp, pos: usize) -> Result<()> {
+        let var = match *place {
+            PlaceExp::Local(i) => &self.locals[i as usize],
+            PlaceExp::Upvalue(i) => self.upvalue_var(self.enclosing.len(), i),
+            _ => return Ok(()),
+        };
+        if var.attrib == LocalAttrib::None {
+            Ok(())
+        } else {
+            let kind = SyntaxError::AssignToConst(var.name.clone());
+            Err(self.error_at(kind, pos))
+        }
+    }
+
+    /// Finds the local variable which upvalue `i` of the function at the
+    /// given depth refers to. Depth 0 is the main chunk.
+    fn upvalue_var(&self, mut depth: usize, mut i: u8) -> &LocalVar {
+        loop {
+            let chunk = if depth == self.enclosing.len() {
+                &self.chunk
+            } else {
+                &self.enclosing[depth].chunk
+            };
+            match chunk.upvalues[i as usize] {
+                UpvalueDesc::Local(slot) => {
+                    return &self.enclosing[depth - 1].locals[slot as usize]
+                }
+                UpvalueDesc::Upvalue(outer) => {
+                    depth -= 1;
+                    i = outer;
+                }
+            }
+        }
+    }
+
     /// Emits code to evaluate the prefix expression as a normal expression.
     fn eval_prefix_exp(&mut self, exp: PrefixExp) {
         match exp {
-            PrefixExp::FunctionCall(num_args) => {
-                self.push(Instr::Call(num_args, 1));
-            }
-            PrefixExp::Parenthesized => (),
+            PrefixExp::FunctionCall | PrefixExp::Parenthesized => (),
             PrefixExp::Place(place) => {
                 let instr = match place {
                     PlaceExp::Local(i) => Instr::GetLocal(i),
+                    PlaceExp::Upvalue(i) => Instr::GetUpvalue(i),
                     PlaceExp::Global(i) => Instr::GetGlobal(i),
                     PlaceExp::FieldAccess(i) => Instr::GetField(i),
                     PlaceExp::TableIndex => Instr::GetTable,
@@ -356,46 +767,72 @@ impl<'a> Parser<'a> {
         }
     }
 
-    /// Parses a variable's name. This should only ever return `Local` or `Global`.
+    /// Parses a variable's name. This should only ever return `Local`,
+    /// `Upvalue` or `Global`.
     fn parse_prefix_identifier(&mut self, name: &str) -> Result<PlaceExp> {
-        match find_last_local(&self.locals, name) {
-            Some(i) => Ok(PlaceExp::Local(i as u8)),
-            None => {
-                let i = self.find_or_add_string(name)?;
-                Ok(PlaceExp::Global(i))
+        if let Some(i) = find_last_local(&self.locals, name) {
+            Ok(PlaceExp::Local(i as u8))
+        } else if let Some(i) = self.find_upvalue(self.enclosing.len(), name)? {
+            Ok(PlaceExp::Upvalue(i))
+        } else {
+            let i = self.find_or_add_string(name.as_bytes())?;
+            Ok(PlaceExp::Global(i))
+        }
+    }
+
+    /// Resolves `name` as an upvalue of the function at the given depth, where
+    /// depth 0 is the main chunk. Any functions between the one which declared
+    /// the local and the one at `depth` also receive an upvalue for it.
+    fn find_upvalue(&mut self, depth: usize, name: &str) -> Result<Option<u8>> {
+        if depth == 0 {
+            return Ok(None);
+        }
+
+        let parent = &mut self.enclosing[depth - 1];
+        let desc = if let Some(i) = find_last_local(&parent.locals, name) {
+            parent.locals[i].captured = true;
+            for lp in &mut parent.loops {
+                if lp.first_slot as usize <= i {
+                    lp.needs_close = true;
+                }
             }
+            UpvalueDesc::Local(i as u8)
+        } else if let Some(i) = self.find_upvalue(depth - 1, name)? {
+            UpvalueDesc::Upvalue(i)
+        } else {
+            return Ok(None);
+        };
+
+        let chunk = if depth == self.enclosing.len() {
+            &mut self.chunk
+        } else {
+            &mut self.enclosing[depth].chunk
+        };
+        match find_or_add(&mut chunk.upvalues, &desc) {
+            Some(i) => Ok(Some(i)),
+            None => Err(self.error(SyntaxError::Complexity)),
         }
     }
 
     /// Parses a `local` declaration.
     fn parse_locals(&mut self) -> Result<()> {
         self.input.next().unwrap(); // `local` keyword
+        if self.input.check_type(TokenType::Function)? {
+            return self.parse_local_function();
+        }
         let old_local_count = self.locals.len() as u8;
 
-        let names = self.parse_namelist()?;
+        let names = self.parse_attnamelist()?;
+        let is_tbc = |(_, attrib): &&(_, LocalAttrib)| *attrib == LocalAttrib::Close;
+        if names.iter().filter(is_tbc).count() > 1 {
+            return Err(self.error(SyntaxError::MultipleToBeClosed));
+        }
 
         let num_names = names.len() as u8;
         if self.input.try_pop(TokenType::Assign)?.is_some() {
             // Also perform the assignment
             let (num_rvalues, last_exp) = self.parse_explist()?;
-            match num_names.cmp(&num_rvalues) {
-                Ordering::Less => {
-                    for _ in num_names..num_rvalues {
-                        self.push(Instr::Pop);
-                    }
-                }
-                Ordering::Greater => {
-                    if let ExpDesc::Prefix(PrefixExp::FunctionCall(num_args)) = last_exp {
-                        self.chunk.code.pop(); // Pop the old 'Call' instruction
-                        self.push(Instr::Call(num_args, 1 + num_names - num_rvalues));
-                    } else {
-                        for _ in num_rvalues..num_names {
-                            self.push(Instr::PushNil);
-                        }
-                    }
-                }
-                Ordering::Equal => (),
-            }
+            self.adjust_explist(num_rvalues, last_exp, num_names)?;
         } else {
             // They've only been declared, just set them all nil
             for _ in &names {
@@ -410,20 +847,68 @@ impl<'a> Parser<'a> {
 
         // Bring the new variables into scope. It is important they are not
         // in scope until after this statement.
-        for name in names {
+        for (name, attrib) in names {
+            let slot = self.locals.len() as u8;
+            let name_id = match attrib {
+                LocalAttrib::Close => Some(self.find_or_add_string(name.as_bytes())?),
+                _ => None,
+            };
             self.add_local(name)?;
+            self.locals.last_mut().unwrap().attrib = attrib;
+            if let Some(name_id) = name_id {
+                self.push(Instr::ToBeClosed(slot, name_id));
+                for lp in &mut self.loops {
+                    lp.needs_close = true;
+                }
+            }
         }
 
         Ok(())
     }
 
-    /// Parse a comma-separated list of identifiers.
-    fn parse_namelist(&mut self) -> Result<Vec<&'a str>> {
-        let mut names = vec![self.expect_identifier()?];
-        while self.input.try_pop(TokenType::Comma)?.is_some() {
-            names.push(self.expect_identifier()?);
+    /// Parses a `local function` declaration. Unlike a normal `local`
+    /// statement, the new local is in scope for the function's body, so that
+    /// the function can call itself.
+    fn parse_local_function(&mut self) -> Result<()> {
+        self.input.next()?; // `function` keyword
+        let name = self.expect_identifier()?;
+        let slot = self.locals.len() as u8;
+        self.add_local(name)?;
+        self.parse_fndef(false)?;
+        self.push(Instr::SetLocal(slot));
+        Ok(())
+    }
+
+    /// Parse a comma-separated list of identifiers, each of which may be
+    /// followed by an attribute.
+    fn parse_attnamelist(&mut self) -> Result<Vec<(&'a str, LocalAttrib)>> {
+        let mut names = Vec::new();
+        loop {
+            let name = self.expect_identifier()?;
+            names.push((name, self.parse_attrib()?));
+            if self.input.try_pop(TokenType::Comma)?.is_none() {
+                break Ok(names);
+            }
+        }
+    }
+
+    /// Parses the optional attribute of a local variable, such as `<const>`.
+    fn parse_attrib(&mut self) -> Result<LocalAttrib> {
+        if self.input.try_pop(TokenType::Less)?.is_none() {
+            return Ok(LocalAttrib::None);
         }
-        Ok(names)
+        let token = self.expect(TokenType::Identifier)?;
+        let start = token.start;
+        let attrib = match self.get_text(token) {
+            "const" => LocalAttrib::Const,
+            "close" => LocalAttrib::Close,
+            name => {
+                let kind = SyntaxError::UnknownAttribute(name.to_string());
+                return Err(self.error_at(kind, start));
+            }
+        };
+        self.expect(TokenType::Greater)?;
+        Ok(attrib)
     }
 
     /// Parses a `for` loop, before we know whether it's generic (`for i in t do`) or
@@ -432,9 +917,14 @@ impl<'a> Parser<'a> {
         self.input.next()?; // `for` keyword
         let name = self.expect_identifier()?;
         self.nest_level += 1;
-        self.expect(TokenType::Assign)?;
-        self.parse_numeric_for(&name)?;
+        self.loop_start();
+        if self.input.try_pop(TokenType::Assign)?.is_some() {
+            self.parse_numeric_for(name)?;
+        } else {
+            self.parse_generic_for(name)?;
+        }
         self.level_down();
+        self.loop_end();
         Ok(())
     }
 
@@ -447,7 +937,10 @@ impl<'a> Parser<'a> {
         self.add_local("")?;
 
         // The actual local is in a fourth slot, so that it can be reassigned to.
-        self.add_local(&name)?;
+        // It belongs to the body's block, so that each iteration gets a fresh
+        // upvalue if it is captured.
+        self.nest_level += 1;
+        self.add_local(name)?;
 
         // First, all 3 control expressions are evaluated.
         self.parse_expr()?;
@@ -465,6 +958,7 @@ impl<'a> Parser<'a> {
         // body
         self.parse_statements()?;
         self.expect(TokenType::End)?;
+        self.level_down();
         let body_length = (self.chunk.code.len() - loop_start_instr_index) as isize;
         self.push(Instr::ForLoop(current_local_slot, -(body_length)));
 
@@ -474,6 +968,50 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    /// Parses a generic `for` loop, starting after the first name.
+    fn parse_generic_for(&mut self, first_name: &str) -> Result<()> {
+        let mut names = vec![first_name];
+        while self.input.try_pop(TokenType::Comma)?.is_some() {
+            names.push(self.expect_identifier()?);
+        }
+        self.expect(TokenType::In)?;
+
+        // The iterator function, state and control variable are stored in
+        // three hidden local slots.
+        let base_slot = self.locals.len() as u8;
+        self.add_local("")?;
+        self.add_local("")?;
+        self.add_local("")?;
+        let (num_exps, last_exp) = self.parse_explist()?;
+        self.adjust_explist(num_exps, last_exp, 3)?;
+        self.expect(TokenType::Do)?;
+        self.push(Instr::SetLocal(base_slot + 2));
+        self.push(Instr::SetLocal(base_slot + 1));
+        self.push(Instr::SetLocal(base_slot));
+
+        // The variables belong to the body's block, like in a numeric loop.
+        self.nest_level += 1;
+        for name in &names {
+            self.add_local(name)?;
+        }
+
+        // Start by jumping to the iterator call at the end of the loop.
+        let jump_index = self.chunk.code.len();
+        self.push(Instr::Jump(0));
+
+        // body
+        self.parse_statements()?;
+        self.expect(TokenType::End)?;
+        self.level_down();
+
+        let call_index = self.chunk.code.len();
+        self.chunk.code[jump_index] = Instr::Jump((call_index - jump_index - 1) as isize);
+        self.push(Instr::GenericForCall(base_slot, names.len() as u8));
+        let body_length = (self.chunk.code.len() - jump_index) as isize;
+        self.push(Instr::GenericForLoop(base_slot, -body_length));
+        Ok(())
+    }
+
     /// Parses the optional step value of a numeric `for` loop.
     fn parse_numeric_for_step(&mut self) -> Result<()> {
         let next_token = self.input.next()?;
@@ -484,8 +1022,8 @@ impl<'a> Parser<'a> {
                 Ok(())
             }
             TokenType::Do => {
-                let i = self.find_or_add_number(1.0)?;
-                self.push(Instr::PushNum(i));
+                let i = self.find_or_add_integer(1)?;
+                self.push(Instr::PushInt(i));
                 Ok(())
             }
             _ => Err(self.err_unexpected(next_token, TokenType::Do)),
@@ -506,13 +1044,17 @@ impl<'a> Parser<'a> {
     fn parse_repeat(&mut self) -> Result<()> {
         self.input.next()?; // `repeat` keyword
         self.nest_level += 1;
+        self.loop_start();
         let body_start = self.chunk.code.len() as isize;
         self.parse_statements()?;
         self.expect(TokenType::Until)?;
         self.parse_expr()?;
+        // The condition can see the body's locals, so they are only closed
+        // after it is evaluated.
+        self.level_down();
         let expr_end = self.chunk.code.len() as isize;
         self.push(Instr::BranchFalse(body_start - (expr_end + 1)));
-        self.level_down();
+        self.loop_end();
         Ok(())
     }
 
@@ -520,22 +1062,23 @@ impl<'a> Parser<'a> {
     fn parse_while(&mut self) -> Result<()> {
         self.input.next()?; // `while` keyword
         self.nest_level += 1;
+        self.loop_start();
         let condition_start = self.chunk.code.len() as isize;
         self.parse_expr()?;
         self.expect(TokenType::Do)?;
-        let mut old_output = Vec::new();
-        swap(&mut self.chunk.code, &mut old_output);
-        self.parse_statements()?;
-        old_output.push(Instr::BranchFalse(self.chunk.code.len() as isize + 1));
-        old_output.append(&mut self.chunk.code);
-        self.chunk.code = old_output;
+        let branch_instr_index = self.chunk.code.len();
+        self.push(Instr::BranchFalse(0));
 
+        self.parse_statements()?;
         self.expect(TokenType::End)?;
+        self.level_down();
         self.push(Instr::Jump(
             condition_start - (self.chunk.code.len() as isize + 1),
         ));
-        self.level_down();
 
+        let branch_offset = (self.chunk.code.len() - branch_instr_index - 1) as isize;
+        self.chunk.code[branch_instr_index] = Instr::BranchFalse(branch_offset);
+        self.loop_end();
         Ok(())
     }
 
@@ -556,6 +1099,7 @@ impl<'a> Parser<'a> {
         self.push(Instr::BranchFalse(0));
 
         self.parse_statements()?;
+        self.level_down();
         let mut branch_target = self.chunk.code.len();
 
         self.close_if_arm()?;
@@ -573,7 +1117,6 @@ impl<'a> Parser<'a> {
     /// Parses the closing keyword of an `if` or `elseif` arms, and any arms
     /// that may follow.
     fn close_if_arm(&mut self) -> Result<()> {
-        self.level_down();
         match self.input.peek_type()? {
             TokenType::ElseIf => self.parse_else_or_elseif(true),
             TokenType::Else => self.parse_else_or_elseif(false),
@@ -633,7 +1176,7 @@ impl<'a> Parser<'a> {
         self.parse_or()
     }
 
-    /// Parses an `or` expression. Precedence 8.
+    /// Parses an `or` expression. Precedence 12.
     fn parse_or(&mut self) -> Result<ExpDesc> {
         let mut exp_desc = self.parse_and()?;
 
@@ -651,7 +1194,7 @@ impl<'a> Parser<'a> {
         Ok(exp_desc)
     }
 
-    /// Parses `and` expression. Precedence 7.
+    /// Parses `and` expression. Precedence 11.
     fn parse_and(&mut self) -> Result<ExpDesc> {
         let mut exp_desc = self.parse_comparison()?;
 
@@ -669,11 +1212,11 @@ impl<'a> Parser<'a> {
         Ok(exp_desc)
     }
 
-    /// Parses a comparison expression. Precedence 6.
+    /// Parses a comparison expression. Precedence 10.
     ///
     /// `==`, `~=`, `<`, `<=`, `>`, `>=`
     fn parse_comparison(&mut self) -> Result<ExpDesc> {
-        let mut exp_desc = self.parse_concat()?;
+        let mut exp_desc = self.parse_bitwise_or()?;
         loop {
             let instr = match self.input.peek_type()? {
                 TokenType::Less => Instr::Less,
@@ -686,6 +1229,56 @@ impl<'a> Parser<'a> {
             };
             exp_desc = ExpDesc::Other;
             self.input.next()?;
+            self.parse_bitwise_or()?;
+            self.push(instr);
+        }
+        Ok(exp_desc)
+    }
+
+    /// Parses a bitwise or expression (`|`). Precedence 9.
+    fn parse_bitwise_or(&mut self) -> Result<ExpDesc> {
+        let mut exp_desc = self.parse_bitwise_xor()?;
+        while self.input.try_pop(TokenType::Pipe)?.is_some() {
+            exp_desc = ExpDesc::Other;
+            self.parse_bitwise_xor()?;
+            self.push(Instr::BitOr);
+        }
+        Ok(exp_desc)
+    }
+
+    /// Parses a bitwise exclusive or expression (binary `~`). Precedence 8.
+    fn parse_bitwise_xor(&mut self) -> Result<ExpDesc> {
+        let mut exp_desc = self.parse_bitwise_and()?;
+        while self.input.try_pop(TokenType::Tilde)?.is_some() {
+            exp_desc = ExpDesc::Other;
+            self.parse_bitwise_and()?;
+            self.push(Instr::BitXor);
+        }
+        Ok(exp_desc)
+    }
+
+    /// Parses a bitwise and expression (`&`). Precedence 7.
+    fn parse_bitwise_and(&mut self) -> Result<ExpDesc> {
+        let mut exp_desc = self.parse_shift()?;
+        while self.input.try_pop(TokenType::Ampersand)?.is_some() {
+            exp_desc = ExpDesc::Other;
+            self.parse_shift()?;
+            self.push(Instr::BitAnd);
+        }
+        Ok(exp_desc)
+    }
+
+    /// Parses a shift expression (`<<`, `>>`). Precedence 6.
+    fn parse_shift(&mut self) -> Result<ExpDesc> {
+        let mut exp_desc = self.parse_concat()?;
+        loop {
+            let instr = match self.input.peek_type()? {
+                TokenType::ShiftLeft => Instr::ShiftLeft,
+                TokenType::ShiftRight => Instr::ShiftRight,
+                _ => break,
+            };
+            exp_desc = ExpDesc::Other;
+            self.input.next()?;
             self.parse_concat()?;
             self.push(instr);
         }
@@ -721,13 +1314,14 @@ impl<'a> Parser<'a> {
         Ok(exp_desc)
     }
 
-    /// Parses a multiplication expression (`*`, `/`, `%`). Precedence 3.
+    /// Parses a multiplication expression (`*`, `/`, `//`, `%`). Precedence 3.
     fn parse_multiplication(&mut self) -> Result<ExpDesc> {
         let mut exp_desc = self.parse_unary()?;
         loop {
             let instr = match self.input.peek_type()? {
                 TokenType::Star => Instr::Multiply,
                 TokenType::Slash => Instr::Divide,
+                TokenType::DoubleSlash => Instr::FloorDivide,
                 TokenType::Mod => Instr::Mod,
                 _ => break,
             };
@@ -739,12 +1333,13 @@ impl<'a> Parser<'a> {
         Ok(exp_desc)
     }
 
-    /// Parses a unary expression (`not`, `#`, `-`). Precedence 2.
+    /// Parses a unary expression (`not`, `#`, `-`, `~`). Precedence 2.
     fn parse_unary(&mut self) -> Result<ExpDesc> {
         let instr = match self.input.peek_type()? {
             TokenType::Not => Instr::Not,
             TokenType::Hash => Instr::Length,
             TokenType::Minus => Instr::Negate,
+            TokenType::Tilde => Instr::BitNot,
             _ => {
                 return self.parse_pow();
             }
@@ -811,7 +1406,7 @@ impl<'a> Parser<'a> {
                 self.eval_prefix_exp(base_expr);
                 self.input.next()?;
                 let name = self.expect_identifier()?;
-                let i = self.find_or_add_string(&name)?;
+                let i = self.find_or_add_string(name.as_bytes())?;
                 let prefix = PlaceExp::FieldAccess(i).into();
                 self.parse_prefix_extension(prefix)
             }
@@ -823,16 +1418,20 @@ impl<'a> Parser<'a> {
                 let prefix = PlaceExp::TableIndex.into();
                 self.parse_prefix_extension(prefix)
             }
-            TokenType::LParen => {
+            TokenType::LParen | TokenType::LiteralString | TokenType::LCurly => {
                 self.eval_prefix_exp(base_expr);
-                self.input.next()?;
-                let (num_args, _) = self.parse_call()?;
-                let prefix = PrefixExp::FunctionCall(num_args);
+                self.parse_call(false)?;
+                let prefix = PrefixExp::FunctionCall;
                 self.parse_prefix_extension(prefix)
             }
-            TokenType::Colon => panic!("Method calls unsupported"),
-            TokenType::LiteralString | TokenType::LCurly => {
-                panic!("Unparenthesized function calls unsupported")
+            TokenType::Colon => {
+                self.eval_prefix_exp(base_expr);
+                self.input.next()?;
+                let name_id = self.expect_identifier_id()?;
+                self.push(Instr::GetMethod(name_id));
+                self.parse_call(true)?;
+                let prefix = PrefixExp::FunctionCall;
+                self.parse_prefix_extension(prefix)
             }
             _ => Ok(base_expr),
         }
@@ -850,30 +1449,43 @@ impl<'a> Parser<'a> {
             TokenType::LCurly => self.parse_table()?,
             TokenType::LiteralNumber => {
                 let text = self.get_text(tok);
-                let number = text.parse::<f64>().unwrap();
-                let idx = self.find_or_add_number(number)?;
-                self.push(Instr::PushNum(idx));
+                let is_float = text.contains(['.', 'e', 'E']);
+                // A decimal integer which doesn't fit becomes a float.
+                match text.parse::<i64>() {
+                    Ok(number) if !is_float => {
+                        let idx = self.find_or_add_integer(number)?;
+                        self.push(Instr::PushInt(idx));
+                    }
+                    _ => {
+                        let number = text.parse::<f64>().unwrap();
+                        let idx = self.find_or_add_number(number)?;
+                        self.push(Instr::PushNum(idx));
+                    }
+                }
             }
             TokenType::LiteralHexNumber => {
-                // Cut off the "0x"
+                // Cut off the "0x". Hexadecimal integers wrap around.
                 let text = &self.get_text(tok)[2..];
-                let number = u128::from_str_radix(text, 16).unwrap() as f64;
-                let idx = self.find_or_add_number(number)?;
-                self.push(Instr::PushNum(idx));
-            }
-            TokenType::LiteralString => {
-                let text = self.get_literal_string_contents(tok);
-                let idx = self.find_or_add_string(text)?;
-                self.push(Instr::PushString(idx));
+                let number = text.chars().fold(0i64, |acc, c| {
+                    let digit = c.to_digit(16).unwrap() as i64;
+                    acc.wrapping_mul(16).wrapping_add(digit)
+                });
+                let idx = self.find_or_add_integer(number)?;
+                self.push(Instr::PushInt(idx));
             }
+            TokenType::LiteralString => self.push_literal_string(tok)?,
             TokenType::Function => {
-                self.parse_fndef()?;
+                self.parse_fndef(false)?;
             }
             TokenType::Nil => self.push(Instr::PushNil),
             TokenType::False => self.push(Instr::PushBool(false)),
             TokenType::True => self.push(Instr::PushBool(true)),
             TokenType::DotDotDot => {
-                return Err(self.error(ErrorKind::UnsupportedFeature));
+                if !self.chunk.is_vararg {
+                    return Err(self.error_at(SyntaxError::InvalidVarArg, tok.start));
+                }
+                self.push(Instr::VarArg(1));
+                return Ok(ExpDesc::VarArg);
             }
             _ => {
                 return Err(self.err_unexpected(tok, TokenType::Nil));
@@ -882,33 +1494,42 @@ impl<'a> Parser<'a> {
         Ok(ExpDesc::Other)
     }
 
-    /// Parses the parameters in a function definition.
-    fn parse_params(&mut self) -> Result<Vec<&'a str>> {
+    /// Parses the parameters in a function definition. Also returns whether
+    /// the parameters end with `...`.
+    fn parse_params(&mut self) -> Result<(Vec<&'a str>, bool)> {
         self.expect(TokenType::LParen)?;
         let mut args = Vec::new();
         if self.input.try_pop(TokenType::RParen)?.is_some() {
-            return Ok(args);
+            return Ok((args, false));
         }
-        args.push(self.expect_identifier()?);
-        while self.input.try_pop(TokenType::Comma)?.is_some() {
+        let mut is_vararg = false;
+        loop {
+            if self.input.try_pop(TokenType::DotDotDot)?.is_some() {
+                is_vararg = true;
+                break;
+            }
             args.push(self.expect_identifier()?);
+            if self.input.try_pop(TokenType::Comma)?.is_none() {
+                break;
+            }
         }
         self.expect(TokenType::RParen)?;
-        Ok(args)
+        Ok((args, is_vararg))
     }
 
-    /// Parses the parameters and body of a function definition.
-    fn parse_fndef(&mut self) -> Result<()> {
-        let params = self.parse_params()?;
+    /// Parses the parameters and body of a function definition. A method gets
+    /// an extra parameter, `self`, before the others.
+    fn parse_fndef(&mut self, is_method: bool) -> Result<()> {
+        let (mut params, is_vararg) = self.parse_params()?;
+        if is_method {
+            params.insert(0, "self");
+        }
         if self.chunk.nested.len() >= u8::MAX as usize {
             return Err(self.error(SyntaxError::Complexity));
         }
 
-        self.nest_level += 1;
-        let new_chunk = self.parse_chunk(&params)?;
-        self.level_down();
-
-        self.chunk.nested.push(new_chunk);
+        let new_chunk = self.parse_chunk(&params, is_vararg)?;
+        self.chunk.nested.push(Rc::new(new_chunk));
         self.push(Instr::Closure(self.chunk.nested.len() as u8 - 1));
         self.expect(TokenType::End)?;
         Ok(())
@@ -919,34 +1540,41 @@ impl<'a> Parser<'a> {
         self.push(Instr::NewTable);
         if self.input.try_pop(TokenType::RCurly)?.is_none() {
             // i is the number of array-style entries.
-            let mut i = 0;
-            i = self.parse_table_entry(i)?;
+            let (mut i, mut last_exp) = self.parse_table_entry(0)?;
             while let TokenType::Comma | TokenType::Semi = self.input.peek_type()? {
                 self.input.next()?;
                 if self.input.check_type(TokenType::RCurly)? {
                     break;
                 } else {
-                    i = self.parse_table_entry(i)?;
+                    let (new_i, exp) = self.parse_table_entry(i)?;
+                    i = new_i;
+                    last_exp = exp;
                 }
             }
             self.expect(TokenType::RCurly)?;
 
             if i > 0 {
-                self.push(Instr::SetList(i));
+                // If the last entry was array-style, all its values are used.
+                if self.expand_last_exp(&last_exp) {
+                    self.push(Instr::SetListMulti(i - 1));
+                } else {
+                    self.push(Instr::SetList(i));
+                }
             }
         }
         Ok(())
     }
 
-    /// Parses a table entry.
-    fn parse_table_entry(&mut self, counter: u8) -> Result<u8> {
+    /// Parses a table entry. Returns the new count of array-style entries,
+    /// and the entry's expression if it was array-style.
+    fn parse_table_entry(&mut self, counter: u8) -> Result<(u8, ExpDesc)> {
         match self.input.peek_type()? {
-            TokenType::Identifier => {
+            TokenType::Identifier if self.input.peek_type2()? == TokenType::Assign => {
                 let index = self.expect_identifier_id().unwrap();
                 self.expect(TokenType::Assign)?;
                 self.parse_expr()?;
                 self.push(Instr::InitField(counter, index));
-                Ok(counter)
+                Ok((counter, ExpDesc::Other))
             }
             TokenType::LSquare => {
                 self.input.next().unwrap();
@@ -955,36 +1583,74 @@ impl<'a> Parser<'a> {
                 self.expect(TokenType::Assign)?;
                 self.parse_expr()?;
                 self.push(Instr::InitIndex(counter));
-                Ok(counter)
+                Ok((counter, ExpDesc::Other))
             }
             _ => {
                 if counter == u8::MAX {
                     return Err(self.error(SyntaxError::Complexity));
                 }
-                self.parse_expr()?;
-                Ok(counter + 1)
+                let exp_desc = self.parse_expr()?;
+                Ok((counter + 1, exp_desc))
             }
         }
     }
 
-    /// Parses a function call. Returns the number of arguments.
-    fn parse_call(&mut self) -> Result<(u8, ExpDesc)> {
-        let tup = if self.input.check_type(TokenType::RParen)? {
-            (0, ExpDesc::Other)
-        } else {
-            self.parse_explist()?
-        };
+    /// Emits code to push the contents of a literal string token.
+    fn push_literal_string(&mut self, tok: Token) -> Result<()> {
+        let text = self.get_literal_string_contents(tok);
+        let idx = self.find_or_add_string(&text)?;
+        self.push(Instr::PushString(idx));
+        Ok(())
+    }
+
+    /// Parses the arguments of a function call, then emits the call. The call
+    /// returns a single value, unless changed by `set_call_returns`. A method
+    /// call has already pushed its receiver as the first argument.
+    ///
+    /// The arguments are either a list in parentheses, or a single table
+    /// constructor or literal string, as in `f{...}` or `f"str"`.
+    fn parse_call(&mut self, is_method: bool) -> Result<()> {
+        let num_self_args = is_method as u8;
+        let tok = self.input.next()?;
+        match tok.typ {
+            TokenType::LParen => (),
+            TokenType::LCurly => {
+                self.parse_table()?;
+                self.push(Instr::Call(num_self_args + 1, 1));
+                return Ok(());
+            }
+            TokenType::LiteralString => {
+                self.push_literal_string(tok)?;
+                self.push(Instr::Call(num_self_args + 1, 1));
+                return Ok(());
+            }
+            _ => return Err(self.err_unexpected(tok, TokenType::LParen)),
+        }
+        if self.input.try_pop(TokenType::RParen)?.is_some() {
+            self.push(Instr::Call(num_self_args, 1));
+            return Ok(());
+        }
+        let (num_args, last_exp) = self.parse_explist()?;
         self.expect(TokenType::RParen)?;
-        Ok(tup)
+        let num_args = match num_args.checked_add(num_self_args) {
+            Some(n) => n,
+            None => return Err(self.error(SyntaxError::Complexity)),
+        };
+        if self.expand_last_exp(&last_exp) {
+            self.push(Instr::CallMulti(num_args - 1, 1));
+        } else {
+            self.push(Instr::Call(num_args, 1));
+        }
+        Ok(())
     }
 }
 
 /// Finds the index of the last local entry which matches `name`.
-fn find_last_local(locals: &[(String, i32)], name: &str) -> Option<usize> {
+fn find_last_local(locals: &[LocalVar], name: &str) -> Option<usize> {
     let mut i = locals.len();
     while i > 0 {
         i -= 1;
-        if locals[i].0 == name {
+        if locals[i].name == name {
             return Some(i);
         }
     }
@@ -1017,6 +1683,9 @@ mod tests {
     use super::parse_str;
     use super::Chunk;
     use super::Instr::{self, *};
+    use super::UpvalueDesc;
+    use super::MULTRET;
+    use std::rc::Rc;
 
     fn check_it(input: &str, output: Chunk) {
         assert_eq!(parse_str(input).unwrap(), output);
@@ -1026,9 +1695,10 @@ mod tests {
     fn test01() {
         let text = "x = 5 + 6";
         let out = Chunk {
-            code: vec![PushNum(0), PushNum(1), Add, SetGlobal(0), Return(0)],
-            number_literals: vec![5.0, 6.0],
+            code: vec![PushInt(0), PushInt(1), Add, SetGlobal(0), Return(0)],
+            integer_literals: vec![5, 6],
             string_literals: vec!["x".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, out);
@@ -1038,9 +1708,10 @@ mod tests {
     fn test02() {
         let text = "x = -5^2";
         let out = Chunk {
-            code: vec![PushNum(0), PushNum(1), Pow, Negate, SetGlobal(0), Return(0)],
-            number_literals: vec![5.0, 2.0],
+            code: vec![PushInt(0), PushInt(1), Pow, Negate, SetGlobal(0), Return(0)],
+            integer_literals: vec![5, 2],
             string_literals: vec!["x".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, out);
@@ -1051,7 +1722,7 @@ mod tests {
         let text = "x = 5 + true .. 'hi'";
         let out = Chunk {
             code: vec![
-                PushNum(0),
+                PushInt(0),
                 PushBool(true),
                 Add,
                 PushString(1),
@@ -1059,8 +1730,9 @@ mod tests {
                 SetGlobal(0),
                 Return(0),
             ],
-            number_literals: vec![5.0],
+            integer_literals: vec![5],
             string_literals: vec!["x".into(), "hi".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, out);
@@ -1071,16 +1743,17 @@ mod tests {
         let text = "x = 1 .. 2 + 3";
         let output = Chunk {
             code: vec![
-                PushNum(0),
-                PushNum(1),
-                PushNum(2),
+                PushInt(0),
+                PushInt(1),
+                PushInt(2),
                 Add,
                 Concat,
                 SetGlobal(0),
                 Return(0),
             ],
-            number_literals: vec![1.0, 2.0, 3.0],
+            integer_literals: vec![1, 2, 3],
             string_literals: vec!["x".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, output);
@@ -1090,9 +1763,10 @@ mod tests {
     fn test05() {
         let text = "x = 2^-3";
         let output = Chunk {
-            code: vec![PushNum(0), PushNum(1), Negate, Pow, SetGlobal(0), Return(0)],
-            number_literals: vec![2.0, 3.0],
+            code: vec![PushInt(0), PushInt(1), Negate, Pow, SetGlobal(0), Return(0)],
+            integer_literals: vec![2, 3],
             string_literals: vec!["x".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, output);
@@ -1102,9 +1776,10 @@ mod tests {
     fn test06() {
         let text = "x=  not not 1";
         let output = Chunk {
-            code: vec![PushNum(0), Instr::Not, Instr::Not, SetGlobal(0), Return(0)],
-            number_literals: vec![1.0],
+            code: vec![PushInt(0), Instr::Not, Instr::Not, SetGlobal(0), Return(0)],
+            integer_literals: vec![1],
             string_literals: vec!["x".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, output);
@@ -1114,9 +1789,10 @@ mod tests {
     fn test07() {
         let text = "a = 5";
         let output = Chunk {
-            code: vec![PushNum(0), SetGlobal(0), Return(0)],
-            number_literals: vec![5.0],
-            string_literals: vec!["a".to_string()],
+            code: vec![PushInt(0), SetGlobal(0), Return(0)],
+            integer_literals: vec![5],
+            string_literals: vec!["a".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, output);
@@ -1135,6 +1811,7 @@ mod tests {
                 Return(0),
             ],
             string_literals: vec!["x".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, output);
@@ -1144,7 +1821,7 @@ mod tests {
     fn test09() {
         let text = "x =  5 or nil and true";
         let code = vec![
-            PushNum(0),
+            PushInt(0),
             BranchTrueKeep(5),
             Pop,
             PushNil,
@@ -1156,8 +1833,9 @@ mod tests {
         ];
         let output = Chunk {
             code,
-            number_literals: vec![5.0],
+            integer_literals: vec![5],
             string_literals: vec!["x".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, output);
@@ -1169,14 +1847,15 @@ mod tests {
         let code = vec![
             PushBool(true),
             BranchFalse(2),
-            PushNum(0),
+            PushInt(0),
             SetGlobal(0),
             Return(0),
         ];
         let chunk = Chunk {
             code,
-            number_literals: vec![5.0],
-            string_literals: vec!["a".to_string()],
+            integer_literals: vec![5],
+            string_literals: vec!["a".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1188,18 +1867,19 @@ mod tests {
         let code = vec![
             PushBool(true),
             BranchFalse(6),
-            PushNum(0),
+            PushInt(0),
             SetGlobal(0),
             PushBool(true),
             BranchFalse(2),
-            PushNum(1),
+            PushInt(1),
             SetGlobal(1),
             Return(0),
         ];
         let chunk = Chunk {
             code,
-            number_literals: vec![5.0, 4.0],
-            string_literals: vec!["a".to_string(), "b".to_string()],
+            integer_literals: vec![5, 4],
+            string_literals: vec!["a".into(), "b".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1211,17 +1891,18 @@ mod tests {
         let code = vec![
             PushBool(true),
             BranchFalse(3),
-            PushNum(0),
+            PushInt(0),
             SetGlobal(0),
             Jump(2),
-            PushNum(1),
+            PushInt(1),
             SetGlobal(0),
             Return(0),
         ];
         let chunk = Chunk {
             code,
-            number_literals: vec![5.0, 4.0],
-            string_literals: vec!["a".to_string()],
+            integer_literals: vec![5, 4],
+            string_literals: vec!["a".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1233,24 +1914,25 @@ mod tests {
         let code = vec![
             PushBool(true),
             BranchFalse(3),
-            PushNum(0),
+            PushInt(0),
             SetGlobal(0),
             Jump(9),
-            PushNum(1),
-            PushNum(2),
+            PushInt(1),
+            PushInt(2),
             Instr::Equal,
             BranchFalse(3),
-            PushNum(3),
+            PushInt(3),
             SetGlobal(0),
             Jump(2),
-            PushNum(4),
+            PushInt(4),
             SetGlobal(0),
             Return(0),
         ];
         let chunk = Chunk {
             code,
-            number_literals: vec![5.0, 6.0, 7.0, 3.0, 4.0],
-            string_literals: vec!["a".to_string()],
+            integer_literals: vec![5, 6, 7, 3, 4],
+            string_literals: vec!["a".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1261,11 +1943,11 @@ mod tests {
         let text = "while a < 10 do a = a + 1 end";
         let code = vec![
             GetGlobal(0),
-            PushNum(0),
+            PushInt(0),
             Instr::Less,
             BranchFalse(5),
             GetGlobal(0),
-            PushNum(1),
+            PushInt(1),
             Add,
             SetGlobal(0),
             Jump(-9),
@@ -1273,8 +1955,9 @@ mod tests {
         ];
         let chunk = Chunk {
             code,
-            number_literals: vec![10.0, 1.0],
-            string_literals: vec!["a".to_string()],
+            integer_literals: vec![10, 1],
+            string_literals: vec!["a".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1284,21 +1967,22 @@ mod tests {
     fn test15() {
         let text = "repeat local x = 5 until a == b y = 4";
         let code = vec![
-            PushNum(0),
+            PushInt(0),
             SetLocal(0),
             GetGlobal(0),
             GetGlobal(1),
             Instr::Equal,
             BranchFalse(-6),
-            PushNum(1),
+            PushInt(1),
             SetGlobal(2),
             Return(0),
         ];
         let chunk = Chunk {
             code,
-            number_literals: vec![5.0, 4.0],
+            integer_literals: vec![5, 4],
             string_literals: vec!["a".into(), "b".into(), "y".into()],
             num_locals: 1,
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1307,11 +1991,12 @@ mod tests {
     #[test]
     fn test16() {
         let text = "local i i = 2";
-        let code = vec![PushNil, SetLocal(0), PushNum(0), SetLocal(0), Return(0)];
+        let code = vec![PushNil, SetLocal(0), PushInt(0), SetLocal(0), Return(0)];
         let chunk = Chunk {
             code,
-            number_literals: vec![2.0],
+            integer_literals: vec![2],
             num_locals: 1,
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1334,6 +2019,7 @@ mod tests {
             code,
             string_literals: vec!["print".into()],
             num_locals: 2,
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1357,6 +2043,7 @@ mod tests {
             code,
             string_literals: vec!["x".into()],
             num_locals: 2,
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1378,6 +2065,7 @@ mod tests {
             code,
             string_literals: vec!["x".into(), "i".into()],
             num_locals: 1,
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1402,6 +2090,7 @@ mod tests {
             code,
             string_literals: vec!["x".into()],
             num_locals: 2,
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1411,9 +2100,9 @@ mod tests {
     fn test21() {
         let text = "for i = 1,5 do x = i end";
         let code = vec![
-            PushNum(0),
-            PushNum(1),
-            PushNum(0),
+            PushInt(0),
+            PushInt(1),
+            PushInt(0),
             ForPrep(0, 3),
             GetLocal(3),
             SetGlobal(0),
@@ -1422,9 +2111,10 @@ mod tests {
         ];
         let chunk = Chunk {
             code,
-            number_literals: vec![1.0, 5.0],
+            integer_literals: vec![1, 5],
             string_literals: vec!["x".into()],
             num_locals: 4,
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1433,11 +2123,12 @@ mod tests {
     #[test]
     fn test22() {
         let text = "a, b = 1";
-        let code = vec![PushNum(0), PushNil, SetGlobal(1), SetGlobal(0), Return(0)];
+        let code = vec![PushInt(0), PushNil, SetGlobal(1), SetGlobal(0), Return(0)];
         let chunk = Chunk {
             code,
-            number_literals: vec![1.0],
-            string_literals: vec!["a".to_string(), "b".to_string()],
+            integer_literals: vec![1],
+            string_literals: vec!["a".into(), "b".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1447,16 +2138,17 @@ mod tests {
     fn test23() {
         let text = "a, b = 1, 2";
         let code = vec![
-            PushNum(0),
-            PushNum(1),
+            PushInt(0),
+            PushInt(1),
             SetGlobal(1),
             SetGlobal(0),
             Return(0),
         ];
         let chunk = Chunk {
             code,
-            number_literals: vec![1.0, 2.0],
-            string_literals: vec!["a".to_string(), "b".to_string()],
+            integer_literals: vec![1, 2],
+            string_literals: vec!["a".into(), "b".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1466,9 +2158,9 @@ mod tests {
     fn test24() {
         let text = "a, b = 1, 2, 3";
         let code = vec![
-            PushNum(0),
-            PushNum(1),
-            PushNum(2),
+            PushInt(0),
+            PushInt(1),
+            PushInt(2),
             Pop,
             SetGlobal(1),
             SetGlobal(0),
@@ -1476,8 +2168,9 @@ mod tests {
         ];
         let chunk = Chunk {
             code,
-            number_literals: vec![1.0, 2.0, 3.0],
-            string_literals: vec!["a".to_string(), "b".to_string()],
+            integer_literals: vec![1, 2, 3],
+            string_literals: vec!["a".into(), "b".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1489,7 +2182,8 @@ mod tests {
         let code = vec![GetGlobal(0), Call(0, 0), Return(0)];
         let chunk = Chunk {
             code,
-            string_literals: vec!["puts".to_string()],
+            string_literals: vec!["puts".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1500,15 +2194,16 @@ mod tests {
         let text = "y = {x = 5,}";
         let code = vec![
             NewTable,
-            PushNum(0),
+            PushInt(0),
             InitField(0, 1),
             SetGlobal(0),
             Return(0),
         ];
         let chunk = Chunk {
             code,
-            number_literals: vec![5.0],
+            integer_literals: vec![5],
             string_literals: vec!["y".into(), "x".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1526,8 +2221,9 @@ mod tests {
         ];
         let chunk = Chunk {
             code,
-            string_literals: vec!["t".to_string(), "x".to_string(), "y".to_string()],
+            string_literals: vec!["t".into(), "x".into(), "y".into()],
             num_locals: 1,
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1538,14 +2234,15 @@ mod tests {
         let text = "x = function () end";
         let code = vec![Closure(0), SetGlobal(0), Return(0)];
         let string_literals = vec!["x".into()];
-        let nested = vec![Chunk {
+        let nested = vec![Rc::new(Chunk {
             code: vec![Return(0)],
             ..Chunk::default()
-        }];
+        })];
         let chunk = Chunk {
             code,
             string_literals,
             nested,
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1553,19 +2250,19 @@ mod tests {
 
     #[test]
     fn test29() {
-        let default = Chunk::default();
         let text = "x = function () local y = 7 end";
         let inner_chunk = Chunk {
-            code: vec![PushNum(0), SetLocal(0), Return(0)],
-            number_literals: vec![7.0],
+            code: vec![PushInt(0), SetLocal(0), Return(0)],
+            integer_literals: vec![7],
             num_locals: 1,
-            ..default
+            ..Chunk::default()
         };
         let outer_chunk = Chunk {
             code: vec![Closure(0), SetGlobal(0), Return(0)],
             string_literals: vec!["x".into()],
-            nested: vec![inner_chunk],
-            ..default
+            nested: vec![Rc::new(inner_chunk)],
+            is_vararg: true,
+            ..Chunk::default()
         };
         check_it(text, outer_chunk);
     }
@@ -1579,8 +2276,8 @@ mod tests {
             print(y)
         end";
         let z = Chunk {
-            code: vec![PushNum(0), SetLocal(0), Return(0)],
-            number_literals: vec![21.0],
+            code: vec![PushInt(0), SetLocal(0), Return(0)],
+            integer_literals: vec![21],
             num_locals: 1,
             ..Chunk::default()
         };
@@ -1598,7 +2295,7 @@ mod tests {
                 Return(0),
             ],
             string_literals: vec!["print".into()],
-            nested: vec![y],
+            nested: vec![Rc::new(y)],
             num_locals: 1,
             ..Chunk::default()
         };
@@ -1610,8 +2307,9 @@ mod tests {
                 SetGlobal(1),
                 Return(0),
             ],
-            nested: vec![z, x],
+            nested: vec![Rc::new(z), Rc::new(x)],
             string_literals: vec!["z".into(), "x".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, outer_chunk);
@@ -1620,12 +2318,13 @@ mod tests {
     #[test]
     fn test31() {
         let text = "local s = type(4)";
-        let code = vec![GetGlobal(0), PushNum(0), Call(1, 1), SetLocal(0), Return(0)];
+        let code = vec![GetGlobal(0), PushInt(0), Call(1, 1), SetLocal(0), Return(0)];
         let chunk = Chunk {
             code,
             num_locals: 1,
-            number_literals: vec![4.0],
+            integer_literals: vec![4],
             string_literals: vec!["type".into()],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
@@ -1642,13 +2341,432 @@ mod tests {
             GetLocal(1),
             GetLocal(0),
             PushNil,
-            Call(1, 1),
-            Call(1, 0),
+            Call(1, MULTRET),
+            CallMulti(0, 0),
             Return(0),
         ];
         let chunk = Chunk {
             code,
             num_locals: 2,
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test33() {
+        let text = "local x x = function () x = 1 return x end";
+        let inner = Chunk {
+            code: vec![
+                PushInt(0),
+                SetUpvalue(0),
+                GetUpvalue(0),
+                Return(1),
+                Return(0),
+            ],
+            integer_literals: vec![1],
+            upvalues: vec![UpvalueDesc::Local(0)],
+            ..Chunk::default()
+        };
+        let chunk = Chunk {
+            code: vec![PushNil, SetLocal(0), Closure(0), SetLocal(0), Return(0)],
+            num_locals: 1,
+            nested: vec![Rc::new(inner)],
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test34() {
+        let text = "do local x f = function () return function () return x end end end";
+        let innermost = Chunk {
+            code: vec![GetUpvalue(0), Return(1), Return(0)],
+            upvalues: vec![UpvalueDesc::Upvalue(0)],
+            ..Chunk::default()
+        };
+        let inner = Chunk {
+            code: vec![Closure(0), Return(1), Return(0)],
+            nested: vec![Rc::new(innermost)],
+            upvalues: vec![UpvalueDesc::Local(0)],
+            ..Chunk::default()
+        };
+        let chunk = Chunk {
+            code: vec![
+                PushNil,
+                SetLocal(0),
+                Closure(0),
+                SetGlobal(0),
+                Close(0),
+                Return(0),
+            ],
+            string_literals: vec!["f".into()],
+            num_locals: 1,
+            nested: vec![Rc::new(inner)],
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test35() {
+        let text = "local a, b = f() return a, f()";
+        let chunk = Chunk {
+            code: vec![
+                GetGlobal(0),
+                Call(0, 2),
+                SetLocal(1),
+                SetLocal(0),
+                GetLocal(0),
+                GetGlobal(0),
+                Call(0, MULTRET),
+                ReturnMulti(1),
+                Return(0),
+            ],
+            string_literals: vec!["f".into()],
+            num_locals: 2,
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test36() {
+        let text = "t = {f(), (f())} u = {f()}";
+        let chunk = Chunk {
+            code: vec![
+                NewTable,
+                GetGlobal(1),
+                Call(0, 1),
+                GetGlobal(1),
+                Call(0, 1),
+                SetList(2),
+                SetGlobal(0),
+                NewTable,
+                GetGlobal(1),
+                Call(0, MULTRET),
+                SetListMulti(0),
+                SetGlobal(2),
+                Return(0),
+            ],
+            string_literals: vec!["t".into(), "f".into(), "u".into()],
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test37() {
+        let text = "local f = function (a, ...) return a, ... end";
+        let inner = Chunk {
+            code: vec![GetLocal(0), VarArg(MULTRET), ReturnMulti(1), Return(0)],
+            num_params: 1,
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        let chunk = Chunk {
+            code: vec![Closure(0), SetLocal(0), Return(0)],
+            num_locals: 1,
+            nested: vec![Rc::new(inner)],
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test38() {
+        let text = "local f = function () return ... end";
+        assert!(parse_str(text).is_err());
+    }
+
+    #[test]
+    fn test39() {
+        let text = "while true do break end";
+        let chunk = Chunk {
+            code: vec![PushBool(true), BranchFalse(2), Jump(1), Jump(-4), Return(0)],
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test40() {
+        let text = "repeat local x f = function () return x end break until x";
+        let inner = Chunk {
+            code: vec![GetUpvalue(0), Return(1), Return(0)],
+            upvalues: vec![UpvalueDesc::Local(0)],
+            ..Chunk::default()
+        };
+        let chunk = Chunk {
+            code: vec![
+                PushNil,
+                SetLocal(0),
+                Closure(0),
+                SetGlobal(0),
+                Jump(3),
+                GetLocal(0),
+                Close(0),
+                BranchFalse(-8),
+                Close(0),
+                Return(0),
+            ],
+            string_literals: vec!["f".into()],
+            num_locals: 1,
+            nested: vec![Rc::new(inner)],
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test41() {
+        assert!(parse_str("break").is_err());
+        assert!(parse_str("while true do local f = function () break end end").is_err());
+    }
+
+    #[test]
+    fn test42() {
+        let text = "::a:: goto a goto b ::b::";
+        let chunk = Chunk {
+            code: vec![Jump(-1), Jump(0), Return(0)],
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test43() {
+        // A label at the end of a block is outside the scope of its locals.
+        assert!(parse_str("do goto a local x ::a:: end").is_ok());
+        assert!(parse_str("while x do goto a local x ::a:: ; end").is_ok());
+        assert!(parse_str("do goto a local x ::a:: x = 1 end").is_err());
+        assert!(parse_str("repeat goto a local x ::a:: until x").is_err());
+        // Labels are only visible in their own block and nested blocks.
+        assert!(parse_str("do ::a:: do goto a end end").is_ok());
+        assert!(parse_str("do do ::a:: end goto a end").is_err());
+        assert!(parse_str("::a:: local f = function () goto a end").is_err());
+        assert!(parse_str("goto a").is_err());
+        // Duplicate labels
+        assert!(parse_str("::a:: ::a::").is_err());
+        assert!(parse_str("::a:: do ::a:: end").is_err());
+        assert!(parse_str("do ::a:: end ::a::").is_ok());
+    }
+
+    #[test]
+    fn test44() {
+        let text = "for k, v in pairs(t) do x = v end";
+        let chunk = Chunk {
+            code: vec![
+                GetGlobal(0),
+                GetGlobal(1),
+                Call(1, 3),
+                SetLocal(2),
+                SetLocal(1),
+                SetLocal(0),
+                Jump(2),
+                GetLocal(4),
+                SetGlobal(2),
+                GenericForCall(0, 2),
+                GenericForLoop(0, -4),
+                Return(0),
+            ],
+            string_literals: vec!["pairs".into(), "t".into(), "x".into()],
+            num_locals: 5,
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test45() {
+        let text = "function a.b:m(x) end a.b:m(1)";
+        let inner = Chunk {
+            code: vec![Return(0)],
+            num_params: 2,
+            ..Chunk::default()
+        };
+        let chunk = Chunk {
+            code: vec![
+                GetGlobal(0),
+                GetField(1),
+                Closure(0),
+                SetField(0, 2),
+                GetGlobal(0),
+                GetField(1),
+                GetMethod(2),
+                PushInt(0),
+                Call(2, 0),
+                Return(0),
+            ],
+            integer_literals: vec![1],
+            string_literals: vec!["a".into(), "b".into(), "m".into()],
+            nested: vec![Rc::new(inner)],
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test46() {
+        let text = "x = 7 // 2.0 + 0x10 - 1e1 + 9223372036854775808";
+        let chunk = Chunk {
+            code: vec![
+                PushInt(0),
+                PushNum(0),
+                FloorDivide,
+                PushInt(1),
+                Add,
+                PushNum(1),
+                Subtract,
+                PushNum(2),
+                Add,
+                SetGlobal(0),
+                Return(0),
+            ],
+            number_literals: vec![2.0, 10.0, 9223372036854775808.0],
+            integer_literals: vec![7, 16],
+            string_literals: vec!["x".into()],
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test47() {
+        let text = "x = 1 | 2 ~ 3 & 4 << 5 .. 6 == ~7";
+        let chunk = Chunk {
+            code: vec![
+                PushInt(0),
+                PushInt(1),
+                PushInt(2),
+                PushInt(3),
+                PushInt(4),
+                PushInt(5),
+                Concat,
+                ShiftLeft,
+                BitAnd,
+                BitXor,
+                BitOr,
+                PushInt(6),
+                BitNot,
+                Equal,
+                SetGlobal(0),
+                Return(0),
+            ],
+            integer_literals: vec![1, 2, 3, 4, 5, 6, 7],
+            string_literals: vec!["x".into()],
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test48() {
+        let text = "local function g(x) return (f(x)) end return f(...)";
+        let inner = Chunk {
+            code: vec![GetGlobal(0), GetLocal(0), Call(1, 1), Return(1), Return(0)],
+            string_literals: vec!["f".into()],
+            num_params: 1,
+            ..Chunk::default()
+        };
+        let chunk = Chunk {
+            code: vec![
+                Closure(0),
+                SetLocal(0),
+                GetGlobal(0),
+                VarArg(MULTRET),
+                TailCallMulti(0),
+                ReturnMulti(0),
+                Return(0),
+            ],
+            string_literals: vec!["f".into()],
+            num_locals: 1,
+            is_vararg: true,
+            nested: vec![Rc::new(inner)],
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test49() {
+        let text = "do local x <close> = f() local y <const> = 1 return g(x) end";
+        let chunk = Chunk {
+            code: vec![
+                GetGlobal(0),
+                Call(0, 1),
+                SetLocal(0),
+                ToBeClosed(0, 1),
+                PushInt(0),
+                SetLocal(1),
+                GetGlobal(2),
+                GetLocal(0),
+                Call(1, MULTRET),
+                ReturnMulti(0),
+                Close(0),
+                Return(0),
+            ],
+            string_literals: vec!["f".into(), "x".into(), "g".into()],
+            integer_literals: vec![1],
+            num_locals: 2,
+            is_vararg: true,
+            ..Chunk::default()
+        };
+        check_it(text, chunk);
+    }
+
+    #[test]
+    fn test50() {
+        assert!(parse_str("local x <const> = 1 x = 2").is_err());
+        assert!(parse_str("local x <close> = nil x = 2").is_err());
+        assert!(parse_str("local x <const> = 1 local function f() x = 2 end").is_err());
+        assert!(
+            parse_str("local x <const> local function f() return function() x = 2 end end")
+                .is_err()
+        );
+        assert!(parse_str("local x <const> = 1 function x() end").is_err());
+        assert!(parse_str("local x <const> = 1 do local x = 2 x = 3 end").is_ok());
+        assert!(parse_str("local x <const>, y = 1 y = 2").is_ok());
+        assert!(parse_str("local x <close>, y <close> = 1, 2").is_err());
+        assert!(parse_str("local x <static> = 1").is_err());
+    }
+
+    #[test]
+    fn test51() {
+        let text = "f'a'[[b]]{} obj:m{1}";
+        let chunk = Chunk {
+            code: vec![
+                GetGlobal(0),
+                PushString(1),
+                Call(1, 1),
+                PushString(2),
+                Call(1, 1),
+                NewTable,
+                Call(1, 0),
+                GetGlobal(3),
+                GetMethod(4),
+                NewTable,
+                PushInt(0),
+                SetList(1),
+                Call(2, 0),
+                Return(0),
+            ],
+            string_literals: vec!["f".into(), "a".into(), "b".into(), "obj".into(), "m".into()],
+            integer_literals: vec![1],
+            is_vararg: true,
             ..Chunk::default()
         };
         check_it(text, chunk);
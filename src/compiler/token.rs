@@ -12,16 +12,17 @@ pub(super) struct Token {
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub(super) enum TokenType {
     // Keywords
-    And, Break, Do, Else, ElseIf, End, False, For, Function, If, In, Local,
+    And, Break, Do, Else, ElseIf, End, False, For, Function, Goto, If, In, Local,
     Nil, Not, Or, Repeat, Return, Then, True, Until, While,
     // Operator symbols
-    Plus, Minus, Star, Slash, Mod, Caret, Hash,
+    Plus, Minus, Star, Slash, DoubleSlash, Mod, Caret, Hash,
+    Ampersand, Tilde, Pipe, ShiftLeft, ShiftRight,
     // Comparisons
     Equal, NotEqual, LessEqual, GreaterEqual, Less, Greater,
     // L/R stuff
     LParen, RParen, LCurly, RCurly, LSquare, RSquare,
     // Other symbols
-    Semi, Colon, Comma, Dot, DotDot, DotDotDot, Assign,
+    Semi, Colon, DoubleColon, Comma, Dot, DotDot, DotDotDot, Assign,
     // Others
     Identifier,
     LiteralNumber,
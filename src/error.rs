@@ -33,24 +33,40 @@ pub struct ArgError {
 
 #[derive(Debug)]
 pub enum SyntaxError {
+    AssignToConst(String),
     BadNumber,
+    BreakOutsideLoop,
     Complexity,
+    DecimalEscapeTooLarge,
+    DuplicateLabel(String),
     InvalidCharacter,
+    InvalidEscape,
+    InvalidLongStringDelimiter,
+    InvalidVarArg,
+    JumpIntoScope(String),
+    MultipleToBeClosed,
     TooManyLocals,
     TooManyNumbers,
     TooManyStrings,
+    UnclosedLongComment,
+    UnclosedLongString,
     UnclosedString,
     UnexpectedEof,
     UnexpectedTok,
+    UndefinedLabel(String),
+    UnicodeEscapeTooLarge,
+    UnknownAttribute(String),
 }
 
 #[derive(Debug)]
 pub enum TypeError {
     Arithmetic(LuaType),
+    Bitwise(LuaType),
     Comparison(LuaType, LuaType),
     Concat(LuaType),
     FunctionCall(LuaType),
     Length(LuaType),
+    NoIntegerRep,
     TableIndex(LuaType),
     TableKeyNan,
     TableKeyNil,
@@ -99,7 +115,10 @@ impl SyntaxError {
     /// more text to the source code.
     pub fn is_recoverable(&self) -> bool {
         // matches!(self, Self::UnclosedString | Self::UnexpectedEof)
-        matches!(self, Self::UnexpectedEof)
+        matches!(
+            self,
+            Self::UnclosedLongComment | Self::UnclosedLongString | Self::UnexpectedEof
+        )
     }
 }
 
@@ -150,15 +169,29 @@ impl fmt::Display for SyntaxError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use SyntaxError::*;
         match self {
+            AssignToConst(name) => write!(f, "attempt to assign to const variable '{}'", name),
             BadNumber => write!(f, "malformed number"),
+            BreakOutsideLoop => write!(f, "break outside a loop"),
             Complexity => write!(f, "complexity"),
+            DecimalEscapeTooLarge => write!(f, "decimal escape too large"),
+            DuplicateLabel(name) => write!(f, "label '{}' already defined", name),
             InvalidCharacter => write!(f, "invalid character"),
+            InvalidEscape => write!(f, "invalid escape sequence"),
+            InvalidLongStringDelimiter => write!(f, "invalid long string delimiter"),
+            InvalidVarArg => write!(f, "cannot use '...' outside a vararg function"),
+            JumpIntoScope(name) => write!(f, "jumps into the scope of local '{}'", name),
+            MultipleToBeClosed => write!(f, "multiple to-be-closed variables in local list"),
             TooManyLocals => write!(f, "too many local variables"),
             TooManyNumbers => write!(f, "too many literal numbers"),
             TooManyStrings => write!(f, "too many literal strings"),
+            UnclosedLongComment => write!(f, "unfinished long comment"),
+            UnclosedLongString => write!(f, "unfinished long string"),
             UnclosedString => write!(f, "unfinished string"),
             UnexpectedEof => write!(f, "unexpected <eof>"),
             UnexpectedTok => write!(f, "syntax error"),
+            UndefinedLabel(name) => write!(f, "no visible label '{}' for goto", name),
+            UnicodeEscapeTooLarge => write!(f, "UTF-8 value too large"),
+            UnknownAttribute(name) => write!(f, "unknown attribute '{}'", name),
         }
     }
 }
@@ -168,10 +201,15 @@ impl fmt::Display for TypeError {
         use TypeError::*;
         match self {
             Arithmetic(typ) => write!(f, "attempt to perform arithmetic on a {} value", typ),
+            Bitwise(typ) => write!(f, "attempt to perform bitwise operation on a {} value", typ),
+            Comparison(type1, type2) if type1 == type2 => {
+                write!(f, "attempt to compare two {} values", type1)
+            }
             Comparison(type1, type2) => write!(f, "attempt to compare {} with {}", type1, type2),
             Concat(typ) => write!(f, "attempt to concatenate a {} value", typ),
             FunctionCall(typ) => write!(f, "attempt to call a {} value", typ),
             Length(typ) => write!(f, "attempt to get length of a {} value", typ),
+            NoIntegerRep => write!(f, "number has no integer representation"),
             TableIndex(typ) => write!(f, "attempt to index a {} value", typ),
             TableKeyNan => write!(f, "table index was NaN"),
             TableKeyNil => write!(f, "table index was nil"),
@@ -5,6 +5,10 @@
 ///
 /// Several others use a u8 parameter to index either the locals, the number
 /// literals, or the string literals.
+///
+/// A function call with `MULTRET` return values produces a number of values
+/// only known at runtime. The VM remembers that number, and the `*Multi`
+/// instruction which consumes the values adds it to its own count.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub(super) enum Instr {
     /// Move the instruction pointer by the given offset.
@@ -45,6 +49,24 @@ pub(super) enum Instr {
     /// index.
     SetLocal(u8),
 
+    /// Copy the given upvalue to the top of the stack.
+    GetUpvalue(u8),
+
+    /// Pop the value at the top of the stack, and place it in the given
+    /// upvalue.
+    SetUpvalue(u8),
+
+    /// Close every upvalue which refers to a local at the given index or
+    /// higher. The closed upvalues keep the current values of those locals.
+    /// The to-be-closed variables among those locals are closed too, from
+    /// the last one to the first.
+    Close(u8),
+
+    /// Mark local `op0` as a to-be-closed variable. Its value must have a
+    /// `__close` metamethod, unless it's `nil` or `false`. String literal
+    /// `op1` is the variable's name, used in the error message.
+    ToBeClosed(u8, u8),
+
     /// Create a new table and place it on the stack.
     NewTable,
 
@@ -88,6 +110,9 @@ pub(super) enum Instr {
     /// Fetch the number (float) from the literal set at the given index.
     PushNum(u8),
 
+    /// Fetch the integer from the literal set at the given index.
+    PushInt(u8),
+
     /// Fetch the string from the literal set at the given index.
     PushString(u8),
 
@@ -100,9 +125,41 @@ pub(super) enum Instr {
     /// parameter.
     ForLoop(u8, isize),
 
+    /// Calls the iterator of a generic for loop, which is in the local slot
+    /// of the first parameter. The state and control variable are in the
+    /// next two slots. The results are adjusted to the second parameter, and
+    /// placed into the slots after those.
+    GenericForCall(u8, u8),
+
+    /// End a generic for loop. If the first variable, which follows the
+    /// three locals starting at the first parameter, isn't `nil`, it becomes
+    /// the new control variable, and jump using the second parameter.
+    GenericForLoop(u8, isize),
+
+    /// Pop a table, then push its field named by the string literal at the
+    /// given index, followed by the table itself. This prepares a method
+    /// call.
+    GetMethod(u8),
+
     /// Function call (number of arguments, number of needed return values).
+    /// If the number of return values is `MULTRET`, all of them are kept.
     Call(u8, u8),
 
+    /// Like `Call`, but the arguments end with a multi-valued expression.
+    /// The first parameter only counts the arguments before it.
+    CallMulti(u8, u8),
+
+    /// Call a function with the given number of arguments, as the last
+    /// action of the current function, keeping all of the return values. A
+    /// Lua function replaces the current frame instead of adding a new one.
+    /// This is always followed by `ReturnMulti(0)`, which returns the
+    /// results when the frame couldn't be replaced.
+    TailCall(u8),
+
+    /// Like `TailCall`, but the arguments end with a multi-valued
+    /// expression.
+    TailCallMulti(u8),
+
     /// Add the two values on the top of the stack.
     Add,
 
@@ -115,6 +172,10 @@ pub(super) enum Instr {
     /// Divide the second value on the stack by the first.
     Divide,
 
+    /// Divide the second value on the stack by the first, rounding the
+    /// quotient towards negative infinity.
+    FloorDivide,
+
     /// Raise the second value on the stack to the power of the first.
     Pow,
 
@@ -122,6 +183,21 @@ pub(super) enum Instr {
     /// first.
     Mod,
 
+    /// Bitwise and of the two values on the top of the stack.
+    BitAnd,
+
+    /// Bitwise or of the two values on the top of the stack.
+    BitOr,
+
+    /// Bitwise exclusive or of the two values on the top of the stack.
+    BitXor,
+
+    /// Shift the second value on the stack left by the first.
+    ShiftLeft,
+
+    /// Shift the second value on the stack right by the first.
+    ShiftRight,
+
     /// Concatenate the two values on the top of the stack.
     Concat,
 
@@ -159,14 +235,30 @@ pub(super) enum Instr {
     /// stack.
     Negate,
 
+    /// Applies the bitwise not operator (unary `~`) to the value at the top
+    /// of the stack.
+    BitNot,
+
+    /// Push the extra arguments of a vararg function, adjusted to n values.
+    /// If n is `MULTRET`, all of them are pushed.
+    VarArg(u8),
+
     /// Return n values from the chunk.
     Return(u8),
 
-    /// Create a closure from a Chunk and push it onto the stack.
+    /// Return n values, followed by the values of a multi-valued expression.
+    ReturnMulti(u8),
+
+    /// Create a closure from a nested Chunk and push it onto the stack. The
+    /// closure captures the upvalues described by that Chunk.
     Closure(u8),
 
     /// Pop n values from the stack, then pop a table. Assign the last value
     /// popped to `table[1]`, the second-to-last value to `table[2]`, etc.
     /// Push the table back afterwards.
     SetList(u8),
+
+    /// Like `SetList`, but the values end with a multi-valued expression.
+    /// The parameter only counts the values before it.
+    SetListMulti(u8),
 }
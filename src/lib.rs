@@ -21,6 +21,8 @@ pub mod error;
 pub use vm::LuaType;
 pub use vm::RustFunc;
 pub use vm::State;
+pub use vm::ThreadStatus;
+pub use vm::MULTRET;
 
 use compiler::Chunk;
 use instr::Instr;
@@ -1,11 +1,17 @@
 //! Lua's standard library
 
 mod basic;
+mod coroutine;
+mod math;
 
 pub(crate) use basic::open_base;
+pub(crate) use coroutine::open_coroutine;
+pub(crate) use math::open_math;
 
 use crate::State;
 
 pub(crate) fn open_libs(state: &mut State) {
     open_base(state);
+    open_coroutine(state);
+    open_math(state);
 }
@@ -1,8 +1,12 @@
 //! Lua's Standard Library
 
+use std::io::{self, Write};
+
 use crate::error::ErrorKind;
 use crate::LuaType;
+use crate::Result;
 use crate::State;
+use crate::MULTRET;
 
 pub(crate) fn open_base(state: &mut State) {
     let mut add = |name, func| {
@@ -27,6 +31,28 @@ pub(crate) fn open_base(state: &mut State) {
         }
     });
 
+    // getmetatable(object)
+    //
+    // If `object` does not have a metatable, returns `nil`. Otherwise, if the
+    // object's metatable has a `__metatable` field, returns the associated
+    // value. Otherwise, returns the metatable of the given object.
+    add("getmetatable", |state| {
+        state.check_any(1)?;
+        state.set_top(1);
+        if !state.get_metatable(1) {
+            state.push_nil();
+            return Ok(1);
+        }
+        state.push_string("__metatable".into());
+        state.raw_get(2)?;
+        if state.typ(3) != LuaType::Nil {
+            state.replace(2);
+        } else {
+            state.pop(1);
+        }
+        Ok(1)
+    });
+
     add("ipairs", |state| {
         state.check_type(1, LuaType::Table)?;
         state.set_top(1);
@@ -34,13 +60,12 @@ pub(crate) fn open_base(state: &mut State) {
             state.check_type(1, LuaType::Table)?;
             state.check_type(2, LuaType::Number)?;
             state.set_top(2);
-            let old_index = state.to_number(2).unwrap();
-            let new_index = old_index + 1.0;
+            let old_index = state.to_integer(2)?;
+            let new_index = old_index.wrapping_add(1);
             state.pop(1); // pop the old number
-            state.push_number(new_index);
-            state.get_table(1)?;
+            state.get_i(1, new_index)?;
             if state.to_boolean(-1) {
-                state.push_number(new_index);
+                state.push_integer(new_index);
                 state.replace(1); // Replaces the table with the index
                 Ok(2)
             } else {
@@ -53,24 +78,150 @@ pub(crate) fn open_base(state: &mut State) {
         state.push_value(1);
         state.remove(1);
         // Push the initial index
-        state.push_number(0.0);
+        state.push_integer(0);
+        Ok(3)
+    });
+
+    add("next", next);
+
+    // pairs(t)
+    //
+    // Returns three values: the `next` function, the table `t`, and `nil`, so
+    // that `for k, v in pairs(t) do body end` will iterate over all key-value
+    // pairs of table `t`.
+    add("pairs", |state| {
+        state.check_type(1, LuaType::Table)?;
+        state.set_top(1);
+        state.push_rust_fn(next);
+        state.insert(1);
+        state.push_nil();
         Ok(3)
     });
 
     // Receives any number of arguments, and prints their values to `stdout`.
     add("print", |state| {
-        let range = 1..=state.get_top();
-        let mut strings = range.map(|i| state.to_string(i as isize));
-        if let Some(s) = strings.next() {
-            print!("{}", s);
-            for s in strings {
-                print!("\t{}", s);
+        let mut line = Vec::new();
+        for i in 1..=state.get_top() {
+            if i > 1 {
+                line.push(b'\t');
             }
+            line.extend(state.to_bytes(i as isize));
         }
-        println!();
+        line.push(b'\n');
+        io::stdout().write_all(&line)?;
         Ok(0)
     });
 
+    // rawequal(v1, v2)
+    //
+    // Checks whether `v1` is equal to `v2`, without invoking the `__eq`
+    // metamethod. Returns a boolean.
+    add("rawequal", |state| {
+        state.check_any(1)?;
+        state.check_any(2)?;
+        let result = state.raw_equal(1, 2);
+        state.set_top(0);
+        state.push_boolean(result);
+        Ok(1)
+    });
+
+    // rawget(table, index)
+    //
+    // Gets the real value of `table[index]`, without using the `__index`
+    // metavalue.
+    add("rawget", |state| {
+        state.check_type(1, LuaType::Table)?;
+        state.check_any(2)?;
+        state.set_top(2);
+        state.raw_get(1)?;
+        Ok(1)
+    });
+
+    // rawlen(v)
+    //
+    // Returns the length of `v`, which must be a table or a string, without
+    // invoking the `__len` metamethod. Returns an integer.
+    add("rawlen", |state| {
+        if !matches!(state.typ(1), LuaType::Table | LuaType::String) {
+            let msg = "bad argument #1 to 'rawlen' (table or string expected)".to_string();
+            return Err(state.error(ErrorKind::WithMessage(msg)));
+        }
+        let len = state.raw_len(1);
+        state.set_top(0);
+        state.push_integer(len as i64);
+        Ok(1)
+    });
+
+    // rawset(table, index, value)
+    //
+    // Sets the real value of `table[index]` to `value`, without using the
+    // `__newindex` metavalue. Returns `table`.
+    add("rawset", |state| {
+        state.check_type(1, LuaType::Table)?;
+        state.check_any(2)?;
+        state.check_any(3)?;
+        state.set_top(3);
+        state.raw_set(1)?;
+        Ok(1)
+    });
+
+    // select(index, ...)
+    //
+    // If `index` is a number, returns all arguments after argument number
+    // `index`; a negative number indexes from the end (-1 is the last
+    // argument). Otherwise, `index` must be the string "#", and `select`
+    // returns the total number of extra arguments it received.
+    add("select", |state| {
+        state.check_any(1)?;
+        let top = state.get_top() as isize;
+        if state.typ(1) == LuaType::String && state.to_string(1) == "#" {
+            state.set_top(0);
+            state.push_integer((top - 1) as i64);
+            return Ok(1);
+        }
+        state.check_type(1, LuaType::Number)?;
+        let mut n = state.to_integer(1)? as isize;
+        if n < 0 {
+            n += top;
+        } else if n > top {
+            n = top;
+        }
+        if n < 1 {
+            let msg = "bad argument #1 to 'select' (index out of range)".to_string();
+            return Err(state.error(ErrorKind::WithMessage(msg)));
+        }
+        if top - n >= MULTRET as isize {
+            let msg = "too many results to select".to_string();
+            return Err(state.error(ErrorKind::WithMessage(msg)));
+        }
+        Ok((top - n) as u8)
+    });
+
+    // setmetatable(table, metatable)
+    //
+    // Sets the metatable for the given table. If `metatable` is `nil`,
+    // removes the metatable of the given table. If the original metatable
+    // has a `__metatable` field, raises an error. Returns `table`.
+    add("setmetatable", |state| {
+        state.check_type(1, LuaType::Table)?;
+        if state.get_top() < 2 || !matches!(state.typ(2), LuaType::Nil | LuaType::Table) {
+            let msg = "bad argument #2 to 'setmetatable' (nil or table expected)".to_string();
+            return Err(state.error(ErrorKind::WithMessage(msg)));
+        }
+        state.set_top(2);
+        if state.get_metatable(1) {
+            state.push_string("__metatable".into());
+            state.raw_get(3)?;
+            if state.typ(4) != LuaType::Nil {
+                let msg = "cannot change a protected metatable".to_string();
+                return Err(state.error(ErrorKind::WithMessage(msg)));
+            }
+            state.pop(2);
+        }
+        state.set_metatable(1);
+        Ok(1)
+    });
+
     // Returns the type of its only argument, coded as a string.
     add("type", |state| {
         state.check_any(1)?;
@@ -88,17 +239,34 @@ pub(crate) fn open_base(state: &mut State) {
     // supported yet.
     add("unpack", |state| {
         state.check_type(1, LuaType::Table)?;
-        let mut i = 1.0;
-        loop {
-            state.push_number(i);
-            state.get_table(1)?;
-            if let LuaType::Nil = state.typ(-1) {
-                state.pop(1);
-                break;
-            } else {
-                i += 1.0;
-            }
+        state.set_top(1);
+        state.len(1)?;
+        let len = state.to_integer(-1)?;
+        state.pop(1);
+        if len >= MULTRET as i64 {
+            let msg = "too many results to unpack".to_string();
+            return Err(state.error(ErrorKind::WithMessage(msg)));
+        }
+        for i in 1..=len {
+            state.get_i(1, i)?;
         }
-        Ok(i as u8 - 1)
+        Ok(len.max(0) as u8)
     });
 }
+
+/// next(table [, index])
+///
+/// Returns the next index of the table and its associated value. When called
+/// with `nil` as its second argument, `next` returns an initial index and its
+/// associated value. When called with the last index, or with `nil` in an
+/// empty table, `next` returns `nil`.
+fn next(state: &mut State) -> Result<u8> {
+    state.check_type(1, LuaType::Table)?;
+    state.set_top(2);
+    if state.next(1)? {
+        Ok(2)
+    } else {
+        state.push_nil();
+        Ok(1)
+    }
+}
@@ -0,0 +1,139 @@
+//! Lua's `coroutine` library
+
+use crate::error::ErrorKind;
+use crate::LuaType;
+use crate::Result;
+use crate::State;
+use crate::MULTRET;
+
+pub(crate) fn open_coroutine(state: &mut State) {
+    state.new_table();
+    let mut add = |name, func| {
+        state.push_rust_fn(func);
+        state.set_field(-2, name).unwrap();
+    };
+
+    // coroutine.close(co)
+    //
+    // Closes coroutine `co`, which must be suspended or dead, putting it in
+    // a dead state. Returns `true`, unless the coroutine had stopped because
+    // of an error, in which case returns `false` plus the error message.
+    add("close", |state| {
+        state.check_type(1, LuaType::Thread)?;
+        state.set_top(1);
+        if state.close_thread(1)? {
+            state.push_boolean(true);
+            Ok(1)
+        } else {
+            state.push_boolean(false);
+            state.insert(-2);
+            Ok(2)
+        }
+    });
+
+    // coroutine.create(f)
+    //
+    // Creates a new coroutine, with body `f`, and returns it.
+    add("create", |state| {
+        state.check_type(1, LuaType::Function)?;
+        state.set_top(1);
+        state.new_thread();
+        Ok(1)
+    });
+
+    // coroutine.isyieldable()
+    //
+    // Returns `true` if the running coroutine can yield.
+    add("isyieldable", |state| {
+        let yieldable = state.is_yieldable();
+        state.push_boolean(yieldable);
+        Ok(1)
+    });
+
+    // coroutine.resume(co, ...)
+    //
+    // Starts or continues the execution of coroutine `co`, passing it the
+    // extra arguments. If the coroutine runs without errors, returns `true`
+    // plus the values passed to `yield` or returned by its body. If there is
+    // any error, returns `false` plus the error message.
+    add("resume", |state| {
+        state.check_type(1, LuaType::Thread)?;
+        let num_args = state.get_top() - 1;
+        match state.resume(1, num_args as u8) {
+            Ok(n) => {
+                state.push_boolean(true);
+                state.insert(-(n as isize) - 1);
+                num_results(state, n + 1)
+            }
+            Err(e) => {
+                state.push_boolean(false);
+                state.push_string(e.to_string());
+                Ok(2)
+            }
+        }
+    });
+
+    // coroutine.running()
+    //
+    // Returns the running coroutine plus a boolean, which is `true` when the
+    // running coroutine is the main one.
+    add("running", |state| {
+        let is_main = state.push_thread();
+        state.push_boolean(is_main);
+        Ok(2)
+    });
+
+    // coroutine.status(co)
+    //
+    // Returns the status of coroutine `co`, as a string: "running",
+    // "suspended", "normal", or "dead".
+    add("status", |state| {
+        state.check_type(1, LuaType::Thread)?;
+        let status = state.thread_status(1);
+        state.push_string(status.as_str().into());
+        Ok(1)
+    });
+
+    // coroutine.wrap(f)
+    //
+    // Creates a new coroutine, with body `f`, and returns a function which
+    // resumes the coroutine each time it is called. Its arguments are passed
+    // to `resume`, and it returns the same values as `resume`, except the
+    // first boolean. Errors are propagated to the caller.
+    add("wrap", |state| {
+        state.check_type(1, LuaType::Function)?;
+        state.set_top(1);
+        state.new_thread();
+        state.push_rust_closure(
+            |state| {
+                state.push_upvalue(1);
+                state.insert(1);
+                let num_args = state.get_top() - 1;
+                let n = state.resume(1, num_args as u8)?;
+                num_results(state, n)
+            },
+            1,
+        );
+        Ok(1)
+    });
+
+    // coroutine.yield(...)
+    //
+    // Suspends the execution of the running coroutine. The arguments are
+    // passed as extra results to `resume`.
+    add("yield", |state| {
+        let n = state.get_top();
+        state.yield_values(n as u8)
+    });
+
+    state.set_global("coroutine");
+}
+
+/// Checks that `n` values can be returned from a Rust function.
+fn num_results(state: &State, n: usize) -> Result<u8> {
+    if n >= MULTRET as usize {
+        let msg = "too many results to resume".to_string();
+        return Err(state.error(ErrorKind::WithMessage(msg)));
+    }
+    Ok(n as u8)
+}
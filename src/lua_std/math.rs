@@ -0,0 +1,56 @@
+//! Lua's `math` library
+
+use crate::LuaType;
+use crate::State;
+
+pub(crate) fn open_math(state: &mut State) {
+    state.new_table();
+    let mut add = |name, func| {
+        state.push_rust_fn(func);
+        state.set_field(-2, name).unwrap();
+    };
+
+    // math.tointeger(x)
+    //
+    // If the value `x` is convertible to an integer, returns that integer.
+    // Otherwise, returns `nil`.
+    add("tointeger", |state| {
+        state.check_any(1)?;
+        let result = match state.typ(1) {
+            LuaType::Number => state.to_integer(1).ok(),
+            _ => None,
+        };
+        state.set_top(0);
+        match result {
+            Some(i) => state.push_integer(i),
+            None => state.push_nil(),
+        }
+        Ok(1)
+    });
+
+    // math.type(x)
+    //
+    // Returns "integer" if `x` is an integer, "float" if it is a float, or
+    // `nil` if `x` is not a number.
+    add("type", |state| {
+        state.check_any(1)?;
+        let result = match state.typ(1) {
+            LuaType::Number if state.is_integer(1) => Some("integer"),
+            LuaType::Number => Some("float"),
+            _ => None,
+        };
+        state.set_top(0);
+        match result {
+            Some(s) => state.push_string(s.into()),
+            None => state.push_nil(),
+        }
+        Ok(1)
+    });
+
+    state.push_integer(i64::MAX);
+    state.set_field(-2, "maxinteger").unwrap();
+    state.push_integer(i64::MIN);
+    state.set_field(-2, "mininteger").unwrap();
+
+    state.set_global("math");
+}
@@ -3,15 +3,19 @@
 
 mod frame;
 mod lua_val;
+mod metamethod;
 mod object;
 mod table;
 
 pub use lua_val::LuaType;
 pub use lua_val::RustFunc;
+pub use object::ThreadStatus;
 
+use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::io;
+use std::rc::Rc;
 
 use super::compiler;
 use super::error::Error;
@@ -21,23 +25,59 @@ use super::Chunk;
 use super::Instr;
 use super::Result;
 
-use frame::Frame;
-use lua_val::Val;
-use object::{GcHeap, Markable};
+use frame::{Frame, FrameExit};
+use lua_val::{float_to_int, Val};
+use metamethod::MetaMethod;
+use object::{GcHeap, LuaClosure, Markable, ObjectPtr, RustClosure, Thread};
+use object::{Upvalue, UpvalueRef};
 use table::Table;
 
+/// Option for multiple returns in `State::call`. When used as the number of
+/// expected return values, all results are kept. Equivalent to Lua's
+/// `LUA_MULTRET`.
+pub const MULTRET: u8 = u8::MAX;
+
 /// The main interface into the Lua VM.
 pub struct State {
     /// The global environment. This may be changed to an actual Table in the future.
-    globals: HashMap<String, Val>,
-    /// The main stack which stores values.
+    globals: HashMap<Vec<u8>, Val>,
+    /// The stack of the running thread, which stores values.
     stack: Vec<Val>,
     /// The bottom index of the current frame in the stack.
     stack_bottom: usize,
+    /// The suspended Lua frames of the running thread. The frame which is
+    /// executing isn't in this list.
+    frames: Vec<Frame>,
     /// The heap which holds any garbage-collected Objects.
     heap: GcHeap,
     /// The string literals (as `Val`s) of every active `Frame`.
     string_literals: Vec<Val>,
+    /// Every upvalue which still refers to a local on the stack.
+    open_upvalues: Vec<UpvalueRef>,
+    /// The stack indices of the to-be-closed variables which are in scope,
+    /// in the order they were declared.
+    tbc_slots: Vec<usize>,
+    /// The number of calls in progress in the running thread which it can't
+    /// yield across.
+    non_yieldable_calls: usize,
+    /// The thread which is running. Its stack, frames, and so on are moved
+    /// into the fields above while it runs.
+    current_thread: ObjectPtr,
+    /// The thread which isn't a coroutine.
+    main_thread: ObjectPtr,
+    /// Set by `yield_values`, so that the frame which called the Rust
+    /// function suspends the thread.
+    yielding: bool,
+    /// The maximum number of Lua frames a thread can have.
+    max_call_depth: usize,
+    /// The number of calls from Rust into Lua, and of resumed threads, which
+    /// are in progress. Each of these uses space on the Rust stack.
+    rust_call_depth: usize,
+    /// The names of the metatable fields, indexed by `MetaMethod`.
+    metamethod_names: Vec<Val>,
+    /// Whether finalizers are currently being run. Finalizers never run
+    /// inside other finalizers.
+    running_finalizers: bool,
 }
 
 // Important note on how the stack is tracked:
@@ -46,17 +86,38 @@ pub struct State {
 // frames use this stack. `self.stack_bottom` refers to the first value in the
 // stack which belongs to the current frame. Note that Rust functions access
 // the stack using 1-based indexing, but Lua code uses 0-based indexing.
+// While a function runs, the function itself stays on the stack below its
+// frame, so that the GC can see its upvalues. The extra arguments of a vararg
+// function are kept between the function and its frame.
+// Each coroutine has a stack of its own. Switching threads swaps the running
+// thread's stack (and the other per-thread fields) with the ones stored in
+// the `Thread` object.
+// Calls between Lua functions don't recurse on the Rust stack. The frames of
+// the callers are kept in `self.frames`, and `execute` runs the innermost one.
+// Only calls made from Rust (metamethods, `State::call`, resuming a thread)
+// start a nested `execute`, which stops once its own frames have returned.
 
 impl Markable for State {
-    fn mark_reachable(&self) {
-        self.stack.mark_reachable();
-        self.globals.mark_reachable();
-        self.string_literals.mark_reachable();
+    fn mark_reachable(&self, weak_tables: &mut Vec<ObjectPtr>) {
+        self.stack.mark_reachable(weak_tables);
+        self.globals.mark_reachable(weak_tables);
+        self.string_literals.mark_reachable(weak_tables);
+        self.metamethod_names.mark_reachable(weak_tables);
+        self.current_thread.mark_reachable(weak_tables);
+        self.main_thread.mark_reachable(weak_tables);
     }
 }
 
 impl State {
     const GC_INITIAL_THRESHOLD: usize = 20;
+    /// The default value for `set_max_call_depth`.
+    const DEFAULT_MAX_CALL_DEPTH: usize = 200_000;
+    /// The maximum number of nested calls from Rust into Lua, such as
+    /// metamethods. Unlike calls between Lua functions, these recurse on the
+    /// Rust stack, so the limit has to be much lower.
+    const MAX_RUST_CALL_DEPTH: usize = 100;
+    /// The maximum length of a chain of `__index` or `__newindex` tables.
+    const MAX_META_CHAIN: usize = 2000;
 
     /// Creates a new, independent state.
     pub fn new() -> Self {
@@ -69,13 +130,36 @@ impl State {
     /// The global namespace of this state is entirely empty. This corresponds
     /// to the `lua_newstate' function in the C API.
     pub fn empty() -> Self {
-        Self {
+        let mut heap = GcHeap::with_threshold(Self::GC_INITIAL_THRESHOLD);
+        let main_thread = Thread {
+            status: ThreadStatus::Running,
+            ..Thread::default()
+        };
+        // The heap is empty, so there is nothing to mark.
+        let main_thread = heap.new_thread(main_thread, |_| ());
+        let mut state = Self {
             globals: HashMap::new(),
             stack: Vec::new(),
             stack_bottom: 0,
-            heap: GcHeap::with_threshold(Self::GC_INITIAL_THRESHOLD),
+            frames: Vec::new(),
+            heap,
             string_literals: Vec::new(),
+            open_upvalues: Vec::new(),
+            tbc_slots: Vec::new(),
+            non_yieldable_calls: 0,
+            current_thread: main_thread,
+            main_thread,
+            yielding: false,
+            max_call_depth: Self::DEFAULT_MAX_CALL_DEPTH,
+            rust_call_depth: 0,
+            metamethod_names: Vec::new(),
+            running_finalizers: false,
+        };
+        for event in MetaMethod::ALL {
+            let name = state.alloc_string(event.name());
+            state.metamethod_names.push(name);
         }
+        state
     }
 
     /// Calls a function.
@@ -87,41 +171,17 @@ impl State {
     /// arguments that you pushed onto the stack. All arguments and the function
     /// value are popped from the stack when the function is called. The
     /// function results are pushed onto the stack when the function returns.
-    /// The number of results is adjusted to `num_ret_expected`. The function
-    /// results are pushed onto the stack in direct order (the first result is
-    /// pushed first), so that after the call the last result is on the top of
-    /// the stack.
+    /// The number of results is adjusted to `num_ret_expected`, unless it is
+    /// `MULTRET`. In this case, all results from the function are pushed. The
+    /// function results are pushed onto the stack in direct order (the first
+    /// result is pushed first), so that after the call the last result is on
+    /// the top of the stack.
+    ///
+    /// If the value being called is not a function but has a `__call`
+    /// metamethod, the metamethod is called with the value prepended to the
+    /// arguments.
     pub fn call(&mut self, num_args: u8, num_ret_expected: u8) -> Result<()> {
-        let idx = self.stack.len() - num_args as usize - 1;
-        let func_val = self.stack.remove(idx);
-        let num_ret_actual = if let Val::RustFn(f) = func_val {
-            let old_stack_bottom = self.stack_bottom;
-            self.stack_bottom = idx;
-            let num_ret_reported = f(self)?;
-            let num_ret_actual = self.get_top() as u8;
-            match num_ret_reported.cmp(&num_ret_actual) {
-                Ordering::Greater => {
-                    for _ in num_ret_actual..num_ret_reported {
-                        self.push_nil();
-                    }
-                }
-                Ordering::Less => {
-                    let slc = &mut self.stack[self.stack_bottom..];
-                    slc.rotate_right(num_ret_reported as usize);
-                    let new_len =
-                        self.stack.len() - num_ret_actual as usize + num_ret_reported as usize;
-                    self.stack.truncate(new_len);
-                }
-                Ordering::Equal => (),
-            }
-            self.stack_bottom = old_stack_bottom;
-            num_ret_reported
-        } else if let Some(chunk) = func_val.as_lua_function() {
-            self.eval_chunk(chunk, num_args)?
-        } else {
-            return Err(self.type_error(TypeError::FunctionCall(func_val.typ())));
-        };
-        self.balance_stack(num_ret_expected as usize, num_ret_actual as usize);
+        self.call_helper(num_args as usize, num_ret_expected)?;
         Ok(())
     }
 
@@ -130,7 +190,6 @@ impl State {
     /// is, the function does nothing); if `n` is 0, the result is the empty
     /// string.
     pub fn concat(&mut self, n: usize) -> Result<()> {
-        assert!(n == 2, "Can only concatenate two at a time for now");
         self.concat_helper(n)
     }
 
@@ -144,10 +203,36 @@ impl State {
 
     /// Pushes onto the stack the value of the global `name`.
     pub fn get_global(&mut self, name: &str) {
-        let val = self.globals.get(name).cloned().unwrap_or_default();
+        let val = self
+            .globals
+            .get(name.as_bytes())
+            .cloned()
+            .unwrap_or_default();
         self.stack.push(val);
     }
 
+    /// Pushes onto the stack the value `t[n]`, where `t` is the value at the
+    /// given index. As in Lua, this may trigger the `__index` metamethod.
+    pub fn get_i(&mut self, i: isize, n: i64) -> Result<()> {
+        let table = self.at_index(i);
+        let val = self.get_table_helper(table, Val::Int(n))?;
+        self.stack.push(val);
+        Ok(())
+    }
+
+    /// If the value at the given index has a metatable, pushes that
+    /// metatable onto the stack and returns `true`. Otherwise, pushes nothing
+    /// and returns `false`.
+    pub fn get_metatable(&mut self, i: isize) -> bool {
+        match self.metatable_of(&self.at_index(i)) {
+            Some(metatable) => {
+                self.stack.push(Val::Obj(metatable));
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Pushes onto the stack the value `t[k]`, where `t` is the value at the given
     /// valid index and `k` is the value at the top of the stack.
     ///
@@ -157,16 +242,11 @@ impl State {
     pub fn get_table(&mut self, i: isize) -> Result<()> {
         let idx = self.convert_idx(i);
         assert!(idx != self.stack.len() - 1);
-        let mut table = self.stack[idx].clone();
-        match table.as_table() {
-            Some(t) => {
-                let key = self.pop_val();
-                let val = t.get(&key);
-                self.stack.push(val);
-                Ok(())
-            }
-            None => Err(self.type_error(TypeError::TableIndex(self.stack[idx].typ()))),
-        }
+        let table = self.stack[idx].clone();
+        let key = self.pop_val();
+        let val = self.get_table_helper(table, key)?;
+        self.stack.push(val);
+        Ok(())
     }
 
     /// Returns the index of the top element in the stack. Because indices start
@@ -184,6 +264,66 @@ impl State {
         slice.rotate_right(1);
     }
 
+    /// Closes the suspended or dead thread at the given index: its pending
+    /// to-be-closed variables are closed, its pending frames are discarded,
+    /// and it becomes dead. Returns `false` and pushes the error message if
+    /// the thread had died from an error, or if closing a variable raised
+    /// one; otherwise, returns `true`. Closing the running thread, or a
+    /// thread which resumed it, is an error.
+    pub fn close_thread(&mut self, i: isize) -> Result<bool> {
+        let mut thread_ptr = self.thread_at(i);
+        let thread = thread_ptr.as_thread().unwrap();
+        match thread.status {
+            ThreadStatus::Suspended | ThreadStatus::Dead => (),
+            status => {
+                let msg = format!("cannot close a {} coroutine", status.as_str());
+                return Err(self.error(ErrorKind::WithMessage(msg)));
+            }
+        }
+        let error = if thread.tbc_slots.is_empty() {
+            close_upvalues_of(&mut thread.open_upvalues, &thread.stack, 0);
+            thread.error.take()
+        } else {
+            // The `__close` metamethods run inside the thread.
+            self.with_thread(thread_ptr, 0, |state| match state.close_locals(0) {
+                Ok(()) => None,
+                Err(e) => Some(state.unwind_locals(0, e).to_string()),
+            })?
+        };
+        let thread = thread_ptr.as_thread().unwrap();
+        *thread = Thread {
+            status: ThreadStatus::Dead,
+            ..Thread::default()
+        };
+        match error {
+            Some(msg) => {
+                self.push_string(msg);
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Returns whether the value at the given index is a number with the
+    /// integer subtype.
+    pub fn is_integer(&self, idx: isize) -> bool {
+        matches!(self.at_index(idx), Val::Int(_))
+    }
+
+    /// Returns whether the running thread can yield. The main thread can't,
+    /// and neither can a coroutine which is inside a call from Rust (such as
+    /// a metamethod).
+    pub fn is_yieldable(&self) -> bool {
+        self.current_thread != self.main_thread && self.non_yieldable_calls == 0
+    }
+
+    /// Pushes the length of the value at the given index, as the `#`
+    /// operator would. This may call the `__len` metamethod.
+    pub fn len(&mut self, i: isize) -> Result<()> {
+        self.push_value(i);
+        self.len_helper()
+    }
+
     /// Calls `reader` to produce source code, then parses that code and returns
     /// the chunk. If the code is syntactically invalid, but could be valid if
     /// more code was appended, then `reader` will be called again. A common use
@@ -211,6 +351,44 @@ impl State {
         self.stack.push(val);
     }
 
+    /// Pops a function from the stack, and pushes a new thread which will
+    /// call that function when it is first resumed.
+    pub fn new_thread(&mut self) {
+        let body = self.stack.last().unwrap().clone();
+        let thread = Thread {
+            stack: vec![body],
+            ..Thread::default()
+        };
+        let val = self.alloc_thread(thread);
+        self.pop_val();
+        self.stack.push(val);
+    }
+
+    /// Pops a key from the stack, and pushes a key-value pair from the table
+    /// at the given index, the "next" pair after the given key. If there are
+    /// no more elements in the table, then `next` returns `false` and pushes
+    /// nothing.
+    ///
+    /// While traversing a table, avoid assigning to fields which don't already
+    /// exist, because this may change the order of traversal.
+    pub fn next(&mut self, idx: isize) -> Result<bool> {
+        let idx = self.convert_idx(idx);
+        let key = self.pop_val();
+        let mut tbl_val = self.stack[idx].clone();
+        let tbl = match tbl_val.as_table() {
+            Some(tbl) => tbl,
+            None => return Err(self.type_error(TypeError::TableIndex(tbl_val.typ()))),
+        };
+        match tbl.next(&key)? {
+            Some((key, value)) => {
+                self.stack.push(key);
+                self.stack.push(value);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
     /// Pops `n` elements from the stack.
     pub fn pop(&mut self, n: isize) {
         assert!(
@@ -228,6 +406,11 @@ impl State {
         self.stack.push(Val::Bool(b));
     }
 
+    /// Pushes an integer with value `n` onto the stack.
+    pub fn push_integer(&mut self, n: i64) {
+        self.stack.push(Val::Int(n));
+    }
+
     /// Pushes a `nil` value onto the stack.
     pub fn push_nil(&mut self) {
         self.stack.push(Val::Nil);
@@ -238,6 +421,21 @@ impl State {
         self.stack.push(Val::Num(n));
     }
 
+    /// Pops `num_upvalues` values from the stack, and pushes a Rust function
+    /// which can push those values with `push_upvalue`. Equivalent to
+    /// `lua_pushcclosure`.
+    pub fn push_rust_closure(&mut self, f: RustFunc, num_upvalues: u8) {
+        let len = self.stack.len();
+        let first_upvalue = len - num_upvalues as usize;
+        let closure = RustClosure {
+            func: f,
+            upvalues: self.stack[first_upvalue..].to_vec(),
+        };
+        let val = self.alloc_rust_closure(closure);
+        self.stack.truncate(first_upvalue);
+        self.stack.push(val);
+    }
+
     /// Pushes a Rust function onto the stack.
     pub fn push_rust_fn(&mut self, f: RustFunc) {
         self.stack.push(Val::RustFn(f));
@@ -249,6 +447,25 @@ impl State {
         self.stack.push(val);
     }
 
+    /// Pushes the running thread onto the stack. Returns whether it is the
+    /// main thread.
+    pub fn push_thread(&mut self) -> bool {
+        self.stack.push(Val::Obj(self.current_thread));
+        self.current_thread == self.main_thread
+    }
+
+    /// Pushes the `n`th upvalue (counting from 1) of the running Rust
+    /// closure. Panics if the running function isn't a Rust closure with
+    /// that many upvalues.
+    pub fn push_upvalue(&mut self, n: usize) {
+        let val = match &self.stack[self.stack_bottom - 1] {
+            Val::Obj(o) => o.as_rust_closure().map(|c| c.upvalues[n - 1].clone()),
+            _ => None,
+        };
+        let val = val.expect("The running function isn't a Rust closure");
+        self.stack.push(val);
+    }
+
     /// Pushes a copy of the element at the given index onto the stack.
     pub fn push_value(&mut self, i: isize) {
         // TODO: figure out what lua does when index is invalid
@@ -256,11 +473,111 @@ impl State {
         self.stack.push(val);
     }
 
+    /// Returns whether the two values at the given indices are primitively
+    /// equal (that is, without calling the `__eq` metamethod).
+    pub fn raw_equal(&self, i1: isize, i2: isize) -> bool {
+        self.at_index(i1) == self.at_index(i2)
+    }
+
+    /// Similar to `get_table`, but does a raw access (i.e., without
+    /// metamethods).
+    pub fn raw_get(&mut self, i: isize) -> Result<()> {
+        let mut table = self.at_index(i);
+        let key = self.pop_val();
+        match table.as_table() {
+            Some(t) => {
+                let val = t.get(&key);
+                self.stack.push(val);
+                Ok(())
+            }
+            None => Err(self.type_error(TypeError::TableIndex(table.typ()))),
+        }
+    }
+
+    /// Returns the raw length of the value at the given index: the length of
+    /// a string, or a border of a table, without calling any metamethods.
+    /// For other values, returns 0.
+    pub fn raw_len(&self, i: isize) -> usize {
+        let mut val = self.at_index(i);
+        if let Some(s) = val.as_string() {
+            s.len()
+        } else if let Some(t) = val.as_table() {
+            t.border() as usize
+        } else {
+            0
+        }
+    }
+
+    /// Does the equivalent of `t[k] = v` without metamethods, where `t` is
+    /// the value at the given index, `v` is the value at the top of the
+    /// stack, and `k` is the value just below the top.
+    ///
+    /// This function pops both the key and the value from the stack.
+    pub fn raw_set(&mut self, i: isize) -> Result<()> {
+        let mut table = self.at_index(i);
+        let val = self.pop_val();
+        let key = self.pop_val();
+        match table.as_table() {
+            Some(t) => t.insert(key, val),
+            None => Err(self.type_error(TypeError::TableIndex(table.typ()))),
+        }
+    }
+
     pub fn remove(&mut self, i: isize) {
         let idx = self.convert_idx(i);
         self.stack.remove(idx);
     }
 
+    /// Starts or continues running the thread at the given index, passing it
+    /// `num_args` values popped from the stack. A thread which hasn't started
+    /// calls its function with those values, and a thread which has yielded
+    /// gets them as the results of its call to `coroutine.yield`.
+    ///
+    /// Once the thread yields or returns, the values it yielded or returned
+    /// are pushed onto the stack, and their number is returned. If it raises
+    /// an error instead, the thread is dead, and the error is returned.
+    pub fn resume(&mut self, i: isize, num_args: u8) -> Result<usize> {
+        let mut thread = self.thread_at(i);
+        match thread.as_thread().unwrap().status {
+            ThreadStatus::Suspended => (),
+            ThreadStatus::Dead => {
+                let msg = "cannot resume dead coroutine".to_string();
+                return Err(self.error(ErrorKind::WithMessage(msg)));
+            }
+            _ => {
+                let msg = "cannot resume non-suspended coroutine".to_string();
+                return Err(self.error(ErrorKind::WithMessage(msg)));
+            }
+        }
+        let num_args = num_args as usize;
+        let (status, result) =
+            self.with_thread(thread, num_args, |state| match state.run_thread(num_args) {
+                Ok(FrameExit::Yield(n)) => {
+                    let values = state.stack.split_off(state.stack.len() - n);
+                    (ThreadStatus::Suspended, Ok(values))
+                }
+                Ok(FrameExit::Return(n)) => {
+                    let values = state.stack.split_off(state.stack.len() - n);
+                    (ThreadStatus::Dead, Ok(values))
+                }
+                Ok(_) => unreachable!(),
+                Err(e) => (ThreadStatus::Dead, Err(state.unwind_locals(0, e))),
+            })?;
+        let thread = thread.as_thread().unwrap();
+        if status == ThreadStatus::Dead {
+            // Free everything the dead thread was using.
+            *thread = Thread::default();
+            if let Err(e) = &result {
+                thread.error = Some(e.to_string());
+            }
+        }
+        thread.status = status;
+        let values = result?;
+        let num_values = values.len();
+        self.stack.extend(values);
+        Ok(num_values)
+    }
+
     /// Pops a value from the stack, then replaces the value at the given index
     /// with that value.
     pub fn replace(&mut self, i: isize) {
@@ -269,11 +586,71 @@ impl State {
         self.stack[idx] = val;
     }
 
+    /// Does the equivalent of `t[k] = v`, where `t` is the value at the given
+    /// valid index, `k` is the string `key`, and `v` is the value at the top
+    /// of the stack.
+    ///
+    /// This function pops the value from the stack.
+    pub fn set_field(&mut self, i: isize, key: &str) -> Result<()> {
+        let table = self.at_index(i);
+        // Allocate the key before popping the value, so the value can't be
+        // collected.
+        let key = self.alloc_string(key);
+        let val = self.pop_val();
+        self.set_table_helper(table, key, val)
+    }
+
+    /// Sets the maximum number of nested Lua calls a thread can make. Going
+    /// deeper raises a "stack overflow" error, which can be caught like any
+    /// other error. Tail calls don't count towards the limit.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
     /// Pops a value from the stack and sets it as the new value of global
     /// `name`.
     pub fn set_global(&mut self, name: &str) {
         let val = self.pop_val();
-        self.globals.insert(name.to_string(), val);
+        self.globals.insert(name.into(), val);
+    }
+
+    /// Pops a table or `nil` from the stack and sets it as the new metatable
+    /// for the table at the given index. Panics if that value isn't a
+    /// table, since other types can't have metatables yet.
+    ///
+    /// If the metatable has a `__gc` field at this point, the table is marked
+    /// for finalization: once it becomes unreachable, `__gc` is called with
+    /// the table as its argument. Similarly, the metatable's `__mode` field
+    /// decides whether the table's keys and values are weak.
+    pub fn set_metatable(&mut self, i: isize) {
+        let mut table = self.at_index(i);
+        let metatable = match self.pop_val() {
+            Val::Nil => None,
+            Val::Obj(o) if o.typ() == LuaType::Table => Some(o),
+            val => panic!("Tried to use a {} as a metatable", val.typ()),
+        };
+        let (weak_keys, weak_values) = match metatable {
+            Some(mut metatable) => {
+                let name = &self.metamethod_names[MetaMethod::Mode as usize];
+                let mode = metatable.as_table().unwrap().get(name);
+                let mode = mode.as_string().unwrap_or_default();
+                (mode.contains(&b'k'), mode.contains(&b'v'))
+            }
+            None => (false, false),
+        };
+        match table.as_table() {
+            Some(t) => {
+                t.set_metatable(metatable);
+                t.set_weak_mode(weak_keys, weak_values);
+            }
+            None => panic!("Tried to set the metatable of a {}", table.typ()),
+        }
+        if let (Val::Obj(obj), Some(mut metatable)) = (table, metatable) {
+            let name = &self.metamethod_names[MetaMethod::Gc as usize];
+            if !matches!(metatable.as_table().unwrap().get(name), Val::Nil) {
+                self.heap.mark_for_finalization(obj);
+            }
+        }
     }
 
     /// Accepts any acceptable index, or 0, and sets the stack top to this index.
@@ -311,6 +688,22 @@ impl State {
         val.truthy()
     }
 
+    /// Returns the status of the thread at the given index.
+    pub fn thread_status(&self, i: isize) -> ThreadStatus {
+        self.thread_at(i).as_thread().unwrap().status
+    }
+
+    /// Attempts to convert the value at the given index to an integer. Floats
+    /// are only converted if they have an exact integer representation.
+    pub fn to_integer(&self, idx: isize) -> Result<i64> {
+        let val = self.at_index(idx);
+        match val {
+            Val::Int(i) => Ok(i),
+            Val::Num(f) => float_to_int(f).ok_or_else(|| self.type_error(TypeError::NoIntegerRep)),
+            _ => Err(self.type_error(TypeError::Arithmetic(val.typ()))),
+        }
+    }
+
     /// Attempts to convert the value at the given index to a number.
     pub fn to_number(&self, idx: isize) -> Result<f64> {
         let i = self.convert_idx(idx);
@@ -319,28 +712,91 @@ impl State {
             .ok_or_else(|| self.type_error(TypeError::Arithmetic(val.typ())))
     }
 
-    /// Converts the value at the given index to a string.
+    /// Converts the value at the given index to a string. Any bytes of a
+    /// Lua string which aren't valid UTF-8 are replaced.
     pub fn to_string(&self, idx: isize) -> String {
         let i = self.convert_idx(idx);
         self.stack[i].to_string()
     }
 
+    /// Converts the value at the given index to a string, keeping the exact
+    /// bytes of a Lua string.
+    pub fn to_bytes(&self, idx: isize) -> Vec<u8> {
+        let i = self.convert_idx(idx);
+        self.stack[i].to_bytes()
+    }
+
     /// Returns the type of the value in the given acceptable index.
     pub fn typ(&self, idx: isize) -> LuaType {
         self.at_index(idx).typ()
     }
 
-    fn alloc_string(&mut self, s: String) -> Val {
+    /// Yields the running thread. The `num_values` values on top of the
+    /// stack are passed to the `resume` which resumed it. This must be
+    /// called as the return expression of a Rust function:
+    /// `return state.yield_values(n);`.
+    pub fn yield_values(&mut self, num_values: u8) -> Result<u8> {
+        if !self.is_yieldable() {
+            let msg = if self.current_thread == self.main_thread {
+                "attempt to yield from outside a coroutine"
+            } else {
+                "attempt to yield across a C-call boundary"
+            };
+            return Err(self.error(ErrorKind::WithMessage(msg.into())));
+        }
+        self.yielding = true;
+        Ok(num_values)
+    }
+
+    /// Replaces the results of a call with `expected` values, unless it is
+    /// `MULTRET`. Returns the new number of results.
+    fn adjust_results(&mut self, expected: u8, received: usize) -> usize {
+        if expected == MULTRET {
+            received
+        } else {
+            self.balance_stack(expected as usize, received);
+            expected as usize
+        }
+    }
+
+    fn alloc_rust_closure(&mut self, closure: RustClosure) -> Val {
         let Self {
             stack,
             globals,
             string_literals,
+            metamethod_names,
+            current_thread,
+            main_thread,
             ..
         } = self;
-        let obj = self.heap.new_string(s, || {
-            stack.mark_reachable();
-            globals.mark_reachable();
-            string_literals.mark_reachable();
+        let obj = self.heap.new_rust_closure(closure, |weak_tables| {
+            stack.mark_reachable(weak_tables);
+            globals.mark_reachable(weak_tables);
+            string_literals.mark_reachable(weak_tables);
+            metamethod_names.mark_reachable(weak_tables);
+            current_thread.mark_reachable(weak_tables);
+            main_thread.mark_reachable(weak_tables);
+        });
+        Val::Obj(obj)
+    }
+
+    fn alloc_string(&mut self, s: impl AsRef<[u8]> + Into<Vec<u8>>) -> Val {
+        let Self {
+            stack,
+            globals,
+            string_literals,
+            metamethod_names,
+            current_thread,
+            main_thread,
+            ..
+        } = self;
+        let obj = self.heap.new_string(s, |weak_tables| {
+            stack.mark_reachable(weak_tables);
+            globals.mark_reachable(weak_tables);
+            string_literals.mark_reachable(weak_tables);
+            metamethod_names.mark_reachable(weak_tables);
+            current_thread.mark_reachable(weak_tables);
+            main_thread.mark_reachable(weak_tables);
         });
         Val::Obj(obj)
     }
@@ -350,12 +806,39 @@ impl State {
             stack,
             globals,
             string_literals,
+            metamethod_names,
+            current_thread,
+            main_thread,
+            ..
+        } = self;
+        let obj = self.heap.new_table(|weak_tables| {
+            stack.mark_reachable(weak_tables);
+            globals.mark_reachable(weak_tables);
+            string_literals.mark_reachable(weak_tables);
+            metamethod_names.mark_reachable(weak_tables);
+            current_thread.mark_reachable(weak_tables);
+            main_thread.mark_reachable(weak_tables);
+        });
+        Val::Obj(obj)
+    }
+
+    fn alloc_thread(&mut self, thread: Thread) -> Val {
+        let Self {
+            stack,
+            globals,
+            string_literals,
+            metamethod_names,
+            current_thread,
+            main_thread,
             ..
         } = self;
-        let obj = self.heap.new_table(|| {
-            stack.mark_reachable();
-            globals.mark_reachable();
-            string_literals.mark_reachable();
+        let obj = self.heap.new_thread(thread, |weak_tables| {
+            stack.mark_reachable(weak_tables);
+            globals.mark_reachable(weak_tables);
+            string_literals.mark_reachable(weak_tables);
+            metamethod_names.mark_reachable(weak_tables);
+            current_thread.mark_reachable(weak_tables);
+            main_thread.mark_reachable(weak_tables);
         });
         Val::Obj(obj)
     }
@@ -384,25 +867,122 @@ impl State {
         }
     }
 
-    fn concat_helper(&mut self, n: usize) -> Result<()> {
-        let mut buffer = String::new();
-        let idx = self.stack.len() - n;
-        let drain = self.stack.drain(idx..);
-        let mut abort = None;
-        for val in drain {
-            if let Some(s) = val.as_string() {
-                buffer.push_str(s);
-            } else {
-                abort = Some(TypeError::Concat(val.typ()));
-                break;
-            }
+    /// Looks for a metamethod for the given event in the two values at the
+    /// top of the stack, starting with the first one. If there is one, the
+    /// values are replaced by the result of calling it with both values.
+    /// Returns whether a metamethod was found.
+    fn call_binary_metamethod(&mut self, event: MetaMethod) -> Result<bool> {
+        let len = self.stack.len();
+        let mut metamethod = self.get_metamethod(&self.stack[len - 2], event);
+        if let Val::Nil = metamethod {
+            metamethod = self.get_metamethod(&self.stack[len - 1], event);
         }
-        if let Some(e) = abort {
-            return Err(self.type_error(e));
+        if let Val::Nil = metamethod {
+            return Ok(false);
         }
+        self.stack.insert(len - 2, metamethod);
+        self.call_helper(2, 1)?;
+        Ok(true)
+    }
 
-        let val = self.alloc_string(buffer);
+    /// Like `call_binary_metamethod`, but for an operation on the value at
+    /// the top of the stack. The value is passed as both arguments.
+    fn call_unary_metamethod(&mut self, event: MetaMethod) -> Result<bool> {
+        let val = self.stack.last().unwrap().clone();
+        let metamethod = self.get_metamethod(&val, event);
+        if let Val::Nil = metamethod {
+            return Ok(false);
+        }
+        let len = self.stack.len();
+        self.stack.insert(len - 1, metamethod);
         self.stack.push(val);
+        self.call_helper(2, 1)?;
+        Ok(true)
+    }
+
+    /// Calls the function below `num_args` arguments, as in `call`. Returns
+    /// the number of results left on the stack. The running thread can't
+    /// yield until the call returns.
+    fn call_helper(&mut self, num_args: usize, num_ret_expected: u8) -> Result<usize> {
+        let num_args = self.resolve_callee(num_args)?;
+        let func_index = self.stack.len() - num_args - 1;
+        self.enter_rust_call()?;
+        self.non_yieldable_calls += 1;
+        let result = match self.stack[func_index].as_lua_function() {
+            Some(closure) => self.eval_closure(closure, num_args),
+            None => self.call_rust_fn(num_args),
+        };
+        self.non_yieldable_calls -= 1;
+        self.rust_call_depth -= 1;
+        Ok(self.adjust_results(num_ret_expected, result?))
+    }
+
+    /// Calls the Rust function below `num_args` arguments. Afterwards, the
+    /// function and its arguments are replaced by its return values. Returns
+    /// the number of return values.
+    fn call_rust_fn(&mut self, num_args: usize) -> Result<usize> {
+        let idx = self.stack.len() - num_args - 1;
+        let f = match &self.stack[idx] {
+            Val::RustFn(f) => *f,
+            Val::Obj(o) => o.as_rust_closure().unwrap().func,
+            val => panic!("Tried to call a {} as a Rust function", val.typ()),
+        };
+        let old_stack_bottom = self.stack_bottom;
+        self.stack_bottom = idx + 1;
+        let num_ret_reported = match f(self) {
+            Ok(n) => n as usize,
+            Err(e) => {
+                self.stack.truncate(idx);
+                self.stack_bottom = old_stack_bottom;
+                return Err(e);
+            }
+        };
+        let num_ret_actual = self.get_top();
+        match num_ret_reported.cmp(&num_ret_actual) {
+            Ordering::Greater => {
+                for _ in num_ret_actual..num_ret_reported {
+                    self.push_nil();
+                }
+            }
+            Ordering::Less => {
+                let slc = &mut self.stack[self.stack_bottom..];
+                slc.rotate_right(num_ret_reported);
+                let new_len = self.stack.len() - num_ret_actual + num_ret_reported;
+                self.stack.truncate(new_len);
+            }
+            Ordering::Equal => (),
+        }
+        self.stack.remove(idx);
+        self.stack_bottom = old_stack_bottom;
+        Ok(num_ret_reported)
+    }
+
+    /// Concatenates the top `n` values on the stack, from right to left,
+    /// as the `..` operator does. Numbers are converted to strings, and
+    /// any other values use the `__concat` metamethod.
+    fn concat_helper(&mut self, n: usize) -> Result<()> {
+        if n == 0 {
+            let val = self.alloc_string("");
+            self.stack.push(val);
+            return Ok(());
+        }
+        for _ in 1..n {
+            let len = self.stack.len();
+            let (a, b) = (&self.stack[len - 2], &self.stack[len - 1]);
+            let is_concatable = |val: &Val| matches!(val.typ(), LuaType::String | LuaType::Number);
+            if is_concatable(a) && is_concatable(b) {
+                let mut buffer = a.to_bytes();
+                buffer.extend_from_slice(&b.to_bytes());
+                let val = self.alloc_string(buffer);
+                self.stack.truncate(len - 2);
+                self.stack.push(val);
+            } else {
+                let culprit = if is_concatable(a) { b.typ() } else { a.typ() };
+                if !self.call_binary_metamethod(MetaMethod::Concat)? {
+                    return Err(self.type_error(TypeError::Concat(culprit)));
+                }
+            }
+        }
         Ok(())
     }
 
@@ -420,6 +1000,18 @@ impl State {
         }
     }
 
+    /// Counts a call which recurses on the Rust stack, or raises an error if
+    /// there are too many of them. `rust_call_depth` must be decremented once
+    /// the call is done.
+    fn enter_rust_call(&mut self) -> Result<()> {
+        if self.rust_call_depth >= Self::MAX_RUST_CALL_DEPTH {
+            let msg = "C stack overflow".to_string();
+            return Err(self.error(ErrorKind::WithMessage(msg)));
+        }
+        self.rust_call_depth += 1;
+        Ok(())
+    }
+
     pub fn error(&self, kind: ErrorKind) -> Error {
         // TODO actually find position
         let pos = 0;
@@ -427,18 +1019,247 @@ impl State {
         Error::new(kind, pos, column)
     }
 
-    fn eval_chunk(&mut self, chunk: Chunk, num_args: u8) -> Result<u8> {
+    /// Closes every open upvalue which refers to the given stack index or
+    /// higher, because those locals are going out of scope.
+    fn close_upvalues(&mut self, first_index: usize) {
+        close_upvalues_of(&mut self.open_upvalues, &self.stack, first_index);
+    }
+
+    /// Closes the upvalues and the to-be-closed variables which are at the
+    /// given stack index or higher, because those locals are going out of
+    /// scope. If a `__close` metamethod raises an error, the variables which
+    /// haven't been closed yet are left for the caller to unwind.
+    fn close_locals(&mut self, first_index: usize) -> Result<()> {
+        self.close_upvalues(first_index);
+        while let Some(&index) = self.tbc_slots.last() {
+            if index < first_index {
+                break;
+            }
+            self.tbc_slots.pop();
+            self.call_close_metamethod(index, None)?;
+        }
+        Ok(())
+    }
+
+    /// Like `close_locals`, but for locals which go out of scope because of
+    /// `error`. Each `__close` metamethod receives the error message, and
+    /// an error it raises replaces the original one. Returns the final
+    /// error.
+    fn unwind_locals(&mut self, first_index: usize, mut error: Error) -> Error {
+        self.close_upvalues(first_index);
+        while let Some(&index) = self.tbc_slots.last() {
+            if index < first_index {
+                break;
+            }
+            self.tbc_slots.pop();
+            if let Err(e) = self.call_close_metamethod(index, Some(&error)) {
+                error = e;
+            }
+        }
+        error
+    }
+
+    /// Calls the `__close` metamethod of the to-be-closed variable at the
+    /// given stack index, with the error message as the second argument.
+    fn call_close_metamethod(&mut self, index: usize, error: Option<&Error>) -> Result<()> {
+        let val = self.stack[index].clone();
+        let metamethod = self.get_metamethod(&val, MetaMethod::Close);
+        self.stack.push(metamethod);
+        self.stack.push(val);
+        match error {
+            Some(e) => self.push_string(e.to_string()),
+            None => self.push_nil(),
+        }
+        self.call_helper(2, 0)?;
+        Ok(())
+    }
+
+    /// Calls a Lua function. The function must be on the stack, followed by
+    /// its arguments. Afterwards, they are replaced by the return values.
+    /// Returns the number of return values.
+    fn eval_closure(&mut self, closure: LuaClosure, num_args: usize) -> Result<usize> {
+        let func_index = self.stack.len() - num_args - 1;
         let old_stack_bottom = self.stack_bottom;
-        self.stack_bottom = self.stack.len() - num_args as usize;
+        let string_literal_start = self.string_literals.len();
+        let base = self.frames.len();
+        let num_vals_returned = match self
+            .prepare_frame(closure, num_args)
+            .and_then(|frame| self.execute(frame, base))
+        {
+            Ok(FrameExit::Return(n)) => n,
+            Ok(_) => unreachable!("yielded across a call from Rust"),
+            Err(e) => {
+                // Unwind every frame which the error escaped from.
+                self.frames.truncate(base);
+                let e = self.unwind_locals(func_index, e);
+                self.string_literals.truncate(string_literal_start);
+                self.stack.truncate(func_index);
+                self.stack_bottom = old_stack_bottom;
+                return Err(e);
+            }
+        };
+        // Move the return values down to where the function was.
+        let first_ret = self.stack.len() - num_vals_returned;
+        self.stack.drain(func_index..first_ret);
+        self.stack_bottom = old_stack_bottom;
+        Ok(num_vals_returned)
+    }
+
+    /// Runs `frame` until it returns, or until the thread yields. When a Lua
+    /// function calls another one, a new frame is pushed and run by this
+    /// same loop, so Lua calls don't recurse on the Rust stack. The first
+    /// `base` frames in `self.frames` belong to outer calls, and are left
+    /// alone. Only returns `FrameExit::Return` or `FrameExit::Yield`.
+    fn execute(&mut self, mut frame: Frame, base: usize) -> Result<FrameExit> {
+        loop {
+            match frame.eval(self)? {
+                FrameExit::Call(closure, num_args) => {
+                    let callee = self.prepare_frame(closure, num_args)?;
+                    self.frames.push(std::mem::replace(&mut frame, callee));
+                }
+                FrameExit::TailCall(closure, num_args) => {
+                    frame = self.prepare_frame(closure, num_args)?;
+                }
+                FrameExit::Return(n) => {
+                    self.close_locals(self.stack_bottom)?;
+                    self.string_literals.truncate(frame.string_literal_start);
+                    if self.frames.len() == base {
+                        return Ok(FrameExit::Return(n));
+                    }
+                    // Move the return values down to where the function was.
+                    let first_ret = self.stack.len() - n;
+                    self.stack.drain(frame.func_index..first_ret);
+                    frame = self.frames.pop().unwrap();
+                    self.stack_bottom = frame.stack_bottom;
+                    self.finish_call(&mut frame, n);
+                }
+                FrameExit::Yield(n) => {
+                    self.frames.push(frame);
+                    return Ok(FrameExit::Yield(n));
+                }
+            }
+        }
+    }
+
+    /// Returns the upvalue for the local at the given stack index, creating it
+    /// if no closure has captured that local yet.
+    fn find_upvalue(&mut self, stack_index: usize) -> UpvalueRef {
+        let existing = self
+            .open_upvalues
+            .iter()
+            .find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(_, i) if i == stack_index));
+        if let Some(upvalue) = existing {
+            upvalue.clone()
+        } else {
+            let upvalue = Upvalue::Open(self.current_thread, stack_index);
+            let upvalue = Rc::new(RefCell::new(upvalue));
+            self.open_upvalues.push(upvalue.clone());
+            upvalue
+        }
+    }
+
+    /// Returns the field of `val`'s metatable for the given event, or `nil`
+    /// if there isn't one.
+    fn get_metamethod(&self, val: &Val, event: MetaMethod) -> Val {
+        match self.metatable_of(val) {
+            Some(mut metatable) => {
+                let name = &self.metamethod_names[event as usize];
+                metatable.as_table().unwrap().get(name)
+            }
+            None => Val::Nil,
+        }
+    }
+
+    /// Indexes `table` with `key`, using the `__index` metamethod if needed.
+    fn get_table_helper(&mut self, mut table: Val, key: Val) -> Result<Val> {
+        for _ in 0..Self::MAX_META_CHAIN {
+            let metamethod = if let Some(t) = table.as_table() {
+                let val = t.get(&key);
+                if !matches!(val, Val::Nil) {
+                    return Ok(val);
+                }
+                let metamethod = self.get_metamethod(&table, MetaMethod::Index);
+                if let Val::Nil = metamethod {
+                    return Ok(Val::Nil);
+                }
+                metamethod
+            } else {
+                match self.get_metamethod(&table, MetaMethod::Index) {
+                    Val::Nil => return Err(self.type_error(TypeError::TableIndex(table.typ()))),
+                    metamethod => metamethod,
+                }
+            };
+            if metamethod.typ() == LuaType::Function {
+                self.stack.extend([metamethod, table, key]);
+                self.call_helper(2, 1)?;
+                return Ok(self.pop_val());
+            }
+            table = metamethod;
+        }
+        let msg = "'__index' chain too long; possibly a loop".to_string();
+        Err(self.error(ErrorKind::WithMessage(msg)))
+    }
+
+    /// Replaces the value on top of the stack with its length, using the
+    /// `__len` metamethod if needed.
+    fn len_helper(&mut self) -> Result<()> {
+        let val = self.stack.last().unwrap();
+        if let Some(s) = val.as_string() {
+            let len = s.len();
+            self.pop_val();
+            self.stack.push(Val::Int(len as i64));
+            return Ok(());
+        }
+        if self.call_unary_metamethod(MetaMethod::Len)? {
+            return Ok(());
+        }
+        let mut val = self.pop_val();
+        match val.as_table() {
+            Some(t) => {
+                let len = t.border();
+                self.stack.push(Val::Int(len));
+                Ok(())
+            }
+            None => Err(self.type_error(TypeError::Length(val.typ()))),
+        }
+    }
 
-        match num_args.cmp(&chunk.num_params) {
+    /// Returns the metatable of a value, if it has one.
+    fn metatable_of(&self, val: &Val) -> Option<ObjectPtr> {
+        match val {
+            Val::Obj(mut o) => o.as_table().and_then(|t| t.metatable()),
+            _ => None,
+        }
+    }
+
+    /// Sets up the stack to call a Lua function, which is on the stack below
+    /// `num_args` arguments, and returns a frame to run it. Raises an error
+    /// if the thread already has too many frames.
+    fn prepare_frame(&mut self, closure: LuaClosure, num_args: usize) -> Result<Frame> {
+        if self.frames.len() >= self.max_call_depth {
+            let msg = "stack overflow".to_string();
+            return Err(self.error(ErrorKind::WithMessage(msg)));
+        }
+        let func_index = self.stack.len() - num_args - 1;
+        self.stack_bottom = func_index + 1;
+
+        let chunk = &closure.chunk;
+        let num_params = chunk.num_params as usize;
+        let mut num_varargs = 0;
+        match num_args.cmp(&num_params) {
             Ordering::Less => {
-                for _ in num_args..chunk.num_params {
+                for _ in num_args..num_params {
                     self.push_nil();
                 }
             }
+            Ordering::Greater if chunk.is_vararg => {
+                // Move the extra arguments below the parameters.
+                num_varargs = num_args - num_params;
+                self.stack[func_index + 1..].rotate_left(num_params);
+                self.stack_bottom += num_varargs;
+            }
             Ordering::Greater => {
-                self.pop((num_args - chunk.num_params) as isize);
+                self.pop((num_args - num_params) as isize);
             }
             Ordering::Equal => (),
         }
@@ -447,45 +1268,36 @@ impl State {
             self.push_nil();
         }
 
-        let mut frame = self.initialize_frame(chunk);
-        let num_vals_returned = frame.eval(self)?;
-        match num_vals_returned {
-            0 => {
-                self.stack.truncate(self.stack_bottom);
-                self.stack_bottom = old_stack_bottom;
-            }
-            1 => {
-                let ret_val = self.pop_val();
-                self.stack.truncate(self.stack_bottom);
-                self.stack_bottom = old_stack_bottom;
-                self.stack.push(ret_val);
-            }
-            _ => {
-                panic!("Can't handle multiple return values");
-            }
-        }
-        Ok(num_vals_returned)
-    }
-
-    fn initialize_frame(&mut self, chunk: Chunk) -> Frame {
         let string_literal_start = self.string_literals.len();
-        for s in &chunk.string_literals {
+        for s in &closure.chunk.string_literals {
             let obj = {
                 let Self {
                     stack,
                     globals,
                     string_literals,
+                    metamethod_names,
+                    current_thread,
+                    main_thread,
                     ..
                 } = self;
-                self.heap.new_string(s.into(), || {
-                    stack.mark_reachable();
-                    globals.mark_reachable();
-                    string_literals.mark_reachable();
+                self.heap.new_string(s.as_slice(), |weak_tables| {
+                    stack.mark_reachable(weak_tables);
+                    globals.mark_reachable(weak_tables);
+                    string_literals.mark_reachable(weak_tables);
+                    metamethod_names.mark_reachable(weak_tables);
+                    current_thread.mark_reachable(weak_tables);
+                    main_thread.mark_reachable(weak_tables);
                 })
             };
             self.string_literals.push(Val::Obj(obj));
         }
-        Frame::new(chunk, string_literal_start)
+        Ok(Frame::new(
+            closure,
+            func_index,
+            self.stack_bottom,
+            string_literal_start,
+            num_varargs,
+        ))
     }
 
     /// Pop a value from the stack
@@ -494,23 +1306,210 @@ impl State {
     }
 
     fn push_chunk(&mut self, chunk: Chunk) {
+        let closure = LuaClosure {
+            chunk: Rc::new(chunk),
+            upvalues: Vec::new(),
+        };
+        self.push_closure(closure);
+    }
+
+    fn push_closure(&mut self, closure: LuaClosure) {
         let Self {
             stack,
             globals,
             string_literals,
+            metamethod_names,
+            current_thread,
+            main_thread,
             ..
         } = self;
-        let obj = self.heap.new_lua_fn(chunk, || {
-            stack.mark_reachable();
-            globals.mark_reachable();
-            string_literals.mark_reachable();
+        let obj = self.heap.new_lua_fn(closure, |weak_tables| {
+            stack.mark_reachable(weak_tables);
+            globals.mark_reachable(weak_tables);
+            string_literals.mark_reachable(weak_tables);
+            metamethod_names.mark_reachable(weak_tables);
+            current_thread.mark_reachable(weak_tables);
+            main_thread.mark_reachable(weak_tables);
         });
         self.stack.push(Val::Obj(obj));
     }
 
+    /// If the value below `num_args` arguments isn't a function, replaces it
+    /// with its `__call` metamethod, which gets the original value as an
+    /// extra first argument. Returns the new number of arguments.
+    fn resolve_callee(&mut self, mut num_args: usize) -> Result<usize> {
+        let idx = self.stack.len() - num_args - 1;
+        let mut func_val = self.stack[idx].clone();
+        let mut chain_len = 0;
+        while func_val.typ() != LuaType::Function {
+            let metamethod = self.get_metamethod(&func_val, MetaMethod::Call);
+            if let Val::Nil = metamethod {
+                return Err(self.type_error(TypeError::FunctionCall(func_val.typ())));
+            }
+            chain_len += 1;
+            if chain_len > Self::MAX_META_CHAIN {
+                let msg = "'__call' chain too long; possibly a loop".to_string();
+                return Err(self.error(ErrorKind::WithMessage(msg)));
+            }
+            self.stack.insert(idx, metamethod.clone());
+            num_args += 1;
+            func_val = metamethod;
+        }
+        Ok(num_args)
+    }
+
+    /// Runs the current thread, which has just been resumed with `num_args`
+    /// values on top of its stack, until it yields or returns.
+    fn run_thread(&mut self, num_args: usize) -> Result<FrameExit> {
+        if let Some(mut frame) = self.frames.pop() {
+            // The thread yielded, so the values are the results of the call
+            // which yielded.
+            self.stack_bottom = frame.stack_bottom;
+            self.finish_call(&mut frame, num_args);
+            return self.execute(frame, 0);
+        }
+        // The thread is starting, so its function is at the bottom of its
+        // stack. A Rust function can't yield, since there is no frame to
+        // suspend.
+        let num_args = self.resolve_callee(num_args)?;
+        match self.stack[0].as_lua_function() {
+            Some(closure) => {
+                let frame = self.prepare_frame(closure, num_args)?;
+                self.execute(frame, 0)
+            }
+            None => self.call_helper(num_args, MULTRET).map(FrameExit::Return),
+        }
+    }
+
+    /// Calls the `__gc` metamethod of every object awaiting finalization.
+    /// Errors raised by finalizers are ignored.
+    fn run_pending_finalizers(&mut self) {
+        if self.running_finalizers {
+            return;
+        }
+        self.running_finalizers = true;
+        while let Some(obj) = self.heap.pop_pending_finalizer() {
+            let val = Val::Obj(obj);
+            let finalizer = self.get_metamethod(&val, MetaMethod::Gc);
+            if let Val::Nil = finalizer {
+                continue;
+            }
+            let old_top = self.stack.len();
+            let old_stack_bottom = self.stack_bottom;
+            self.stack.extend([finalizer, val]);
+            if self.call_helper(1, 0).is_err() {
+                self.stack.truncate(old_top);
+                self.stack_bottom = old_stack_bottom;
+            }
+        }
+        self.running_finalizers = false;
+    }
+
+    /// Assigns `val` to `table[key]`, using the `__newindex` metamethod if
+    /// needed.
+    fn set_table_helper(&mut self, mut table: Val, key: Val, val: Val) -> Result<()> {
+        for _ in 0..Self::MAX_META_CHAIN {
+            let metamethod = if let Some(t) = table.as_table() {
+                if !matches!(t.get(&key), Val::Nil) {
+                    return t.insert(key, val);
+                }
+                let metamethod = self.get_metamethod(&table, MetaMethod::NewIndex);
+                if let Val::Nil = metamethod {
+                    // `table` has to be borrowed again, since getting the
+                    // metamethod borrowed `self`.
+                    return table.as_table().unwrap().insert(key, val);
+                }
+                metamethod
+            } else {
+                match self.get_metamethod(&table, MetaMethod::NewIndex) {
+                    Val::Nil => return Err(self.type_error(TypeError::TableIndex(table.typ()))),
+                    metamethod => metamethod,
+                }
+            };
+            if metamethod.typ() == LuaType::Function {
+                self.stack.extend([metamethod, table, key, val]);
+                self.call_helper(3, 0)?;
+                return Ok(());
+            }
+            table = metamethod;
+        }
+        let msg = "'__newindex' chain too long; possibly a loop".to_string();
+        Err(self.error(ErrorKind::WithMessage(msg)))
+    }
+
+    /// Moves the running thread's stack, frames, and so on into its object,
+    /// and replaces them with those of `thread`, which becomes the running
+    /// thread.
+    fn switch_thread(&mut self, mut thread: ObjectPtr) {
+        let mut current = self.current_thread;
+        self.swap_thread_fields(current.as_thread().unwrap());
+        self.swap_thread_fields(thread.as_thread().unwrap());
+        self.current_thread = thread;
+    }
+
+    fn swap_thread_fields(&mut self, thread: &mut Thread) {
+        std::mem::swap(&mut self.stack, &mut thread.stack);
+        std::mem::swap(&mut self.stack_bottom, &mut thread.stack_bottom);
+        std::mem::swap(&mut self.frames, &mut thread.frames);
+        std::mem::swap(&mut self.string_literals, &mut thread.string_literals);
+        std::mem::swap(&mut self.open_upvalues, &mut thread.open_upvalues);
+        std::mem::swap(&mut self.tbc_slots, &mut thread.tbc_slots);
+        std::mem::swap(
+            &mut self.non_yieldable_calls,
+            &mut thread.non_yieldable_calls,
+        );
+    }
+
+    /// Returns the thread at the given index. Panics if the value isn't a
+    /// thread.
+    fn thread_at(&self, i: isize) -> ObjectPtr {
+        match self.at_index(i) {
+            Val::Obj(o) if o.typ() == LuaType::Thread => o,
+            val => panic!("Expected a thread, got a {}", val.typ()),
+        }
+    }
+
     fn type_error(&self, e: TypeError) -> Error {
         self.error(ErrorKind::TypeError(e))
     }
+
+    /// Runs `f` with `thread` as the running thread, after moving the top
+    /// `num_values` values of the current stack onto the thread's stack.
+    /// The current thread is running again once `f` returns.
+    fn with_thread<T>(
+        &mut self,
+        mut thread: ObjectPtr,
+        num_values: usize,
+        f: impl FnOnce(&mut Self) -> T,
+    ) -> Result<T> {
+        self.enter_rust_call()?;
+        let values = self.stack.split_off(self.stack.len() - num_values);
+        let mut caller = self.current_thread;
+        caller.as_thread().unwrap().status = ThreadStatus::Normal;
+        thread.as_thread().unwrap().status = ThreadStatus::Running;
+        self.switch_thread(thread);
+        self.stack.extend(values);
+        let result = f(self);
+        self.switch_thread(caller);
+        self.rust_call_depth -= 1;
+        caller.as_thread().unwrap().status = ThreadStatus::Running;
+        Ok(result)
+    }
+}
+
+/// Closes every upvalue in `open_upvalues` which refers to the given index of
+/// `stack` or higher.
+fn close_upvalues_of(open_upvalues: &mut Vec<UpvalueRef>, stack: &[Val], first_index: usize) {
+    open_upvalues.retain(|upvalue| {
+        let mut upvalue = upvalue.borrow_mut();
+        match *upvalue {
+            Upvalue::Open(_, i) if i >= first_index => {
+                *upvalue = Upvalue::Closed(stack[i].clone());
+                false
+            }
+            _ => true,
+        }
+    });
 }
 
 impl Default for State {
@@ -519,6 +1518,20 @@ impl Default for State {
     }
 }
 
+impl Drop for State {
+    /// Calls the finalizers of all objects marked for finalization, whether
+    /// or not they are still reachable.
+    fn drop(&mut self) {
+        loop {
+            self.heap.finalize_all();
+            if !self.heap.has_pending_finalizers() {
+                break;
+            }
+            self.run_pending_finalizers();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::compiler::parse_str;
@@ -526,13 +1539,17 @@ mod tests {
     use super::Chunk;
     use super::Instr::*;
     use super::State;
+    use super::ThreadStatus;
+    use super::MULTRET;
+    use crate::LuaType;
 
     #[test]
     fn vm_test01() {
         let mut state = State::new();
         let input = parse_str("a = 1").unwrap();
-        state.eval_chunk(input, 0).unwrap();
-        assert_eq!(Val::Num(1.0), *state.globals.get("a").unwrap());
+        state.push_chunk(input);
+        state.call(0, 0).unwrap();
+        assert_eq!(Val::Num(1.0), *state.globals.get("a".as_bytes()).unwrap());
     }
 
     #[test]
@@ -546,12 +1563,13 @@ mod tests {
                 SetGlobal(0),
                 Return(0),
             ],
-            string_literals: vec!["key".to_string(), "a".to_string(), "b".to_string()],
+            string_literals: vec!["key".into(), "a".into(), "b".into()],
             ..Chunk::default()
         };
-        state.eval_chunk(input, 0).unwrap();
-        let val = state.globals.get("key").unwrap();
-        assert_eq!("ab".to_string(), val.as_string().unwrap());
+        state.push_chunk(input);
+        state.call(0, 0).unwrap();
+        let val = state.globals.get("key".as_bytes()).unwrap();
+        assert_eq!(b"ab", val.as_string().unwrap());
     }
 
     #[test]
@@ -560,11 +1578,12 @@ mod tests {
         let input = Chunk {
             code: vec![PushNum(0), PushNum(0), Equal, SetGlobal(0), Return(0)],
             number_literals: vec![2.5],
-            string_literals: vec!["a".to_string()],
+            string_literals: vec!["a".into()],
             ..Chunk::default()
         };
-        state.eval_chunk(input, 0).unwrap();
-        assert_eq!(Val::Bool(true), *state.globals.get("a").unwrap());
+        state.push_chunk(input);
+        state.call(0, 0).unwrap();
+        assert_eq!(Val::Bool(true), *state.globals.get("a".as_bytes()).unwrap());
     }
 
     #[test]
@@ -579,11 +1598,15 @@ mod tests {
                 SetGlobal(0),
                 Return(0),
             ],
-            string_literals: vec!["key".to_string()],
+            string_literals: vec!["key".into()],
             ..Chunk::default()
         };
-        state.eval_chunk(input, 0).unwrap();
-        assert_eq!(Val::Bool(false), *state.globals.get("key").unwrap());
+        state.push_chunk(input);
+        state.call(0, 0).unwrap();
+        assert_eq!(
+            Val::Bool(false),
+            *state.globals.get("key".as_bytes()).unwrap()
+        );
     }
 
     #[test]
@@ -599,11 +1622,12 @@ mod tests {
         let chunk = Chunk {
             code,
             number_literals: vec![5.0],
-            string_literals: vec!["a".to_string()],
+            string_literals: vec!["a".into()],
             ..Chunk::default()
         };
-        state.eval_chunk(chunk, 0).unwrap();
-        assert_eq!(Val::Num(5.0), *state.globals.get("a").unwrap());
+        state.push_chunk(chunk);
+        state.call(0, 0).unwrap();
+        assert_eq!(Val::Num(5.0), *state.globals.get("a".as_bytes()).unwrap());
     }
 
     #[test]
@@ -621,11 +1645,12 @@ mod tests {
         let chunk = Chunk {
             code,
             number_literals: vec![2.0],
-            string_literals: vec!["a".to_string()],
+            string_literals: vec!["a".into()],
             ..Chunk::default()
         };
-        state.eval_chunk(chunk, 0).unwrap();
-        assert!(state.globals.get("a").is_none());
+        state.push_chunk(chunk);
+        state.call(0, 0).unwrap();
+        assert!(!state.globals.contains_key("a".as_bytes()));
     }
 
     #[test]
@@ -647,11 +1672,12 @@ mod tests {
         let chunk = Chunk {
             code,
             number_literals: vec![1.0, 10.0, 0.0],
-            string_literals: vec!["a".to_string()],
+            string_literals: vec!["a".into()],
             ..Chunk::default()
         };
         let mut state = State::new();
-        state.eval_chunk(chunk, 0).unwrap();
+        state.push_chunk(chunk);
+        state.call(0, 0).unwrap();
     }
 
     #[test]
@@ -680,13 +1706,14 @@ mod tests {
         let chunk = Chunk {
             code,
             number_literals: vec![1.0, 10.0, 1.0],
-            string_literals: vec!["x".to_string()],
+            string_literals: vec!["x".into()],
             num_locals: 1,
             ..Chunk::default()
         };
         let mut state = State::new();
-        state.eval_chunk(chunk, 0).unwrap();
-        assert_eq!(Val::Num(10.0), *state.globals.get("x").unwrap());
+        state.push_chunk(chunk);
+        state.call(0, 0).unwrap();
+        assert_eq!(Val::Num(10.0), *state.globals.get("x".as_bytes()).unwrap());
     }
 
     #[test]
@@ -707,13 +1734,14 @@ mod tests {
         let chunk = Chunk {
             code,
             number_literals: vec![6.0, 2.0],
-            string_literals: vec!["a".to_string()],
+            string_literals: vec!["a".into()],
             num_locals: 4,
             ..Chunk::default()
         };
         let mut state = State::new();
-        state.eval_chunk(chunk, 0).unwrap();
-        assert!(state.globals.get("a").is_none());
+        state.push_chunk(chunk);
+        state.call(0, 0).unwrap();
+        assert!(!state.globals.contains_key("a".as_bytes()));
     }
 
     #[test]
@@ -723,10 +1751,342 @@ mod tests {
             for i = 1, 3 do
                 a = a + i
             end";
-        let chunk = parse_str(&text).unwrap();
+        let chunk = parse_str(text).unwrap();
         let mut state = State::new();
-        state.eval_chunk(chunk, 0).unwrap();
-        let a = state.globals.get("a").unwrap().as_num().unwrap();
+        state.push_chunk(chunk);
+        state.call(0, 0).unwrap();
+        let a = state.globals.get("a".as_bytes()).unwrap().as_num().unwrap();
         assert_eq!(a, 6.0);
     }
+
+    #[test]
+    fn vm_test12() {
+        let mut state = State::new();
+        state.load_string("return 1, 2, 3").unwrap();
+        state.call(0, MULTRET).unwrap();
+        assert_eq!(state.get_top(), 3);
+        assert_eq!(state.to_number(1).unwrap(), 1.0);
+        assert_eq!(state.to_number(3).unwrap(), 3.0);
+        state.load_string("return 1, 2, 3").unwrap();
+        state.call(0, 1).unwrap();
+        assert_eq!(state.get_top(), 4);
+        assert_eq!(state.to_number(4).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn vm_test13() {
+        let ones = vec!["1"; 200].join(", ");
+        let code = format!(
+            "function f() return {0} end
+            function g() return {0}, f() end
+            assert(select('#', g()) == 400)",
+            ones
+        );
+        let mut state = State::new();
+        state.do_string(&code).unwrap();
+        let err = state.do_string("select(2, g())").unwrap_err();
+        assert!(err.to_string().contains("too many results to select"));
+        state.do_string("assert(select(200, g()) == 1)").unwrap();
+    }
+
+    #[test]
+    fn vm_test14() {
+        let mut state = State::new();
+        state
+            .load_string("return 1, 1.0, -0.0, 1e15, 1e16, 0.1, 1/3, 2^63, 1e-5, 3 // 0.0")
+            .unwrap();
+        state.call(0, MULTRET).unwrap();
+        let strings: Vec<_> = (1..=10).map(|i| state.to_string(i)).collect();
+        let expected = [
+            "1",
+            "1.0",
+            "-0.0",
+            "1e+15",
+            "1e+16",
+            "0.1",
+            "0.33333333333333",
+            "9.2233720368548e+18",
+            "1e-05",
+            "inf",
+        ];
+        assert_eq!(strings, expected);
+        assert!(state.is_integer(1));
+        assert!(!state.is_integer(2));
+        assert_eq!(state.to_integer(2).unwrap(), 1);
+        assert!(state.to_integer(6).is_err());
+        assert!(state.do_string("return 1 // 0").is_err());
+        assert!(state.do_string("return 1 % 0").is_err());
+        assert!(state.do_string("return 1 // 0.0, 1 % 0.0").is_ok());
+        assert!(state.do_string("for i = 1, 2, 0 do end").is_err());
+    }
+
+    #[test]
+    fn vm_test15() {
+        let mut state = State::new();
+        let err = state.do_string("return 1.5 | 0").unwrap_err();
+        assert!(err.to_string().contains("no integer representation"));
+        let err = state.do_string("return '1.5' | 0").unwrap_err();
+        assert!(err.to_string().contains("no integer representation"));
+        let err = state.do_string("return 'x' & 1").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("bitwise operation on a string value"));
+        let err = state.do_string("return ~{}").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("bitwise operation on a table value"));
+    }
+
+    #[test]
+    fn vm_test16() {
+        let mut state = State::new();
+        let code = "local t = setmetatable({}, {__metatable = 1}) setmetatable(t, {})";
+        let err = state.do_string(code).unwrap_err();
+        assert!(err.to_string().contains("protected metatable"));
+        let code = "local t = {} setmetatable(t, {__index = t}) return t.x";
+        let err = state.do_string(code).unwrap_err();
+        assert!(err.to_string().contains("'__index' chain too long"));
+        let code = "local t = {} setmetatable(t, {__newindex = t}) t.x = 1";
+        let err = state.do_string(code).unwrap_err();
+        assert!(err.to_string().contains("'__newindex' chain too long"));
+        let code = "setmetatable({}, {__index = 5}).x = 1 return setmetatable({}, {__index = 5}).x";
+        let err = state.do_string(code).unwrap_err();
+        assert!(err.to_string().contains("attempt to index a number value"));
+    }
+
+    #[test]
+    fn vm_test17() {
+        let mut state = State::new();
+        let err = state.do_string("return 1 + {}").unwrap_err();
+        assert!(err.to_string().contains("arithmetic on a table value"));
+        let err = state.do_string("return 'x' * 2").unwrap_err();
+        assert!(err.to_string().contains("arithmetic on a string value"));
+        let err = state.do_string("return 'a' .. {}").unwrap_err();
+        assert!(err.to_string().contains("concatenate a table value"));
+        let err = state.do_string("return -{}").unwrap_err();
+        assert!(err.to_string().contains("arithmetic on a table value"));
+
+        state.push_string("a".into());
+        state.push_integer(1);
+        state.push_number(2.5);
+        state.concat(3).unwrap();
+        assert_eq!(state.to_string(-1), "a12.5");
+        state.concat(0).unwrap();
+        assert_eq!(state.to_string(-1), "");
+    }
+
+    #[test]
+    fn vm_test18() {
+        let mut state = State::new();
+        let err = state.do_string("return {} < {}").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("attempt to compare two table values"));
+        let err = state.do_string("return 1 < 'x'").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("attempt to compare number with string"));
+        let err = state.do_string("return 1 > nil").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("attempt to compare nil with number"));
+
+        state.new_table();
+        state.new_table();
+        assert!(!state.raw_equal(-1, -2));
+        assert!(state.raw_equal(-1, -1));
+        state.push_integer(1);
+        state.push_number(1.0);
+        assert!(state.raw_equal(-1, -2));
+    }
+
+    #[test]
+    fn vm_test19() {
+        let mut state = State::new();
+        let code = "
+            adder = setmetatable({}, {
+                __call = function(self, a, b) return self, a + b end
+            })
+        ";
+        state.do_string(code).unwrap();
+        state.get_global("adder");
+        state.push_integer(2);
+        state.push_integer(3);
+        state.call(2, 2).unwrap();
+        assert_eq!(state.get_top(), 2);
+        assert_eq!(state.typ(-2), LuaType::Table);
+        assert_eq!(state.to_integer(-1).unwrap(), 5);
+        state.set_top(0);
+
+        state.new_table();
+        let err = state.call(0, 0).unwrap_err();
+        assert!(err.to_string().contains("attempt to call a table value"));
+        let code = "local t = setmetatable({}, {}) getmetatable(t).__call = t t()";
+        let err = state.do_string(code).unwrap_err();
+        assert!(err.to_string().contains("'__call' chain too long"));
+    }
+
+    #[test]
+    fn vm_test20() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static FINALIZED: AtomicUsize = AtomicUsize::new(0);
+        fn finalizer(_: &mut State) -> crate::Result<u8> {
+            FINALIZED.fetch_add(1, Ordering::SeqCst);
+            Ok(0)
+        }
+
+        let mut state = State::new();
+        state.push_rust_fn(finalizer);
+        state.set_global("finalizer");
+        let code = "
+            local mt = {__gc = finalizer}
+            kept = setmetatable({}, mt)
+            local t = setmetatable({}, mt)
+            setmetatable(t, nil)
+            setmetatable({}, {})
+        ";
+        state.do_string(code).unwrap();
+        state.new_table();
+        state.new_table();
+        state.push_rust_fn(finalizer);
+        state.set_field(-2, "__gc").unwrap();
+        state.set_metatable(-2);
+        assert_eq!(FINALIZED.load(Ordering::SeqCst), 0);
+        // Every object still marked for finalization is finalized when the
+        // state is closed, even if it is reachable.
+        drop(state);
+        assert_eq!(FINALIZED.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn vm_test21() {
+        let mut state = State::new();
+        let err = state.do_string("return #5").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("attempt to get length of a number value"));
+        let err = state.do_string("return rawlen(5)").unwrap_err();
+        assert!(err.to_string().contains("table or string expected"));
+
+        state
+            .do_string("t = setmetatable({1, 2, 3}, {__len = function() return 'x' end})")
+            .unwrap();
+        state.get_global("t");
+        assert_eq!(state.raw_len(-1), 3);
+        state.len(-1).unwrap();
+        assert_eq!(state.to_string(-1), "x");
+        state.push_string("four".into());
+        assert_eq!(state.raw_len(-1), 4);
+        state.len(-1).unwrap();
+        assert_eq!(state.to_integer(-1).unwrap(), 4);
+        state.push_nil();
+        assert_eq!(state.raw_len(-1), 0);
+    }
+
+    #[test]
+    fn vm_test22() {
+        let mut state = State::new();
+        let same_object = |state: &State| match &state.stack[state.stack.len() - 2..] {
+            [Val::Obj(a), Val::Obj(b)] => a == b,
+            _ => panic!("expected two objects"),
+        };
+        state.push_string("short".into());
+        state.push_string(String::from("sho") + "rt");
+        assert!(same_object(&state));
+        state.do_string("s = 'short'").unwrap();
+        state.get_global("s");
+        assert!(same_object(&state));
+
+        let long = "a string which is too long to be interned by the state";
+        state.push_string(long.into());
+        state.push_string(long.into());
+        assert!(!same_object(&state));
+        assert!(state.raw_equal(-1, -2));
+    }
+
+    #[test]
+    fn vm_test23() {
+        let mut state = State::new();
+        let err = state.do_string("coroutine.yield()").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("attempt to yield from outside a coroutine"));
+        let code = "
+            local t = setmetatable({}, {__index = function() coroutine.yield() end})
+            coroutine.wrap(function() return t.x end)()
+        ";
+        let err = state.do_string(code).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("attempt to yield across a C-call boundary"));
+        let code = "coroutine.wrap(function() return {} + 1 end)()";
+        let err = state.do_string(code).unwrap_err();
+        assert!(err.to_string().contains("arithmetic on a table value"));
+
+        state
+            .do_string("co = coroutine.create(function(a) while true do a = coroutine.yield(a * 2) end end)")
+            .unwrap();
+        state.get_global("co");
+        assert_eq!(state.typ(1), LuaType::Thread);
+        assert_eq!(state.thread_status(1), ThreadStatus::Suspended);
+        for i in 1..=3 {
+            state.push_integer(i);
+            assert_eq!(state.resume(1, 1).unwrap(), 1);
+            assert_eq!(state.to_integer(-1).unwrap(), i * 2);
+            state.pop(1);
+        }
+        assert!(state.close_thread(1).unwrap());
+        assert_eq!(state.thread_status(1), ThreadStatus::Dead);
+        let err = state.resume(1, 0).unwrap_err();
+        assert!(err.to_string().contains("cannot resume dead coroutine"));
+        assert_eq!(state.get_top(), 1);
+
+        state.push_integer(5);
+        state.push_rust_closure(
+            |state| {
+                state.push_upvalue(1);
+                Ok(1)
+            },
+            1,
+        );
+        assert_eq!(state.typ(-1), LuaType::Function);
+        state.call(0, 1).unwrap();
+        assert_eq!(state.to_integer(-1).unwrap(), 5);
+    }
+
+    #[test]
+    fn vm_test24() {
+        let mut state = State::new();
+        state.set_max_call_depth(50);
+        let code = "
+            function f(n) if n == 0 then return 0 end return 1 + f(n - 1) end
+            function loop(n) if n == 0 then return 0 end return loop(n - 1) end
+        ";
+        state.do_string(code).unwrap();
+        state.do_string("assert(f(40) == 40)").unwrap();
+        let err = state.do_string("f(60)").unwrap_err();
+        assert!(err.to_string().contains("stack overflow"));
+        assert_eq!(state.get_top(), 0);
+        // Tail calls don't use up the call depth.
+        state.do_string("assert(loop(1000) == 0)").unwrap();
+
+        let code = "
+            local t = setmetatable({}, {})
+            getmetatable(t).__index = function(t, k) return t[k] end
+            return t.x
+        ";
+        let err = state.do_string(code).unwrap_err();
+        assert!(err.to_string().contains("C stack overflow"));
+        state.do_string("assert(f(10) == 10)").unwrap();
+    }
+
+    #[test]
+    fn vm_test25() {
+        let mut state = State::new();
+        let err = state.do_string("local x <close> = {}").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("variable 'x' got a non-closable value"));
+        state.do_string("local x <close> = nil").unwrap();
+    }
 }
@@ -1,33 +1,82 @@
+use std::cmp::Ordering;
 use std::ops;
+use std::rc::Rc;
 
-use super::super::error::TypeError;
+use super::super::compiler::UpvalueDesc;
+use super::super::error::{Error, ErrorKind, TypeError};
+use super::lua_val::{cmp_numbers, float_to_int};
+use super::metamethod::MetaMethod;
+use super::object::{LuaClosure, Upvalue, UpvalueRef};
 use super::Chunk;
 use super::Instr;
 use super::LuaType;
 use super::Result;
 use super::State;
 use super::Val;
+use super::MULTRET;
 
 /// A `Frame` represents a single stack-frame of a Lua function.
 #[derive(Default)]
 pub(super) struct Frame {
     /// The chunk being executed
-    chunk: Chunk,
+    chunk: Rc<Chunk>,
+    /// The upvalues of the closure being executed
+    upvalues: Vec<UpvalueRef>,
     /// The index of the next (not current) instruction
     ip: usize,
+    /// The index in the stack of the function being executed. Its return
+    /// values are moved here.
+    pub(super) func_index: usize,
+    /// The index in the stack of the frame's first local.
+    pub(super) stack_bottom: usize,
     /// Offset into `State.string_literals` where this chunk's literals are
     /// stored.
-    string_literal_start: usize,
+    pub(super) string_literal_start: usize,
+    /// The number of values produced by the most recent multi-valued
+    /// expression.
+    pub(super) multi_len: usize,
+    /// The number of extra arguments, which are stored just below the
+    /// frame's stack.
+    num_varargs: usize,
+}
+
+/// The reasons a `Frame` stops evaluating instructions.
+pub(super) enum FrameExit {
+    /// The frame returned this many values, which are on top of the stack.
+    Return(usize),
+    /// The frame is calling a Lua function, which is on the stack below the
+    /// given number of arguments. Once it returns, `State::finish_call`
+    /// completes the instruction which called it.
+    Call(LuaClosure, usize),
+    /// The frame is tail-calling a Lua function, which has already been
+    /// moved down to replace the frame's function, followed by the given
+    /// number of arguments.
+    TailCall(LuaClosure, usize),
+    /// A Rust function called by the frame yielded this many values, which
+    /// are on top of the stack.
+    Yield(usize),
 }
 
 impl Frame {
     /// Create a new Frame.
-    pub(super) fn new(chunk: Chunk, string_literal_start: usize) -> Self {
+    pub(super) fn new(
+        closure: LuaClosure,
+        func_index: usize,
+        stack_bottom: usize,
+        string_literal_start: usize,
+        num_varargs: usize,
+    ) -> Self {
+        let LuaClosure { chunk, upvalues } = closure;
         let ip = 0;
         Self {
             chunk,
+            upvalues,
             ip,
+            func_index,
+            stack_bottom,
             string_literal_start,
+            multi_len: 0,
+            num_varargs,
         }
     }
 
@@ -44,7 +93,7 @@ impl Frame {
         i
     }
 
-    fn get_nested_chunk(&mut self, i: u8) -> Chunk {
+    fn get_nested_chunk(&mut self, i: u8) -> Rc<Chunk> {
         self.chunk.nested[i as usize].clone()
     }
 
@@ -52,9 +101,19 @@ impl Frame {
         self.chunk.number_literals[i as usize]
     }
 
-    /// Start evaluating instructions from the current position.
-    pub(super) fn eval(&mut self, state: &mut State) -> Result<u8> {
+    fn get_integer_constant(&self, i: u8) -> i64 {
+        self.chunk.integer_literals[i as usize]
+    }
+
+    /// Start evaluating instructions from the current position, until the
+    /// frame returns or needs `State::execute` to do something for it.
+    pub(super) fn eval(&mut self, state: &mut State) -> Result<FrameExit> {
         loop {
+            // Finalizers only run between instructions, where the stack is in
+            // a consistent state.
+            if state.heap.has_pending_finalizers() {
+                state.run_pending_finalizers();
+            }
             let inst = self.get_instr();
             if option_env!("LUA_DEBUG_VM").is_some() {
                 println!("{:?}", inst);
@@ -74,14 +133,45 @@ impl Frame {
                 Instr::GetLocal(i) => state.instr_get_local(i),
                 Instr::SetLocal(i) => state.instr_set_local(i),
 
+                // Upvalues
+                Instr::GetUpvalue(i) => state.instr_get_upvalue(self, i),
+                Instr::SetUpvalue(i) => state.instr_set_upvalue(self, i),
+                Instr::Close(i) => state.close_locals(state.stack_bottom + i as usize)?,
+                Instr::ToBeClosed(i, name) => state.instr_to_be_closed(self, i, name)?,
+
                 Instr::GetGlobal(i) => state.instr_get_global(self, i),
                 Instr::SetGlobal(i) => state.instr_set_global(self, i),
 
                 // Functions
                 Instr::Closure(i) => state.instr_closure(self, i),
-                Instr::Call(num_args, num_rets) => state.call(num_args, num_rets)?,
+                Instr::Call(num_args, _) => {
+                    if let Some(exit) = state.instr_call(self, num_args as usize)? {
+                        return Ok(exit);
+                    }
+                }
+                Instr::CallMulti(num_args, _) => {
+                    let num_args = num_args as usize + self.multi_len;
+                    if let Some(exit) = state.instr_call(self, num_args)? {
+                        return Ok(exit);
+                    }
+                }
+                Instr::TailCall(num_args) => {
+                    if let Some(exit) = state.instr_tail_call(self, num_args as usize)? {
+                        return Ok(exit);
+                    }
+                }
+                Instr::TailCallMulti(num_args) => {
+                    let num_args = num_args as usize + self.multi_len;
+                    if let Some(exit) = state.instr_tail_call(self, num_args)? {
+                        return Ok(exit);
+                    }
+                }
+                Instr::VarArg(n) => state.instr_vararg(self, n),
                 Instr::Return(n) => {
-                    return Ok(n);
+                    return Ok(FrameExit::Return(n as usize));
+                }
+                Instr::ReturnMulti(n) => {
+                    return Ok(FrameExit::Return(n as usize + self.multi_len));
                 }
 
                 // Literals
@@ -91,56 +181,87 @@ impl Frame {
                     let n = self.get_number_constant(i);
                     state.push_number(n);
                 }
+                Instr::PushInt(i) => {
+                    let n = self.get_integer_constant(i);
+                    state.push_integer(n);
+                }
                 Instr::PushString(i) => {
                     let val = state.get_string_constant(self, i);
                     state.stack.push(val);
                 }
 
                 // Arithmetic
-                Instr::Add => state.eval_float_float(<f64 as ops::Add>::add)?,
-                Instr::Subtract => state.eval_float_float(<f64 as ops::Sub>::sub)?,
-                Instr::Multiply => state.eval_float_float(<f64 as ops::Mul>::mul)?,
-                Instr::Divide => state.eval_float_float(<f64 as ops::Div>::div)?,
-                Instr::Mod => state.eval_float_float(<f64 as ops::Rem>::rem)?,
-                Instr::Pow => state.eval_float_float(f64::powf)?,
-
-                // Equality
-                Instr::Equal => {
-                    let val2 = state.pop_val();
-                    let val1 = state.pop_val();
-                    state.push_boolean(val1 == val2);
+                Instr::Add => {
+                    let int_op = |a: i64, b| Some(a.wrapping_add(b));
+                    state.eval_arith(MetaMethod::Add, int_op, <f64 as ops::Add>::add)?
+                }
+                Instr::Subtract => {
+                    let int_op = |a: i64, b| Some(a.wrapping_sub(b));
+                    state.eval_arith(MetaMethod::Sub, int_op, <f64 as ops::Sub>::sub)?
                 }
-                Instr::NotEqual => {
-                    let val2 = state.pop_val();
-                    let val1 = state.pop_val();
-                    state.push_boolean(val1 != val2);
+                Instr::Multiply => {
+                    let int_op = |a: i64, b| Some(a.wrapping_mul(b));
+                    state.eval_arith(MetaMethod::Mul, int_op, <f64 as ops::Mul>::mul)?
                 }
+                Instr::Divide => state.eval_float_float(MetaMethod::Div, <f64 as ops::Div>::div)?,
+                Instr::FloorDivide => {
+                    state.eval_arith(MetaMethod::Idiv, int_floor_div, float_floor_div)?
+                }
+                Instr::Mod => state.eval_arith(MetaMethod::Mod, int_mod, float_mod)?,
+                Instr::Pow => state.eval_float_float(MetaMethod::Pow, f64::powf)?,
+
+                // Bitwise
+                Instr::BitAnd => state.eval_bitwise(<i64 as ops::BitAnd>::bitand)?,
+                Instr::BitOr => state.eval_bitwise(<i64 as ops::BitOr>::bitor)?,
+                Instr::BitXor => state.eval_bitwise(<i64 as ops::BitXor>::bitxor)?,
+                Instr::ShiftLeft => state.eval_bitwise(shift_left)?,
+                Instr::ShiftRight => state.eval_bitwise(|a, b| shift_left(a, b.wrapping_neg()))?,
 
-                // Orderings
-                Instr::Less => state.eval_float_bool(<f64 as PartialOrd>::lt)?,
-                Instr::Greater => state.eval_float_bool(<f64 as PartialOrd>::gt)?,
-                Instr::LessEqual => state.eval_float_bool(<f64 as PartialOrd>::le)?,
-                Instr::GreaterEqual => state.eval_float_bool(<f64 as PartialOrd>::ge)?,
+                // Equality
+                Instr::Equal => state.eval_equal(false)?,
+                Instr::NotEqual => state.eval_equal(true)?,
+
+                // Orderings. `a > b` is evaluated as `b < a`, and `a >= b` as
+                // `b <= a`.
+                Instr::Less => state.eval_comparison(MetaMethod::Lt, false)?,
+                Instr::Greater => state.eval_comparison(MetaMethod::Lt, true)?,
+                Instr::LessEqual => state.eval_comparison(MetaMethod::Le, false)?,
+                Instr::GreaterEqual => state.eval_comparison(MetaMethod::Le, true)?,
 
                 // `for` loops
                 Instr::ForLoop(slot, offset) => state.instr_for_loop(self, slot, offset)?,
                 Instr::ForPrep(slot, len) => state.instr_for_prep(self, slot, len)?,
+                Instr::GenericForCall(slot, _) => {
+                    if let Some(exit) = state.instr_generic_for_call(self, slot)? {
+                        return Ok(exit);
+                    }
+                }
+                Instr::GenericForLoop(slot, offset) => {
+                    state.instr_generic_for_loop(self, slot, offset)
+                }
 
                 // Unary
-                Instr::Length => state.instr_length()?,
+                Instr::Length => state.len_helper()?,
                 Instr::Negate => state.instr_negate()?,
+                Instr::BitNot => {
+                    let val = state.pop_val();
+                    let n = state.bitwise_operand(&val)?;
+                    state.push_integer(!n);
+                }
                 Instr::Not => state.instr_not(),
 
                 // Manipulating tables
                 Instr::NewTable => state.new_table(),
                 Instr::GetField(i) => state.instr_get_field(self, i)?,
+                Instr::GetMethod(i) => state.instr_get_method(self, i)?,
                 Instr::GetTable => state.instr_get_table()?,
                 Instr::InitField(offset, key_id) => state.instr_init_field(self, offset, key_id)?,
                 Instr::InitIndex(offset) => state.instr_init_index(offset)?,
                 Instr::SetField(offset, i) => state.instr_set_field(self, offset, i)?,
                 Instr::SetTable(offset) => state.instr_set_table(offset)?,
 
-                Instr::SetList(n) => state.instr_set_list(n)?,
+                Instr::SetList(n) => state.instr_set_list(n as usize)?,
+                Instr::SetListMulti(n) => state.instr_set_list(n as usize + self.multi_len)?,
 
                 // Misc.
                 Instr::Concat => state.concat_helper(2)?,
@@ -164,57 +285,200 @@ impl State {
         }
     }
 
+    /// Calls the function below `num_args` arguments. A Rust function is
+    /// called right away, but for a Lua function, the frame returns control
+    /// to `State::execute`, which pushes a new frame instead of recursing.
+    fn instr_call(&mut self, frame: &mut Frame, num_args: usize) -> Result<Option<FrameExit>> {
+        let num_args = self.resolve_callee(num_args)?;
+        let func_index = self.stack.len() - num_args - 1;
+        if let Some(closure) = self.stack[func_index].as_lua_function() {
+            return Ok(Some(FrameExit::Call(closure, num_args)));
+        }
+        let num_results = self.call_rust_fn(num_args)?;
+        if self.yielding {
+            self.yielding = false;
+            return Ok(Some(FrameExit::Yield(num_results)));
+        }
+        self.finish_call(frame, num_results);
+        Ok(None)
+    }
+
+    /// Completes the call instruction which the frame is in the middle of,
+    /// once the called function has left `num_results` values on top of the
+    /// stack. This happens after a Lua function returns, or after a thread
+    /// which yielded is resumed.
+    pub(super) fn finish_call(&mut self, frame: &mut Frame, num_results: usize) {
+        match frame.chunk.code[frame.ip - 1] {
+            Instr::Call(_, num_rets) | Instr::CallMulti(_, num_rets) => {
+                frame.multi_len = self.adjust_results(num_rets, num_results);
+            }
+            Instr::TailCall(_) | Instr::TailCallMulti(_) => frame.multi_len = num_results,
+            Instr::GenericForCall(local_slot, num_vars) => {
+                self.balance_stack(num_vars as usize, num_results);
+                let slot = local_slot as usize + self.stack_bottom;
+                let results = self.stack.split_off(self.stack.len() - num_vars as usize);
+                let var_slots = &mut self.stack[slot + 3..slot + 3 + num_vars as usize];
+                for (var, val) in var_slots.iter_mut().zip(results) {
+                    *var = val;
+                }
+            }
+            i => panic!("Tried to finish a call at {:?}", i),
+        }
+    }
+
+    /// Tail-calls the function below `num_args` arguments. For a Lua
+    /// function, the frame's locals are discarded, and the function and its
+    /// arguments are moved down to where the frame's own function was, so
+    /// that `State::execute` can replace the frame. Anything else is called
+    /// as usual, and the following `ReturnMulti` returns the results.
+    fn instr_tail_call(&mut self, frame: &mut Frame, num_args: usize) -> Result<Option<FrameExit>> {
+        let num_args = self.resolve_callee(num_args)?;
+        let func_index = self.stack.len() - num_args - 1;
+        match self.stack[func_index].as_lua_function() {
+            Some(closure) => {
+                self.close_upvalues(self.stack_bottom);
+                self.string_literals.truncate(frame.string_literal_start);
+                self.stack.drain(frame.func_index..func_index);
+                Ok(Some(FrameExit::TailCall(closure, num_args)))
+            }
+            None => self.instr_call(frame, num_args),
+        }
+    }
+
     fn instr_closure(&mut self, frame: &mut Frame, i: u8) {
         let chunk = frame.get_nested_chunk(i);
-        self.push_chunk(chunk);
+        let upvalues = chunk
+            .upvalues
+            .iter()
+            .map(|desc| match *desc {
+                UpvalueDesc::Local(slot) => self.find_upvalue(self.stack_bottom + slot as usize),
+                UpvalueDesc::Upvalue(i) => frame.upvalues[i as usize].clone(),
+            })
+            .collect();
+        self.push_closure(LuaClosure { chunk, upvalues });
     }
 
+    /// If the initial value and the step are integers, the loop is done
+    /// with integers, and the second slot holds the number of iterations
+    /// left, so that the loop can't overflow. Otherwise, every value is
+    /// converted to a float.
     fn instr_for_prep(&mut self, frame: &mut Frame, local: u8, body_len: isize) -> Result<()> {
         // These slots should only be assigned to during this function.
-        let step = self.pop_val().as_num().unwrap();
-        let end = self.pop_val().as_num().unwrap();
-        let start = self.pop_val().as_num().unwrap();
-        if check_numeric_for_condition(start, end, step) {
-            let mut local_slot = local as usize + self.stack_bottom;
-            for &n in &[start, end, step, start] {
-                self.stack[local_slot] = Val::Num(n);
-                local_slot += 1;
+        let step = self.pop_val();
+        let limit = self.pop_val();
+        let start = self.pop_val();
+        let slots = if let (Val::Int(start), Val::Int(step)) = (&start, &step) {
+            let (start, step) = (*start, *step);
+            if step == 0 {
+                return Err(self.for_error("'for' step is zero"));
             }
+            let limit = match self.for_limit(&limit, step)? {
+                Some(limit) if step > 0 && start <= limit => limit,
+                Some(limit) if step < 0 && start >= limit => limit,
+                _ => {
+                    frame.jump(body_len);
+                    return Ok(());
+                }
+            };
+            let count = if step > 0 {
+                (limit as u64).wrapping_sub(start as u64) / step as u64
+            } else {
+                // Avoid overflowing when negating `i64::MIN`.
+                let step = (-(step + 1)) as u64 + 1;
+                (start as u64).wrapping_sub(limit as u64) / step
+            };
+            [start, count as i64, step, start].map(Val::Int)
         } else {
-            frame.jump(body_len);
+            let start = self.for_float(&start, "initial")?;
+            let limit = self.for_float(&limit, "limit")?;
+            let step = self.for_float(&step, "step")?;
+            if step == 0.0 {
+                return Err(self.for_error("'for' step is zero"));
+            }
+            if !check_numeric_for_condition(start, limit, step) {
+                frame.jump(body_len);
+                return Ok(());
+            }
+            [start, limit, step, start].map(Val::Num)
+        };
+        let local_slot = local as usize + self.stack_bottom;
+        for (slot, val) in self.stack[local_slot..local_slot + 4].iter_mut().zip(slots) {
+            *slot = val;
         }
         Ok(())
     }
 
     fn instr_for_loop(&mut self, frame: &mut Frame, local_slot: u8, offset: isize) -> Result<()> {
         let slot = local_slot as usize + self.stack_bottom;
-        let mut var = self.stack[slot].as_num().unwrap();
-        let limit = self.stack[slot + 1].as_num().unwrap();
-        let step = self.stack[slot + 2].as_num().unwrap();
-        var += step;
-        if check_numeric_for_condition(var, limit, step) {
-            self.stack[slot] = Val::Num(var);
-            self.stack[slot + 3] = Val::Num(var);
-            frame.jump(offset);
+        if let Val::Int(step) = self.stack[slot + 2] {
+            let count = self.stack[slot + 1].as_int().unwrap() as u64;
+            if count > 0 {
+                let var = self.stack[slot].as_int().unwrap().wrapping_add(step);
+                self.stack[slot] = Val::Int(var);
+                self.stack[slot + 1] = Val::Int((count - 1) as i64);
+                self.stack[slot + 3] = Val::Int(var);
+                frame.jump(offset);
+            }
+        } else {
+            let mut var = self.stack[slot].as_num().unwrap();
+            let limit = self.stack[slot + 1].as_num().unwrap();
+            let step = self.stack[slot + 2].as_num().unwrap();
+            var += step;
+            if check_numeric_for_condition(var, limit, step) {
+                self.stack[slot] = Val::Num(var);
+                self.stack[slot + 3] = Val::Num(var);
+                frame.jump(offset);
+            }
         }
         Ok(())
     }
 
-    fn instr_get_field(&mut self, frame: &mut Frame, field_id: u8) -> Result<()> {
-        let mut tbl_val = self.pop_val();
-        if let Some(t) = tbl_val.as_table() {
-            let key = self.get_string_constant(frame, field_id);
-            let val = t.get(&key);
+    /// Calls the iterator of a generic `for` loop with the state and the
+    /// control variable. `finish_call` moves the results into the loop's
+    /// variables.
+    fn instr_generic_for_call(
+        &mut self,
+        frame: &mut Frame,
+        local_slot: u8,
+    ) -> Result<Option<FrameExit>> {
+        let slot = local_slot as usize + self.stack_bottom;
+        for i in slot..slot + 3 {
+            let val = self.stack[i].clone();
             self.stack.push(val);
-            Ok(())
-        } else {
-            Err(self.type_error(TypeError::TableIndex(tbl_val.typ())))
         }
+        self.instr_call(frame, 2)
+    }
+
+    fn instr_generic_for_loop(&mut self, frame: &mut Frame, local_slot: u8, offset: isize) {
+        let slot = local_slot as usize + self.stack_bottom;
+        let first_var = self.stack[slot + 3].clone();
+        if !matches!(first_var, Val::Nil) {
+            self.stack[slot + 2] = first_var;
+            frame.jump(offset);
+        }
+    }
+
+    fn instr_get_field(&mut self, frame: &mut Frame, field_id: u8) -> Result<()> {
+        let tbl = self.pop_val();
+        let key = self.get_string_constant(frame, field_id);
+        let val = self.get_table_helper(tbl, key)?;
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn instr_get_method(&mut self, frame: &mut Frame, field_id: u8) -> Result<()> {
+        let receiver = self.stack.last().unwrap().clone();
+        self.stack.push(receiver);
+        self.instr_get_field(frame, field_id)?;
+        let len = self.stack.len();
+        self.stack.swap(len - 2, len - 1);
+        Ok(())
     }
 
     fn instr_get_global(&mut self, frame: &Frame, string_num: u8) {
         let s = &frame.chunk.string_literals[string_num as usize];
-        self.get_global(s);
+        let val = self.globals.get(s).cloned().unwrap_or_default();
+        self.stack.push(val);
     }
 
     fn instr_get_local(&mut self, local_num: u8) {
@@ -223,15 +487,21 @@ impl State {
         self.stack.push(val);
     }
 
+    fn instr_get_upvalue(&mut self, frame: &Frame, upvalue_num: u8) {
+        let val = match &*frame.upvalues[upvalue_num as usize].borrow() {
+            Upvalue::Open(thread, i) if *thread == self.current_thread => self.stack[*i].clone(),
+            Upvalue::Open(mut thread, i) => thread.as_thread().unwrap().stack[*i].clone(),
+            Upvalue::Closed(val) => val.clone(),
+        };
+        self.stack.push(val);
+    }
+
     fn instr_get_table(&mut self) -> Result<()> {
         let key = self.pop_val();
-        let mut tbl = self.pop_val();
-        if let Some(t) = tbl.as_table() {
-            self.stack.push(t.get(&key));
-            Ok(())
-        } else {
-            Err(self.type_error(TypeError::TableIndex(tbl.typ())))
-        }
+        let tbl = self.pop_val();
+        let val = self.get_table_helper(tbl, key)?;
+        self.stack.push(val);
+        Ok(())
     }
 
     fn instr_init_field(&mut self, frame: &Frame, negative_offset: u8, key_id: u8) -> Result<()> {
@@ -266,25 +536,20 @@ impl State {
         }
     }
 
-    fn instr_length(&mut self) -> Result<()> {
-        let val = self.pop_val();
-        match val.typ() {
-            LuaType::String => {
-                let s = val.as_string().unwrap();
-                let len = s.len();
-                self.stack.push(Val::Num(len as f64));
-                Ok(())
-            }
-            LuaType::Table => {
-                panic!("Unsupported: Length of tables");
-            }
-            typ => Err(self.type_error(TypeError::Length(typ))),
-        }
-    }
-
     fn instr_negate(&mut self) -> Result<()> {
-        let n = self.pop_num()?;
-        self.stack.push(Val::Num(-n));
+        let val = match self.stack.last().unwrap().coerce_to_number() {
+            Some(Val::Int(i)) => Val::Int(i.wrapping_neg()),
+            Some(Val::Num(n)) => Val::Num(-n),
+            _ => {
+                if self.call_unary_metamethod(MetaMethod::Unm)? {
+                    return Ok(());
+                }
+                let typ = self.stack.last().unwrap().typ();
+                return Err(self.type_error(TypeError::Arithmetic(typ)));
+            }
+        };
+        self.pop_val();
+        self.stack.push(val);
         Ok(())
     }
 
@@ -296,14 +561,9 @@ impl State {
     fn instr_set_field(&mut self, frame: &Frame, stack_offset: u8, field_id: u8) -> Result<()> {
         let val = self.pop_val();
         let idx = self.stack.len() - stack_offset as usize - 1;
-        let mut tbl = self.stack.remove(idx);
-        if let Some(t) = tbl.as_table() {
-            let key = self.get_string_constant(frame, field_id);
-            t.insert(key, val)?;
-            Ok(())
-        } else {
-            Err(self.type_error(TypeError::TableIndex(tbl.typ())))
-        }
+        let tbl = self.stack.remove(idx);
+        let key = self.get_string_constant(frame, field_id);
+        self.set_table_helper(tbl, key, val)
     }
 
     fn instr_set_global(&mut self, frame: &Frame, string_num: u8) {
@@ -317,16 +577,11 @@ impl State {
         }
     }
 
-    fn instr_set_list(&mut self, count: u8) -> Result<()> {
-        assert!(count > 0, "Shouldn't use SetList with count 0");
-        let values = self.stack.split_off(self.stack.len() - count as usize);
+    fn instr_set_list(&mut self, count: usize) -> Result<()> {
+        let values = self.stack.split_off(self.stack.len() - count);
         let mut tbl_value = self.pop_val();
         if let Some(tbl) = tbl_value.as_table() {
-            let counter = 1..;
-            for (i, val) in counter.zip(values) {
-                let key = Val::Num(i as f64);
-                tbl.insert(key, val)?;
-            }
+            tbl.set_list(values);
             self.stack.push(tbl_value);
             Ok(())
         } else {
@@ -340,45 +595,303 @@ impl State {
         self.stack[i] = val;
     }
 
+    fn instr_vararg(&mut self, frame: &mut Frame, num_wanted: u8) {
+        let start = self.stack_bottom - frame.num_varargs;
+        let num_pushed = if num_wanted == MULTRET {
+            frame.multi_len = frame.num_varargs;
+            frame.num_varargs
+        } else {
+            num_wanted as usize
+        };
+        for i in 0..num_pushed {
+            let val = if i < frame.num_varargs {
+                self.stack[start + i].clone()
+            } else {
+                Val::Nil
+            };
+            self.stack.push(val);
+        }
+    }
+
+    fn instr_set_upvalue(&mut self, frame: &Frame, upvalue_num: u8) {
+        let val = self.pop_val();
+        match &mut *frame.upvalues[upvalue_num as usize].borrow_mut() {
+            Upvalue::Open(thread, i) if *thread == self.current_thread => self.stack[*i] = val,
+            Upvalue::Open(thread, i) => thread.as_thread().unwrap().stack[*i] = val,
+            Upvalue::Closed(old_val) => *old_val = val,
+        }
+    }
+
+    /// Marks a local as a to-be-closed variable, unless its value is `nil`
+    /// or `false`.
+    fn instr_to_be_closed(&mut self, frame: &Frame, local_slot: u8, name_id: u8) -> Result<()> {
+        let index = local_slot as usize + self.stack_bottom;
+        let val = &self.stack[index];
+        if !val.truthy() {
+            return Ok(());
+        }
+        if let Val::Nil = self.get_metamethod(val, MetaMethod::Close) {
+            let name = &frame.chunk.string_literals[name_id as usize];
+            let msg = format!(
+                "variable '{}' got a non-closable value",
+                String::from_utf8_lossy(name)
+            );
+            return Err(self.error(ErrorKind::WithMessage(msg)));
+        }
+        self.tbc_slots.push(index);
+        Ok(())
+    }
+
     fn instr_set_table(&mut self, offset: u8) -> Result<()> {
         let val = self.pop_val();
         let index = self.stack.len() - offset as usize - 2;
-        let mut tbl = self.stack.remove(index);
+        let tbl = self.stack.remove(index);
         let key = self.stack.remove(index);
-        if let Some(t) = tbl.as_table() {
-            t.insert(key, val)?;
-            Ok(())
-        } else {
-            Err(self.type_error(TypeError::TableIndex(tbl.typ())))
-        }
+        self.set_table_helper(tbl, key, val)
     }
 
     // Helper methods
 
-    fn eval_float_bool(&mut self, f: impl Fn(&f64, &f64) -> bool) -> Result<()> {
-        let n2 = self.pop_num()?;
-        let n1 = self.pop_num()?;
-        self.stack.push(Val::Bool(f(&n1, &n2)));
+    /// Performs an arithmetic operation on the two values at the top of the
+    /// stack. The result keeps the integer subtype if both operands are
+    /// integers; `int_op` returns `None` for an integer division by zero.
+    /// If either operand isn't a number, the operation's metamethod is used.
+    fn eval_arith(
+        &mut self,
+        event: MetaMethod,
+        int_op: impl Fn(i64, i64) -> Option<i64>,
+        float_op: impl Fn(f64, f64) -> f64,
+    ) -> Result<()> {
+        let len = self.stack.len();
+        let a = self.stack[len - 2].coerce_to_number();
+        let b = self.stack[len - 1].coerce_to_number();
+        let result = match (a, b) {
+            (Some(Val::Int(a)), Some(Val::Int(b))) => match int_op(a, b) {
+                Some(n) => Val::Int(n),
+                None => {
+                    let op = if event == MetaMethod::Mod { "%%" } else { "//" };
+                    let msg = format!("attempt to perform 'n{}0'", op);
+                    return Err(self.error(ErrorKind::WithMessage(msg)));
+                }
+            },
+            (Some(a), Some(b)) => Val::Num(float_op(a.as_num().unwrap(), b.as_num().unwrap())),
+            _ => return self.arith_metamethod(event),
+        };
+        self.stack.truncate(len - 2);
+        self.stack.push(result);
+        Ok(())
+    }
+
+    /// Calls the metamethod for an arithmetic operation, or raises an error
+    /// if neither operand has one.
+    fn arith_metamethod(&mut self, event: MetaMethod) -> Result<()> {
+        if self.call_binary_metamethod(event)? {
+            return Ok(());
+        }
+        let len = self.stack.len();
+        // Blame the first operand which isn't a number.
+        let culprit = match self.stack[len - 2].coerce_to_number() {
+            Some(_) => &self.stack[len - 1],
+            None => &self.stack[len - 2],
+        };
+        Err(self.type_error(TypeError::Arithmetic(culprit.typ())))
+    }
+
+    /// Performs a bitwise operation, after converting both operands to
+    /// integers.
+    fn eval_bitwise(&mut self, op: impl Fn(i64, i64) -> i64) -> Result<()> {
+        let val2 = self.pop_val();
+        let val1 = self.pop_val();
+        let a = self.bitwise_operand(&val1)?;
+        let b = self.bitwise_operand(&val2)?;
+        self.stack.push(Val::Int(op(a, b)));
         Ok(())
     }
 
-    fn eval_float_float(&mut self, f: impl Fn(f64, f64) -> f64) -> Result<()> {
-        let n2 = self.pop_num()?;
-        let n1 = self.pop_num()?;
-        self.stack.push(Val::Num(f(n1, n2)));
+    /// Converts an operand of a bitwise operation to an integer. Strings are
+    /// converted to numbers first, and floats must have an exact integer
+    /// representation.
+    fn bitwise_operand(&self, val: &Val) -> Result<i64> {
+        match val.coerce_to_number() {
+            Some(Val::Int(i)) => Ok(i),
+            Some(Val::Num(f)) => {
+                float_to_int(f).ok_or_else(|| self.type_error(TypeError::NoIntegerRep))
+            }
+            _ => Err(self.type_error(TypeError::Bitwise(val.typ()))),
+        }
+    }
+
+    /// Evaluates `<` (if `event` is `Lt`) or `<=` (if `event` is `Le`) on
+    /// the two values at the top of the stack, swapping them first if
+    /// `swap` is true. Numbers and strings are compared directly; other
+    /// values use the `__lt` or `__le` metamethods.
+    fn eval_comparison(&mut self, event: MetaMethod, swap: bool) -> Result<()> {
+        let mut b = self.pop_val();
+        let mut a = self.pop_val();
+        if swap {
+            std::mem::swap(&mut a, &mut b);
+        }
+        let test = match event {
+            MetaMethod::Lt => Ordering::is_lt,
+            _ => Ordering::is_le,
+        };
+        let ordering = match (a.as_string(), b.as_string()) {
+            (Some(s1), Some(s2)) => Some(s1.cmp(s2)),
+            _ if a.typ() == LuaType::Number && b.typ() == LuaType::Number => cmp_numbers(&a, &b),
+            _ => return self.comparison_metamethod(event, a, b),
+        };
+        // NaN is unordered, so every comparison with it is false.
+        self.stack.push(Val::Bool(ordering.is_some_and(test)));
         Ok(())
     }
 
+    /// Compares two values which aren't both numbers or both strings, using
+    /// a metamethod. If there is no `__le` metamethod, `a <= b` is evaluated
+    /// as `not (b < a)`.
+    fn comparison_metamethod(&mut self, event: MetaMethod, a: Val, b: Val) -> Result<()> {
+        let (typ1, typ2) = (a.typ(), b.typ());
+        self.stack.extend([a.clone(), b.clone()]);
+        if self.call_binary_metamethod(event)? {
+            let result = self.pop_val().truthy();
+            self.stack.push(Val::Bool(result));
+            return Ok(());
+        }
+        self.stack.truncate(self.stack.len() - 2);
+        if event == MetaMethod::Le {
+            self.stack.extend([b, a]);
+            if self.call_binary_metamethod(MetaMethod::Lt)? {
+                let result = self.pop_val().truthy();
+                self.stack.push(Val::Bool(!result));
+                return Ok(());
+            }
+            self.stack.truncate(self.stack.len() - 2);
+        }
+        Err(self.type_error(TypeError::Comparison(typ1, typ2)))
+    }
+
+    /// Evaluates `==` on the two values at the top of the stack, or `~=` if
+    /// `negate` is true. Two different tables may be equal according to the
+    /// `__eq` metamethod.
+    fn eval_equal(&mut self, negate: bool) -> Result<()> {
+        let b = self.pop_val();
+        let a = self.pop_val();
+        let mut result = a == b;
+        if !result && a.typ() == LuaType::Table && b.typ() == LuaType::Table {
+            self.stack.extend([a, b]);
+            if self.call_binary_metamethod(MetaMethod::Eq)? {
+                result = self.pop_val().truthy();
+            } else {
+                self.stack.truncate(self.stack.len() - 2);
+            }
+        }
+        self.stack.push(Val::Bool(result != negate));
+        Ok(())
+    }
+
+    /// Like `eval_arith`, but the operation always converts its operands to
+    /// floats.
+    fn eval_float_float(&mut self, event: MetaMethod, f: impl Fn(f64, f64) -> f64) -> Result<()> {
+        let len = self.stack.len();
+        let a = self.stack[len - 2].coerce_to_number();
+        let b = self.stack[len - 1].coerce_to_number();
+        match (a, b) {
+            (Some(a), Some(b)) => {
+                self.stack.truncate(len - 2);
+                let result = f(a.as_num().unwrap(), b.as_num().unwrap());
+                self.stack.push(Val::Num(result));
+                Ok(())
+            }
+            _ => self.arith_metamethod(event),
+        }
+    }
+
+    fn for_error(&self, msg: &str) -> Error {
+        self.error(ErrorKind::WithMessage(msg.into()))
+    }
+
+    /// Converts one of the control values of a float `for` loop.
+    fn for_float(&self, val: &Val, name: &str) -> Result<f64> {
+        val.as_num()
+            .ok_or_else(|| self.for_error(&format!("'for' {} value must be a number", name)))
+    }
+
+    /// Converts the limit of an integer `for` loop to an integer. Returns
+    /// `None` if the loop shouldn't run at all.
+    fn for_limit(&self, limit: &Val, step: i64) -> Result<Option<i64>> {
+        match limit {
+            Val::Int(i) => Ok(Some(*i)),
+            Val::Num(f) if f.is_nan() => Ok(None),
+            Val::Num(f) => {
+                let f = if step > 0 { f.floor() } else { f.ceil() };
+                match float_to_int(f) {
+                    Some(i) => Ok(Some(i)),
+                    // The limit is beyond every integer, in the direction of
+                    // the step.
+                    None if (f > 0.0) == (step > 0) => Ok(Some(f as i64)),
+                    None => Ok(None),
+                }
+            }
+            _ => Err(self.for_error("'for' limit must be a number")),
+        }
+    }
+
     fn get_string_constant(&self, frame: &Frame, i: u8) -> Val {
         // self.string_literals[i as usize].clone()
         let index = frame.string_literal_start + i as usize;
         self.string_literals[index].clone()
     }
+}
 
-    fn pop_num(&mut self) -> Result<f64> {
-        let val = self.pop_val();
-        val.as_num()
-            .ok_or_else(|| self.type_error(TypeError::Arithmetic(val.typ())))
+/// Logical shift left. A negative shift moves right instead, and shifting by
+/// 64 or more bits in either direction results in 0.
+fn shift_left(a: i64, b: i64) -> i64 {
+    if b <= -64 || b >= 64 {
+        0
+    } else if b >= 0 {
+        ((a as u64) << b) as i64
+    } else {
+        ((a as u64) >> -b) as i64
+    }
+}
+
+/// Integer floor division. Returns `None` if the divisor is 0.
+fn int_floor_div(a: i64, b: i64) -> Option<i64> {
+    if b == 0 {
+        return None;
+    }
+    let quotient = a.wrapping_div(b);
+    if a.wrapping_rem(b) != 0 && ((a < 0) != (b < 0)) {
+        Some(quotient - 1)
+    } else {
+        Some(quotient)
+    }
+}
+
+/// Integer modulo, which takes the sign of the divisor. Returns `None` if
+/// the divisor is 0.
+fn int_mod(a: i64, b: i64) -> Option<i64> {
+    if b == 0 {
+        return None;
+    }
+    let remainder = a.wrapping_rem(b);
+    if remainder != 0 && ((remainder < 0) != (b < 0)) {
+        Some(remainder + b)
+    } else {
+        Some(remainder)
+    }
+}
+
+fn float_floor_div(a: f64, b: f64) -> f64 {
+    (a / b).floor()
+}
+
+/// Float modulo, which takes the sign of the divisor.
+fn float_mod(a: f64, b: f64) -> f64 {
+    let remainder = a % b;
+    if remainder != 0.0 && ((remainder < 0.0) != (b < 0.0)) {
+        remainder + b
+    } else {
+        remainder
     }
 }
 
@@ -1,27 +1,31 @@
-use super::object::ObjectPtr;
-use super::Chunk;
+use super::object::{LuaClosure, ObjectPtr};
 use super::Markable;
 use super::Result;
 use super::State;
 use super::Table;
 
+use std::cmp::Ordering;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 
 pub type RustFunc = fn(&mut State) -> Result<u8>;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub(super) enum Val {
+    #[default]
     Nil,
     Bool(bool),
+    /// A number with the float subtype.
     Num(f64),
+    /// A number with the integer subtype.
+    Int(i64),
     RustFn(RustFunc),
     Obj(ObjectPtr),
 }
 use Val::*;
 
 impl Val {
-    pub(super) fn as_lua_function(&self) -> Option<Chunk> {
+    pub(super) fn as_lua_function(&self) -> Option<LuaClosure> {
         if let Obj(o) = self {
             o.as_lua_function()
         } else {
@@ -29,14 +33,44 @@ impl Val {
         }
     }
 
+    /// Returns the value as an integer, if it is an integer or a float with
+    /// an exact integer representation.
+    pub(super) fn as_int(&self) -> Option<i64> {
+        match self {
+            Int(i) => Some(*i),
+            Num(f) => float_to_int(*f),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as a float, converting integers.
     pub(super) fn as_num(&self) -> Option<f64> {
         match self {
             Num(f) => Some(*f),
+            Int(i) => Some(*i as f64),
             _ => None,
         }
     }
 
-    pub(super) fn as_string(&self) -> Option<&str> {
+    /// Returns the value as a number, converting strings which contain a
+    /// numeral, as arithmetic does. Returns `None` for any other value.
+    pub(super) fn coerce_to_number(&self) -> Option<Val> {
+        match self {
+            Int(_) | Num(_) => Some(self.clone()),
+            _ => self.as_string().and_then(bytes_to_number),
+        }
+    }
+
+    /// Converts the value to a string, like `to_string`, but keeps the exact
+    /// bytes of a Lua string.
+    pub(super) fn to_bytes(&self) -> Vec<u8> {
+        match self.as_string() {
+            Some(s) => s.to_vec(),
+            None => self.to_string().into_bytes(),
+        }
+    }
+
+    pub(super) fn as_string(&self) -> Option<&[u8]> {
         if let Obj(o) = self {
             o.as_string()
         } else {
@@ -61,7 +95,7 @@ impl Val {
         match self {
             Nil => LuaType::Nil,
             Bool(_) => LuaType::Boolean,
-            Num(_) => LuaType::Number,
+            Num(_) | Int(_) => LuaType::Number,
             RustFn(_) => LuaType::Function,
             Obj(o) => o.typ(),
         }
@@ -74,24 +108,20 @@ impl fmt::Debug for Val {
             Nil => write!(f, "nil"),
             Bool(b) => b.fmt(f),
             Num(n) => n.fmt(f),
+            Int(i) => i.fmt(f),
             RustFn(func) => write!(f, "<function: {:p}>", func),
             Obj(o) => o.fmt(f),
         }
     }
 }
 
-impl Default for Val {
-    fn default() -> Self {
-        Nil
-    }
-}
-
 impl fmt::Display for Val {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Nil => write!(f, "nil"),
             Bool(b) => b.fmt(f),
-            Num(n) => n.fmt(f),
+            Num(n) => fmt_float(*n, f),
+            Int(i) => i.fmt(f),
             Obj(o) => o.fmt(f),
             _ => write!(f, "{:#?}", self),
         }
@@ -106,18 +136,17 @@ impl Hash for Val {
         match self {
             Nil => (),
             Bool(b) => b.hash(hasher),
-            Obj(o) => match o.as_string() {
-                Some(s) => s.hash(hasher),
-                None => o.hash(hasher),
-            },
+            Obj(o) => o.lua_hash(hasher),
+            // Floats with an integer value must hash like the integer,
+            // since they are equal.
             Num(n) => {
                 debug_assert!(!n.is_nan(), "Can't hash NaN");
-                let mut bits = n.to_bits();
-                if bits == 1 << 63 {
-                    bits = 0;
+                match float_to_int(*n) {
+                    Some(i) => i.hash(hasher),
+                    None => n.to_bits().hash(hasher),
                 }
-                bits.hash(hasher);
             }
+            Int(i) => i.hash(hasher),
             RustFn(func) => {
                 let f: *const RustFunc = func;
                 f.hash(hasher);
@@ -132,6 +161,8 @@ impl PartialEq for Val {
             (Nil, Nil) => true,
             (Bool(a), Bool(b)) => a == b,
             (Num(a), Num(b)) => a == b,
+            (Int(a), Int(b)) => a == b,
+            (Int(a), Num(b)) | (Num(b), Int(a)) => cmp_int_float(*a, *b) == Some(Ordering::Equal),
             (RustFn(a), RustFn(b)) => {
                 let x: *const RustFunc = a;
                 let y: *const RustFunc = b;
@@ -144,10 +175,132 @@ impl PartialEq for Val {
 }
 
 impl Markable for Val {
-    fn mark_reachable(&self) {
+    fn mark_reachable(&self, weak_tables: &mut Vec<ObjectPtr>) {
         if let Obj(o) = self {
-            o.mark_reachable();
+            o.mark_reachable(weak_tables);
+        }
+    }
+}
+
+/// Compares two numbers, without losing precision when one is an integer
+/// and the other is a float. Returns `None` if either number is NaN, or if a
+/// value isn't a number.
+pub(super) fn cmp_numbers(a: &Val, b: &Val) -> Option<Ordering> {
+    match (a, b) {
+        (Int(a), Int(b)) => Some(a.cmp(b)),
+        (Num(a), Num(b)) => a.partial_cmp(b),
+        (Int(a), Num(b)) => cmp_int_float(*a, *b),
+        (Num(a), Int(b)) => cmp_int_float(*b, *a).map(Ordering::reverse),
+        _ => None,
+    }
+}
+
+/// Converts a string to a number, following the syntax of Lua's numerals.
+/// Surrounding whitespace and a sign are allowed.
+fn bytes_to_number(s: &[u8]) -> Option<Val> {
+    str_to_number(std::str::from_utf8(s).ok()?)
+}
+
+fn str_to_number(s: &str) -> Option<Val> {
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (is_negative, unsigned) = match s.as_bytes().first()? {
+        b'-' => (true, &s[1..]),
+        b'+' => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let hex_digits = unsigned
+        .strip_prefix("0x")
+        .or_else(|| unsigned.strip_prefix("0X"));
+    if let Some(digits) = hex_digits {
+        // Hexadecimal integers wrap around.
+        if digits.is_empty() {
+            return None;
+        }
+        let mut n = 0i64;
+        for c in digits.chars() {
+            n = n.wrapping_mul(16).wrapping_add(c.to_digit(16)? as i64);
+        }
+        let n = if is_negative { n.wrapping_neg() } else { n };
+        return Some(Int(n));
+    }
+    if unsigned.bytes().all(|b| b.is_ascii_digit()) {
+        // Decimal integers which don't fit become floats.
+        if let Ok(n) = s.parse::<i64>() {
+            return Some(Int(n));
+        }
+    }
+    // Rust accepts "inf" and "nan", but Lua doesn't.
+    if !unsigned.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        || unsigned.contains(['n', 'N'])
+    {
+        return None;
+    }
+    s.parse::<f64>().ok().map(Num)
+}
+
+/// 2^63, the first float above every integer. It is exactly representable.
+const INT_LIMIT: f64 = 9_223_372_036_854_775_808.0;
+
+fn cmp_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= INT_LIMIT {
+        Some(Ordering::Less)
+    } else if f < -INT_LIMIT {
+        Some(Ordering::Greater)
+    } else {
+        let floor = f.floor();
+        match i.cmp(&(floor as i64)) {
+            // `f` is a bit more than `i`.
+            Ordering::Equal if floor != f => Some(Ordering::Less),
+            ordering => Some(ordering),
+        }
+    }
+}
+
+/// Converts a float to an integer, if it has an exact integer representation.
+pub(super) fn float_to_int(f: f64) -> Option<i64> {
+    if f.floor() == f && (-INT_LIMIT..INT_LIMIT).contains(&f) {
+        Some(f as i64)
+    } else {
+        None
+    }
+}
+
+/// Writes a float the way Lua does (using C's `"%.14g"`), adding `.0` if it
+/// would otherwise look like an integer.
+fn fmt_float(n: f64, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fn trim_zeros(s: &str) -> &str {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.')
+        } else {
+            s
+        }
+    }
+
+    if n.is_nan() {
+        let sign = if n.is_sign_negative() { "-" } else { "" };
+        return write!(f, "{}nan", sign);
+    } else if n.is_infinite() {
+        let sign = if n.is_sign_negative() { "-" } else { "" };
+        return write!(f, "{}inf", sign);
+    }
+    // The exponent after rounding to 14 significant digits decides between
+    // fixed and scientific notation.
+    let scientific = format!("{:.13e}", n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if (-4..14).contains(&exponent) {
+        let fixed = format!("{:.*}", (13 - exponent) as usize, n);
+        let fixed = trim_zeros(&fixed);
+        if fixed.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+            write!(f, "{}.0", fixed)
+        } else {
+            write!(f, "{}", fixed)
         }
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        write!(f, "{}e{}{:02}", trim_zeros(mantissa), sign, exponent.abs())
     }
 }
 
@@ -159,6 +312,7 @@ pub enum LuaType {
     String,
     Table,
     Function,
+    Thread,
 }
 
 impl LuaType {
@@ -171,6 +325,7 @@ impl LuaType {
             String => "string",
             Table => "table",
             Function => "function",
+            Thread => "thread",
         }
     }
 }
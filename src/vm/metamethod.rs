@@ -0,0 +1,79 @@
+/// The fields of a metatable which the VM looks up. Each variant's value is
+/// the index of its name in `State::metamethod_names`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum MetaMethod {
+    Index,
+    NewIndex,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Unm,
+    Idiv,
+    Concat,
+    Len,
+    Eq,
+    Lt,
+    Le,
+    Call,
+    Gc,
+    Mode,
+    Close,
+    /// Not really a metamethod, but `getmetatable` and `setmetatable` look
+    /// for it.
+    Metatable,
+}
+
+impl MetaMethod {
+    /// Every variant, in order.
+    pub(super) const ALL: [Self; 20] = [
+        Self::Index,
+        Self::NewIndex,
+        Self::Add,
+        Self::Sub,
+        Self::Mul,
+        Self::Div,
+        Self::Mod,
+        Self::Pow,
+        Self::Unm,
+        Self::Idiv,
+        Self::Concat,
+        Self::Len,
+        Self::Eq,
+        Self::Lt,
+        Self::Le,
+        Self::Call,
+        Self::Gc,
+        Self::Mode,
+        Self::Close,
+        Self::Metatable,
+    ];
+
+    /// The key of this field in a metatable.
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            Self::Index => "__index",
+            Self::NewIndex => "__newindex",
+            Self::Add => "__add",
+            Self::Sub => "__sub",
+            Self::Mul => "__mul",
+            Self::Div => "__div",
+            Self::Mod => "__mod",
+            Self::Pow => "__pow",
+            Self::Unm => "__unm",
+            Self::Idiv => "__idiv",
+            Self::Concat => "__concat",
+            Self::Len => "__len",
+            Self::Eq => "__eq",
+            Self::Lt => "__lt",
+            Self::Le => "__le",
+            Self::Call => "__call",
+            Self::Gc => "__gc",
+            Self::Mode => "__mode",
+            Self::Close => "__close",
+            Self::Metatable => "__metatable",
+        }
+    }
+}
@@ -4,15 +4,24 @@
 //!
 //! Because of this, it needs to be garbage collected.
 
-use std::cell::Cell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::ops::Drop;
 use std::ptr::{self, NonNull};
+use std::rc::Rc;
 
+use super::frame::Frame;
 use super::Chunk;
 use super::LuaType;
+use super::RustFunc;
 use super::Table;
+use super::Val;
+
+/// Strings up to this length are interned, so that each one is only stored
+/// once. This is the same limit that the reference implementation uses.
+const MAX_SHORT_STRING_LEN: usize = 40;
 
 /// A wrapper around the `LuaVal`s which need to be garbage-collected.
 struct WrappedObject {
@@ -23,22 +32,97 @@ struct WrappedObject {
     /// A flag used in garbage-collection. This is behind a `Cell` so that
     /// we can alter the keys of a table.
     color: Cell<Color>,
+    /// Whether this object is in the heap's list of objects to finalize.
+    finalizable: Cell<bool>,
 }
 
 enum RawObject {
-    // Wrap this in a box to reduce the memory usage. Minimal performance impact
-    // because functions are rarely accessed.
-    LuaFn(Box<Chunk>),
-    Str(String),
+    LuaFn(LuaClosure),
+    RustClosure(RustClosure),
+    Str(Vec<u8>),
     Table(Table),
+    Thread(Thread),
+}
+
+/// A Lua function: a compiled `Chunk`, along with the upvalues it captured
+/// when it was created.
+#[derive(Clone)]
+pub(super) struct LuaClosure {
+    pub(super) chunk: Rc<Chunk>,
+    pub(super) upvalues: Vec<UpvalueRef>,
+}
+
+/// A Rust function, along with the values it can reach through
+/// `State::push_upvalue`.
+pub(super) struct RustClosure {
+    pub(super) func: RustFunc,
+    pub(super) upvalues: Vec<Val>,
+}
+
+/// A coroutine, with its own stack of values and frames. While a thread is
+/// running, its stack and frames are moved into the `State`, so these
+/// fields only hold them while the thread isn't running.
+#[derive(Default)]
+pub(super) struct Thread {
+    pub(super) status: ThreadStatus,
+    pub(super) stack: Vec<Val>,
+    pub(super) stack_bottom: usize,
+    pub(super) frames: Vec<Frame>,
+    pub(super) string_literals: Vec<Val>,
+    pub(super) open_upvalues: Vec<UpvalueRef>,
+    pub(super) tbc_slots: Vec<usize>,
+    /// The number of calls in progress which the thread can't yield
+    /// across, such as calls from Rust.
+    pub(super) non_yieldable_calls: usize,
+    /// The error which killed the thread, if any.
+    pub(super) error: Option<String>,
+}
+
+/// The status of a thread, as reported by `coroutine.status`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ThreadStatus {
+    /// The thread hasn't started, or it has yielded.
+    #[default]
+    Suspended,
+    /// The thread is the one running.
+    Running,
+    /// The thread has resumed another thread, and is waiting for it.
+    Normal,
+    /// The thread has returned or raised an error.
+    Dead,
+}
+
+impl ThreadStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Suspended => "suspended",
+            Self::Running => "running",
+            Self::Normal => "normal",
+            Self::Dead => "dead",
+        }
+    }
+}
+
+/// Upvalues are shared between every closure which captured the same local.
+pub(super) type UpvalueRef = Rc<RefCell<Upvalue>>;
+
+/// A local variable which has been captured by a closure.
+#[derive(Debug)]
+pub(super) enum Upvalue {
+    /// The local is still in scope, at the given index of the given
+    /// thread's stack.
+    Open(ObjectPtr, usize),
+    /// The local has gone out of scope, so the upvalue holds the value itself.
+    Closed(Val),
 }
 
 impl RawObject {
     pub(super) fn typ(&self) -> LuaType {
         match self {
-            RawObject::LuaFn(_) => LuaType::Function,
+            RawObject::LuaFn(_) | RawObject::RustClosure(_) => LuaType::Function,
             RawObject::Str(_) => LuaType::String,
             RawObject::Table(_) => LuaType::Table,
+            RawObject::Thread(_) => LuaType::Thread,
         }
     }
 }
@@ -50,14 +134,21 @@ pub(super) struct ObjectPtr {
 }
 
 impl ObjectPtr {
-    pub(super) fn as_lua_function(self) -> Option<Chunk> {
+    pub(super) fn as_lua_function(self) -> Option<LuaClosure> {
+        match &self.deref().raw {
+            RawObject::LuaFn(closure) => Some(closure.clone()),
+            _ => None,
+        }
+    }
+
+    pub(super) fn as_rust_closure(&self) -> Option<&RustClosure> {
         match &self.deref().raw {
-            RawObject::LuaFn(chunk) => Some((**chunk).clone()),
+            RawObject::RustClosure(closure) => Some(closure),
             _ => None,
         }
     }
 
-    pub(super) fn as_string(&self) -> Option<&str> {
+    pub(super) fn as_string(&self) -> Option<&[u8]> {
         match &self.deref().raw {
             RawObject::Str(s) => Some(s),
             _ => None,
@@ -71,12 +162,32 @@ impl ObjectPtr {
         }
     }
 
+    pub(super) fn as_thread(&mut self) -> Option<&mut Thread> {
+        match &mut self.deref_mut().raw {
+            RawObject::Thread(t) => Some(t),
+            _ => None,
+        }
+    }
+
     /// Returns whether the contained values are equal, according to Lua's
-    /// `==` operator.
+    /// `==` operator. Short strings are interned, so only long strings need
+    /// to be compared by their contents.
     pub(super) fn lua_eq(self, other: Self) -> bool {
+        if self == other {
+            return true;
+        }
         match (self.as_string(), other.as_string()) {
-            (Some(s1), Some(s2)) => s1 == s2,
-            _ => self == other,
+            (Some(s1), Some(s2)) => s1.len() > MAX_SHORT_STRING_LEN && s1 == s2,
+            _ => false,
+        }
+    }
+
+    /// Hashes the object consistently with `lua_eq`. Only long strings are
+    /// hashed by their contents; everything else is hashed by its address.
+    pub(super) fn lua_hash<H: Hasher>(self, hasher: &mut H) {
+        match self.as_string() {
+            Some(s) if s.len() > MAX_SHORT_STRING_LEN => s.hash(hasher),
+            _ => self.hash(hasher),
         }
     }
 
@@ -84,6 +195,12 @@ impl ObjectPtr {
         self.deref().raw.typ()
     }
 
+    /// Returns whether the GC has marked this object as reachable in the
+    /// current collection.
+    pub(super) fn is_marked(self) -> bool {
+        matches!(self.deref().color.get(), Color::Reachable)
+    }
+
     fn deref(&self) -> &WrappedObject {
         unsafe { self.ptr.as_ref() }
     }
@@ -96,9 +213,12 @@ impl ObjectPtr {
 impl fmt::Display for ObjectPtr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.deref().raw {
-            RawObject::LuaFn(_) => write!(f, "function: {:p}", self.ptr),
-            RawObject::Str(s) => s.fmt(f),
+            RawObject::LuaFn(_) | RawObject::RustClosure(_) => {
+                write!(f, "function: {:p}", self.ptr)
+            }
+            RawObject::Str(s) => String::from_utf8_lossy(s).fmt(f),
             RawObject::Table(_) => write!(f, "table: {:p}", self.ptr),
+            RawObject::Thread(_) => write!(f, "thread: {:p}", self.ptr),
         }
     }
 }
@@ -109,6 +229,30 @@ enum Color {
     Reachable,
 }
 
+/// An entry in the set of interned strings. It is hashed and compared by the
+/// contents of its string, so that the set can be searched with a `&[u8]`.
+struct InternedStr(ObjectPtr);
+
+impl std::borrow::Borrow<[u8]> for InternedStr {
+    fn borrow(&self) -> &[u8] {
+        self.0.as_string().unwrap()
+    }
+}
+
+impl Hash for InternedStr {
+    fn hash<H: Hasher>(&self, hasher: &mut H) {
+        self.0.as_string().unwrap().hash(hasher);
+    }
+}
+
+impl PartialEq for InternedStr {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_string() == other.0.as_string()
+    }
+}
+
+impl Eq for InternedStr {}
+
 /// A collection of objects which need to be garbage-collected.
 pub(super) struct GcHeap {
     /// The start of the linked list which contains every Object.
@@ -117,6 +261,17 @@ pub(super) struct GcHeap {
     size: usize,
     /// When the heap grows this large, run the GC.
     threshold: usize,
+    /// Objects whose `__gc` metamethod should be called once they become
+    /// unreachable, in the order they were marked.
+    finalizable: Vec<ObjectPtr>,
+    /// Unreachable objects whose finalizers have yet to be called. These are
+    /// kept alive until their finalizers have run.
+    to_finalize: VecDeque<ObjectPtr>,
+    /// The weak tables which have been marked during the current collection.
+    weak_tables: Vec<ObjectPtr>,
+    /// Every short string in the heap. The strings in this set are not
+    /// marked through it; each is removed when it gets collected.
+    strings: HashSet<InternedStr>,
 }
 
 impl GcHeap {
@@ -126,17 +281,32 @@ impl GcHeap {
             start: ptr::null_mut(),
             size: 0,
             threshold,
+            finalizable: Vec::new(),
+            to_finalize: VecDeque::new(),
+            weak_tables: Vec::new(),
+            strings: HashSet::new(),
         }
     }
 
-    /// Run the garbage-collector.
-    /// Make sure you mark all the roots before calling this function.
-    pub(super) fn collect(&mut self) {
+    /// Run the garbage-collector. `mark_roots` must mark every root, passing
+    /// on the list of weak tables it is given.
+    pub(super) fn collect(&mut self, mark_roots: impl FnOnce(&mut Vec<ObjectPtr>)) {
         if option_env!("LUA_DEBUG_GC").is_some() {
             println!("Running garbage collector");
             println!("Initial size: {}", self.size);
         }
 
+        self.weak_tables.clear();
+        mark_roots(&mut self.weak_tables);
+
+        // Weak values are cleared before objects are resurrected for their
+        // finalizers, but weak keys are cleared after.
+        self.converge_ephemerons();
+        self.clear_weak_tables(false);
+        self.separate_unreachable();
+        self.converge_ephemerons();
+        self.clear_weak_tables(true);
+
         let mut next_ptr_ref = &mut self.start;
         while !next_ptr_ref.is_null() {
             // From right-to-left, this unsafe block means:
@@ -152,41 +322,165 @@ impl GcHeap {
                 }
                 Color::Unmarked => {
                     let boxed = unsafe { Box::from_raw(*next_ptr_ref) };
+                    if let RawObject::Str(s) = &boxed.raw {
+                        if s.len() <= MAX_SHORT_STRING_LEN {
+                            self.strings.remove(s.as_slice());
+                        }
+                    }
                     *next_ptr_ref = boxed.next;
                     self.size -= 1;
                 }
             }
         }
+        self.weak_tables.clear();
         self.threshold = self.size * 2;
     }
 
+    /// Queues every object marked for finalization, whether or not it is
+    /// still reachable. This is used when the `State` is closed.
+    pub(super) fn finalize_all(&mut self) {
+        for obj in self.finalizable.drain(..).rev() {
+            obj.deref().finalizable.set(false);
+            self.to_finalize.push_back(obj);
+        }
+    }
+
+    pub(super) fn has_pending_finalizers(&self) -> bool {
+        !self.to_finalize.is_empty()
+    }
+
+    /// Marks `obj` so that its finalizer is called once it becomes
+    /// unreachable. Marking an object twice has no effect.
+    pub(super) fn mark_for_finalization(&mut self, obj: ObjectPtr) {
+        let flag = &obj.deref().finalizable;
+        if !flag.get() {
+            flag.set(true);
+            self.finalizable.push(obj);
+        }
+    }
+
+    /// Removes the next object whose finalizer should be called. Once it has
+    /// been removed, the object is only kept alive by the caller.
+    pub(super) fn pop_pending_finalizer(&mut self) -> Option<ObjectPtr> {
+        self.to_finalize.pop_front()
+    }
+
     pub(super) fn is_full(&self) -> bool {
         self.size >= self.threshold
     }
 
-    pub(super) fn new_lua_fn(&mut self, chunk: Chunk, mark: impl FnOnce()) -> ObjectPtr {
-        let raw = RawObject::LuaFn(Box::new(chunk));
+    pub(super) fn new_lua_fn(
+        &mut self,
+        closure: LuaClosure,
+        mark: impl FnOnce(&mut Vec<ObjectPtr>),
+    ) -> ObjectPtr {
+        let raw = RawObject::LuaFn(closure);
         self.new_obj_from_raw(raw, mark)
     }
 
-    pub(super) fn new_string(&mut self, s: String, mark: impl FnOnce()) -> ObjectPtr {
-        let raw = RawObject::Str(s);
+    pub(super) fn new_rust_closure(
+        &mut self,
+        closure: RustClosure,
+        mark: impl FnOnce(&mut Vec<ObjectPtr>),
+    ) -> ObjectPtr {
+        let raw = RawObject::RustClosure(closure);
         self.new_obj_from_raw(raw, mark)
     }
 
-    pub(super) fn new_table(&mut self, mark: impl FnOnce()) -> ObjectPtr {
+    /// Returns a string object with the contents `s`. If `s` is short and
+    /// such a string already exists, that object is returned instead of
+    /// allocating a new one.
+    pub(super) fn new_string(
+        &mut self,
+        s: impl AsRef<[u8]> + Into<Vec<u8>>,
+        mark: impl FnOnce(&mut Vec<ObjectPtr>),
+    ) -> ObjectPtr {
+        let is_short = s.as_ref().len() <= MAX_SHORT_STRING_LEN;
+        if is_short {
+            if let Some(interned) = self.strings.get(s.as_ref()) {
+                return interned.0;
+            }
+        }
+        let raw = RawObject::Str(s.into());
+        let obj = self.new_obj_from_raw(raw, mark);
+        if is_short {
+            self.strings.insert(InternedStr(obj));
+        }
+        obj
+    }
+
+    pub(super) fn new_table(&mut self, mark: impl FnOnce(&mut Vec<ObjectPtr>)) -> ObjectPtr {
         let raw = RawObject::Table(Table::default());
         self.new_obj_from_raw(raw, mark)
     }
 
-    fn new_obj_from_raw(&mut self, raw: RawObject, mark: impl FnOnce()) -> ObjectPtr {
+    pub(super) fn new_thread(
+        &mut self,
+        thread: Thread,
+        mark: impl FnOnce(&mut Vec<ObjectPtr>),
+    ) -> ObjectPtr {
+        let raw = RawObject::Thread(thread);
+        self.new_obj_from_raw(raw, mark)
+    }
+
+    /// Removes the unmarked entries from every reachable weak table.
+    fn clear_weak_tables(&mut self, clear_keys: bool) {
+        for obj in &mut self.weak_tables {
+            obj.as_table().unwrap().clear_unmarked(clear_keys);
+        }
+    }
+
+    /// Marks the values of ephemeron tables whose keys are reachable, until
+    /// nothing new gets marked.
+    fn converge_ephemerons(&mut self) {
+        loop {
+            let mut marked_any = false;
+            // Marking values can find more weak tables, which are appended
+            // to the list and visited in this same pass.
+            let mut i = 0;
+            while i < self.weak_tables.len() {
+                let mut obj = self.weak_tables[i];
+                let table = obj.as_table().unwrap();
+                marked_any |= table.mark_ephemeron_values(&mut self.weak_tables);
+                i += 1;
+            }
+            if !marked_any {
+                break;
+            }
+        }
+    }
+
+    /// Moves the unreachable objects marked for finalization to the queue of
+    /// pending finalizers, then resurrects everything in that queue (and
+    /// everything it references) for this cycle. Finalizers are called in the
+    /// reverse order that their objects were marked.
+    fn separate_unreachable(&mut self) {
+        let (unreachable, reachable): (Vec<_>, Vec<_>) = self
+            .finalizable
+            .drain(..)
+            .partition(|obj| matches!(obj.deref().color.get(), Color::Unmarked));
+        self.finalizable = reachable;
+        for obj in unreachable.into_iter().rev() {
+            obj.deref().finalizable.set(false);
+            self.to_finalize.push_back(obj);
+        }
+        for obj in &self.to_finalize {
+            obj.mark_reachable(&mut self.weak_tables);
+        }
+    }
+
+    fn new_obj_from_raw(
+        &mut self,
+        raw: RawObject,
+        mark: impl FnOnce(&mut Vec<ObjectPtr>),
+    ) -> ObjectPtr {
         if self.is_full() {
-            mark();
-            self.collect();
+            self.collect(mark);
         }
         let new_object = WrappedObject {
             next: self.start,
             color: Cell::new(Color::Unmarked),
+            finalizable: Cell::new(false),
             raw,
         };
         let boxed = Box::new(new_object);
@@ -217,39 +511,65 @@ impl Drop for GcHeap {
 /// An item is `Markable` if it can be marked as reachable, and thus it and
 /// anything it references will not be collected by the GC.
 pub(super) trait Markable {
-    /// Mark this item and the references it contains as reachable.
-    fn mark_reachable(&self);
+    /// Mark this item and the references it contains as reachable. Every
+    /// weak table which gets marked is added to `weak_tables`, so that the
+    /// collector can visit it once marking is done.
+    fn mark_reachable(&self, weak_tables: &mut Vec<ObjectPtr>);
 }
 
 impl Markable for WrappedObject {
-    fn mark_reachable(&self) {
+    fn mark_reachable(&self, weak_tables: &mut Vec<ObjectPtr>) {
         if let Color::Unmarked = self.color.get() {
             self.color.set(Color::Reachable);
-            self.raw.mark_reachable();
+            if let RawObject::Table(t) = &self.raw {
+                if t.is_weak() {
+                    weak_tables.push(ObjectPtr { ptr: self.into() });
+                }
+            }
+            self.raw.mark_reachable(weak_tables);
         }
     }
 }
 
 impl Markable for RawObject {
-    fn mark_reachable(&self) {
+    fn mark_reachable(&self, weak_tables: &mut Vec<ObjectPtr>) {
         match self {
-            RawObject::LuaFn(_) | RawObject::Str(_) => (),
-            RawObject::Table(tbl) => tbl.mark_reachable(),
+            RawObject::LuaFn(closure) => closure.mark_reachable(weak_tables),
+            RawObject::RustClosure(closure) => closure.upvalues.mark_reachable(weak_tables),
+            RawObject::Str(_) => (),
+            RawObject::Table(tbl) => tbl.mark_reachable(weak_tables),
+            RawObject::Thread(thread) => {
+                thread.stack.mark_reachable(weak_tables);
+                thread.string_literals.mark_reachable(weak_tables);
+            }
+        }
+    }
+}
+
+impl Markable for LuaClosure {
+    fn mark_reachable(&self, weak_tables: &mut Vec<ObjectPtr>) {
+        for upvalue in &self.upvalues {
+            // An open upvalue points into the stack of a thread, which has
+            // to be kept alive.
+            match &*upvalue.borrow() {
+                Upvalue::Open(thread, _) => thread.mark_reachable(weak_tables),
+                Upvalue::Closed(val) => val.mark_reachable(weak_tables),
+            }
         }
     }
 }
 
 impl Markable for ObjectPtr {
-    fn mark_reachable(&self) {
-        self.deref().mark_reachable()
+    fn mark_reachable(&self, weak_tables: &mut Vec<ObjectPtr>) {
+        self.deref().mark_reachable(weak_tables)
     }
 }
 
 /// This impl is mainly for any `Vec<Val>`s we use.
 impl<T: Markable> Markable for [T] {
-    fn mark_reachable(&self) {
+    fn mark_reachable(&self, weak_tables: &mut Vec<ObjectPtr>) {
         for val in self {
-            val.mark_reachable();
+            val.mark_reachable(weak_tables);
         }
     }
 }
@@ -257,9 +577,9 @@ impl<T: Markable> Markable for [T] {
 /// This is just for the `globals` field of `State`. It can be removed once
 /// globals are stored in a normal `Table`.
 impl<K, V: Markable> Markable for HashMap<K, V> {
-    fn mark_reachable(&self) {
+    fn mark_reachable(&self, weak_tables: &mut Vec<ObjectPtr>) {
         for val in self.values() {
-            val.mark_reachable();
+            val.mark_reachable(weak_tables);
         }
     }
 }
@@ -1,22 +1,98 @@
 use std::collections::HashMap;
 
 use super::Error;
+use super::ErrorKind;
+use super::LuaType;
 use super::Markable;
 use super::Result;
 use super::TypeError;
 use super::Val;
 
+use super::lua_val::float_to_int;
+use super::object::ObjectPtr;
+
 #[derive(Debug, Default)]
 pub(super) struct Table {
-    map: HashMap<Val, Val>,
+    /// The array part, which holds the values of the keys `1..=array.len()`.
+    /// Any of these values may be `nil`.
+    array: Vec<Val>,
+    /// Maps each key in the hash part to its position in `entries`.
+    indices: HashMap<Val, usize>,
+    /// Every key-value pair in the hash part, in insertion order. When a key
+    /// is set to `nil`, its entry stays in place, so that `next` still works
+    /// while the table is being traversed.
+    entries: Vec<(Val, Val)>,
+    /// The table's metatable, if it has one.
+    metatable: Option<ObjectPtr>,
+    /// Whether the keys are weak references, according to `__mode`.
+    weak_keys: bool,
+    /// Whether the values are weak references, according to `__mode`.
+    weak_values: bool,
 }
 
 impl Table {
+    /// Returns a border of the table: an integer `n` such that `t[n]` is not
+    /// `nil` and `t[n + 1]` is `nil`, or 0 if `t[1]` is `nil`. If the table
+    /// is a sequence, this is its length. Otherwise any border may be
+    /// returned.
+    pub(super) fn border(&self) -> i64 {
+        let len = self.array.len();
+        if len > 0 && matches!(self.array[len - 1], Val::Nil) {
+            // There's a border in the array part, so binary search for it.
+            let (mut i, mut j) = (0, len);
+            while j - i > 1 {
+                let m = i + (j - i) / 2;
+                if matches!(self.array[m - 1], Val::Nil) {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            return i as i64;
+        }
+        // The array part is full (or empty), so the border is at or past its
+        // end. This is the fast path for sequences.
+        let len = len as i64;
+        if !self.has_int(len + 1) {
+            return len;
+        }
+        // Find `i` and `j` such that `t[i]` is non-nil and `t[j]` is nil.
+        let (mut i, mut j) = (len + 1, (len + 1).saturating_mul(2));
+        while self.has_int(j) {
+            i = j;
+            if j > i64::MAX / 2 {
+                // Something strange is going on, so give up on being fast.
+                let mut n = 1;
+                while self.has_int(n + 1) {
+                    n += 1;
+                }
+                return n;
+            }
+            j *= 2;
+        }
+        // Binary search for a border between them.
+        while j - i > 1 {
+            let m = i + (j - i) / 2;
+            if self.has_int(m) {
+                i = m;
+            } else {
+                j = m;
+            }
+        }
+        i
+    }
+
     pub(super) fn get(&self, key: &Val) -> Val {
         match key {
             Val::Nil => Val::Nil,
             Val::Num(n) if n.is_nan() => Val::Nil,
-            _ => self.map.get(key).cloned().unwrap_or_default(),
+            _ => match self.array_index(key) {
+                Some(i) => self.array[i].clone(),
+                None => match self.indices.get(key) {
+                    Some(&i) => self.entries[i].1.clone(),
+                    None => Val::Nil,
+                },
+            },
         }
     }
 
@@ -25,18 +101,325 @@ impl Table {
             Val::Nil => Err(Error::new(TypeError::TableKeyNil, 0, 0)),
             Val::Num(n) if n.is_nan() => Err(Error::new(TypeError::TableKeyNan, 0, 0)),
             _ => {
-                self.map.insert(key, value);
+                // Floats with integer values are stored as integers.
+                let key = match key {
+                    Val::Num(n) => float_to_int(n).map_or(key, Val::Int),
+                    _ => key,
+                };
+                if let Some(i) = self.array_index(&key) {
+                    self.array[i] = value;
+                } else if let Some(&i) = self.indices.get(&key) {
+                    self.entries[i].1 = value;
+                } else if let Val::Nil = value {
+                    // Nothing to remove
+                } else if key == Val::Int(self.array.len() as i64 + 1) {
+                    // Appending to the array part is the common case for
+                    // sequences, so it doesn't wait for a rehash.
+                    self.array.push(value);
+                    self.migrate_from_hash();
+                } else {
+                    if self.entries.len() == self.entries.capacity() {
+                        self.rehash(&key);
+                        if let Some(i) = self.array_index(&key) {
+                            self.array[i] = value;
+                            return Ok(());
+                        }
+                    }
+                    self.indices.insert(key.clone(), self.entries.len());
+                    self.entries.push((key, value));
+                }
                 Ok(())
             }
         }
     }
+
+    /// Returns the entry which follows `key` in the table's traversal order,
+    /// or `None` if there are no more entries. If `key` is `nil`, returns the
+    /// first entry. Returns `Err` if `key` isn't in the table.
+    /// The array part is traversed first, in order.
+    pub(super) fn next(&self, key: &Val) -> Result<Option<(Val, Val)>> {
+        let (array_start, start) = match key {
+            Val::Nil => (0, 0),
+            _ => match (self.array_index(key), self.indices.get(key)) {
+                (Some(i), _) => (i + 1, 0),
+                (None, Some(&i)) => (self.array.len(), i + 1),
+                (None, None) => {
+                    let kind = ErrorKind::WithMessage("invalid key to 'next'".into());
+                    return Err(Error::new(kind, 0, 0));
+                }
+            },
+        };
+        let array_entry = self.array[array_start..]
+            .iter()
+            .enumerate()
+            .find(|(_, value)| !matches!(value, Val::Nil));
+        if let Some((i, value)) = array_entry {
+            let key = Val::Int((array_start + i) as i64 + 1);
+            return Ok(Some((key, value.clone())));
+        }
+        let entry = self.entries[start..]
+            .iter()
+            .find(|(_, value)| !matches!(value, Val::Nil))
+            .cloned();
+        Ok(entry)
+    }
+
+    pub(super) fn metatable(&self) -> Option<ObjectPtr> {
+        self.metatable
+    }
+
+    pub(super) fn set_metatable(&mut self, metatable: Option<ObjectPtr>) {
+        self.metatable = metatable;
+    }
+
+    /// Sets `t[1]`, `t[2]`, and so on to `values`, as in a table
+    /// constructor. The values are stored in the array part.
+    pub(super) fn set_list(&mut self, values: Vec<Val>) {
+        if values.len() > self.array.len() {
+            if !self.indices.is_empty() {
+                for i in self.array.len()..values.len() {
+                    self.remove_from_hash(&Val::Int(i as i64 + 1));
+                }
+            }
+            self.array.resize(values.len(), Val::Nil);
+        }
+        for (slot, value) in self.array.iter_mut().zip(values) {
+            *slot = value;
+        }
+        self.migrate_from_hash();
+    }
+
+    pub(super) fn is_weak(&self) -> bool {
+        self.weak_keys || self.weak_values
+    }
+
+    pub(super) fn set_weak_mode(&mut self, weak_keys: bool, weak_values: bool) {
+        self.weak_keys = weak_keys;
+        self.weak_values = weak_values;
+    }
+
+    /// Removes the entries whose weak keys or values weren't marked by the
+    /// GC. Keys are only checked if `clear_keys` is true. A removed key's
+    /// entry stays in place with a `nil` key, so that `next` still works.
+    pub(super) fn clear_unmarked(&mut self, clear_keys: bool) {
+        if self.weak_values {
+            for value in &mut self.array {
+                if is_cleared(value) {
+                    *value = Val::Nil;
+                }
+            }
+        }
+        for (key, value) in &mut self.entries {
+            if clear_keys && self.weak_keys && is_cleared(key) {
+                self.indices.remove(key);
+                *key = Val::Nil;
+                *value = Val::Nil;
+            } else if self.weak_values && is_cleared(value) {
+                *value = Val::Nil;
+            }
+        }
+    }
+
+    /// For a table with only weak keys (an ephemeron table), marks every
+    /// value whose key has been marked. Returns whether anything new was
+    /// marked.
+    pub(super) fn mark_ephemeron_values(&self, weak_tables: &mut Vec<ObjectPtr>) -> bool {
+        if !self.weak_keys || self.weak_values {
+            return false;
+        }
+        let mut marked_any = false;
+        for (key, value) in &self.entries {
+            if !is_cleared(key) && is_cleared(value) {
+                value.mark_reachable(weak_tables);
+                marked_any = true;
+            }
+        }
+        marked_any
+    }
+
+    /// Returns the position in `array` which holds `key`, if there is one.
+    fn array_index(&self, key: &Val) -> Option<usize> {
+        let i = match key {
+            Val::Int(i) => *i,
+            Val::Num(n) => float_to_int(*n)?,
+            _ => return None,
+        };
+        if 1 <= i && i <= self.array.len() as i64 {
+            Some(i as usize - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether `t[i]` is not `nil`.
+    fn has_int(&self, i: i64) -> bool {
+        let key = Val::Int(i);
+        let value = match self.array_index(&key) {
+            Some(idx) => &self.array[idx],
+            None => match self.indices.get(&key) {
+                Some(&idx) => &self.entries[idx].1,
+                None => return false,
+            },
+        };
+        !matches!(value, Val::Nil)
+    }
+
+    /// Moves the keys which directly follow the array part from the hash
+    /// part to the array part.
+    fn migrate_from_hash(&mut self) {
+        if self.indices.is_empty() {
+            return;
+        }
+        loop {
+            let key = Val::Int(self.array.len() as i64 + 1);
+            match self.remove_from_hash(&key) {
+                Val::Nil => break,
+                value => self.array.push(value),
+            }
+        }
+    }
+
+    /// Called when `new_key` is about to be added to a full hash part. The
+    /// array part is resized to the largest power of two `n` such that more
+    /// than half of the keys `1..=n` would be in use, and entries are moved
+    /// between the two parts to match. Dead entries are also removed.
+    fn rehash(&mut self, new_key: &Val) {
+        // `nums[b]` is the number of integer keys `k` such that
+        // `2^(b - 1) < k <= 2^b`.
+        let mut nums = [0; 64];
+        let mut num_int_keys = 0;
+        let mut count_key = |key: &Val| {
+            if let Val::Int(k @ 1..) = key {
+                nums[ceil_log2(*k as u64)] += 1;
+                num_int_keys += 1;
+            }
+        };
+        for (i, value) in self.array.iter().enumerate() {
+            if !matches!(value, Val::Nil) {
+                count_key(&Val::Int(i as i64 + 1));
+            }
+        }
+        for (key, value) in &self.entries {
+            if !matches!(value, Val::Nil) {
+                count_key(key);
+            }
+        }
+        count_key(new_key);
+        let new_len = optimal_array_len(&nums, num_int_keys);
+
+        let old_len = self.array.len();
+        let moved_out = if new_len < old_len {
+            self.array.split_off(new_len)
+        } else {
+            self.array.resize(new_len, Val::Nil);
+            Vec::new()
+        };
+        let array = &mut self.array;
+        self.entries.retain_mut(|(key, value)| {
+            if matches!(value, Val::Nil) {
+                return false;
+            }
+            match key {
+                Val::Int(k) if (old_len as i64) < *k && *k <= new_len as i64 => {
+                    array[*k as usize - 1] = std::mem::take(value);
+                    false
+                }
+                _ => true,
+            }
+        });
+        let moved_out = moved_out.into_iter().enumerate();
+        for (i, value) in moved_out.filter(|(_, value)| !matches!(value, Val::Nil)) {
+            let key = Val::Int((new_len + i) as i64 + 1);
+            self.entries.push((key, value));
+        }
+        self.indices.clear();
+        for (i, (key, _)) in self.entries.iter().enumerate() {
+            self.indices.insert(key.clone(), i);
+        }
+    }
+
+    /// Removes `key` from the hash part, leaving a dead entry in its place,
+    /// and returns its old value.
+    fn remove_from_hash(&mut self, key: &Val) -> Val {
+        match self.indices.remove(key) {
+            Some(i) => {
+                let (key, value) = &mut self.entries[i];
+                *key = Val::Nil;
+                std::mem::take(value)
+            }
+            None => Val::Nil,
+        }
+    }
+}
+
+/// Returns the smallest `b` such that `n <= 2^b`.
+fn ceil_log2(n: u64) -> usize {
+    (u64::BITS - (n - 1).leading_zeros()) as usize
+}
+
+/// Given the number of integer keys in each power-of-two range (as counted
+/// in `Table::rehash`), returns the largest power of two `n` such that more
+/// than half of the keys `1..=n` are in use.
+fn optimal_array_len(nums: &[usize], num_int_keys: usize) -> usize {
+    let mut optimal = 0;
+    let mut num_below = 0;
+    let mut two_to_i: usize = 1;
+    for &num in nums {
+        if num_int_keys <= two_to_i / 2 {
+            break;
+        }
+        num_below += num;
+        if num_below > two_to_i / 2 {
+            optimal = two_to_i;
+        }
+        two_to_i = match two_to_i.checked_mul(2) {
+            Some(n) => n,
+            None => break,
+        };
+    }
+    optimal
+}
+
+/// Returns whether `val` is a weak reference to an object which hasn't been
+/// marked. Strings are values rather than references, so they are never
+/// cleared from weak tables.
+fn is_cleared(val: &Val) -> bool {
+    match val {
+        Val::Obj(o) => o.typ() != LuaType::String && !o.is_marked(),
+        _ => false,
+    }
+}
+
+/// Returns whether a weak table should mark `val` when it is marked itself.
+fn is_strong_in_weak_table(val: &Val) -> bool {
+    match val {
+        Val::Obj(o) => o.typ() == LuaType::String,
+        _ => true,
+    }
 }
 
 impl Markable for Table {
-    fn mark_reachable(&self) {
-        for (k, v) in &self.map {
-            k.mark_reachable();
-            v.mark_reachable();
+    fn mark_reachable(&self, weak_tables: &mut Vec<ObjectPtr>) {
+        // The keys of the array part are integers, so they are never weak.
+        for v in &self.array {
+            if !self.weak_values || is_strong_in_weak_table(v) {
+                v.mark_reachable(weak_tables);
+            }
+        }
+        for (k, v) in &self.entries {
+            let key_is_strong = !self.weak_keys || is_strong_in_weak_table(k);
+            if key_is_strong {
+                k.mark_reachable(weak_tables);
+            }
+            // The value of an ephemeron entry is only marked once its key
+            // has been marked. See `mark_ephemeron_values`.
+            if !self.weak_values && (key_is_strong || !is_cleared(k)) || is_strong_in_weak_table(v)
+            {
+                v.mark_reachable(weak_tables);
+            }
+        }
+        if let Some(metatable) = &self.metatable {
+            metatable.mark_reachable(weak_tables);
         }
     }
 }
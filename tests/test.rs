@@ -60,3 +60,128 @@ fn test10() -> Result<()> {
 fn test11() -> Result<()> {
     run_file("tests/test11.lua")
 }
+
+#[test]
+fn test12() -> Result<()> {
+    run_file("tests/test12.lua")
+}
+
+#[test]
+fn test13() -> Result<()> {
+    run_file("tests/test13.lua")
+}
+
+#[test]
+fn test14() -> Result<()> {
+    run_file("tests/test14.lua")
+}
+
+#[test]
+fn test15() -> Result<()> {
+    run_file("tests/test15.lua")
+}
+
+#[test]
+fn test16() -> Result<()> {
+    run_file("tests/test16.lua")
+}
+
+#[test]
+fn test17() -> Result<()> {
+    run_file("tests/test17.lua")
+}
+
+#[test]
+fn test18() -> Result<()> {
+    run_file("tests/test18.lua")
+}
+
+#[test]
+fn test19() -> Result<()> {
+    run_file("tests/test19.lua")
+}
+
+#[test]
+fn test20() -> Result<()> {
+    run_file("tests/test20.lua")
+}
+
+#[test]
+fn test21() -> Result<()> {
+    run_file("tests/test21.lua")
+}
+
+#[test]
+fn test22() -> Result<()> {
+    run_file("tests/test22.lua")
+}
+
+#[test]
+fn test23() -> Result<()> {
+    run_file("tests/test23.lua")
+}
+
+#[test]
+fn test24() -> Result<()> {
+    run_file("tests/test24.lua")
+}
+
+#[test]
+fn test25() -> Result<()> {
+    run_file("tests/test25.lua")
+}
+
+#[test]
+fn test26() -> Result<()> {
+    run_file("tests/test26.lua")
+}
+
+#[test]
+fn test27() -> Result<()> {
+    run_file("tests/test27.lua")
+}
+
+#[test]
+fn test28() -> Result<()> {
+    run_file("tests/test28.lua")
+}
+
+#[test]
+fn test29() -> Result<()> {
+    run_file("tests/test29.lua")
+}
+
+#[test]
+fn test30() -> Result<()> {
+    run_file("tests/test30.lua")
+}
+
+#[test]
+fn test31() -> Result<()> {
+    run_file("tests/test31.lua")
+}
+
+#[test]
+fn test32() -> Result<()> {
+    run_file("tests/test32.lua")
+}
+
+#[test]
+fn test33() -> Result<()> {
+    run_file("tests/test33.lua")
+}
+
+#[test]
+fn test34() -> Result<()> {
+    run_file("tests/test34.lua")
+}
+
+#[test]
+fn test35() -> Result<()> {
+    run_file("tests/test35.lua")
+}
+
+#[test]
+fn test36() -> Result<()> {
+    run_file("tests/test36.lua")
+}
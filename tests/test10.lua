@@ -22,7 +22,6 @@ assert(a == nil)
 assert(b == nil)
 
 -- ipairs
--- TODO: rewrite this using for loops
 local tbl = {'a', 'b', 'c'}
 local f, t, n = ipairs(tbl)
 assert(t == tbl)
@@ -38,3 +37,19 @@ assert(key == 3)
 assert(value == 'c')
 local key, value = f(t, key)
 assert(key == nil and value == nil)
+
+local expected = {'a', 'b', 'c'}
+local count = 0
+for i, v in ipairs(tbl) do
+  count = count + 1
+  assert(i == count)
+  assert(v == expected[i])
+end
+assert(count == 3)
+
+-- ipairs stops at the first nil
+local count = 0
+for i in ipairs({1, 2, nil, 4}) do
+  count = count + 1
+end
+assert(count == 2)
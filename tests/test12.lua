@@ -0,0 +1,85 @@
+-- Test closures and upvalues
+
+-- A closure keeps its captured local alive
+local function make_counter()
+  local count = 0
+  return function ()
+    count = count + 1
+    return count
+  end
+end
+local counter = make_counter()
+assert(counter() == 1)
+assert(counter() == 2)
+local other_counter = make_counter()
+assert(other_counter() == 1)
+assert(counter() == 3)
+
+-- Sibling closures share the same upvalue
+local get, set
+do
+  local x = 'initial'
+  get = function () return x end
+  set = function (new_x) x = new_x end
+  x = 'changed'
+end
+assert(get() == 'changed')
+set('set')
+assert(get() == 'set')
+
+-- Each loop iteration gets a fresh local
+local fns = {}
+for i = 1, 3 do
+  fns[i] = function () return i end
+end
+assert(fns[1]() == 1)
+assert(fns[2]() == 2)
+assert(fns[3]() == 3)
+
+local fns2 = {}
+local j = 1
+while j <= 3 do
+  local k = j * 10
+  fns2[j] = function () return k end
+  j = j + 1
+end
+assert(fns2[1]() == 10)
+assert(fns2[3]() == 30)
+
+-- Upvalues can pass through several levels of functions
+local function outer()
+  local a = 'a'
+  return function ()
+    return function ()
+      return a
+    end
+  end
+end
+assert(outer()()() == 'a')
+
+-- Parameters can be captured too
+local function adder(n)
+  return function (m) return n + m end
+end
+local add_five = adder(5)
+assert(add_five(2) == 7)
+
+-- Local functions can call themselves
+local function fact(n)
+  if n <= 1 then
+    return 1
+  end
+  return n * fact(n - 1)
+end
+assert(fact(5) == 120)
+
+-- Captured values survive garbage collection
+local function make_holder()
+  local t = { value = 'kept' }
+  return function () return t.value end
+end
+local holder = make_holder()
+for i = 1, 1000 do
+  local garbage = {}
+end
+assert(holder() == 'kept')
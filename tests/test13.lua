@@ -0,0 +1,68 @@
+-- Test multiple return values
+
+local function three()
+  return 1, 2, 3
+end
+
+local function none()
+  return
+end
+
+-- Adjusting to the number of variables
+local a, b, c, d = three()
+assert(a == 1 and b == 2 and c == 3 and d == nil)
+local a, b = three()
+assert(a == 1 and b == 2)
+local a, b, c = none()
+assert(a == nil and b == nil and c == nil)
+
+-- Only the last expression in a list is expanded
+local a, b, c, d = three(), three()
+assert(a == 1 and b == 1 and c == 2 and d == 3)
+x, y, z = three(), 10
+assert(x == 1 and y == 10 and z == nil)
+
+-- Parentheses truncate to a single value
+local a, b = (three())
+assert(a == 1 and b == nil)
+local a = (none())
+assert(a == nil)
+
+-- Returning the results of another call
+local function prefix()
+  return 0, three()
+end
+local a, b, c, d = prefix()
+assert(a == 0 and b == 1 and c == 2 and d == 3)
+
+-- Expanding into arguments
+local function count_args(a, b, c, d, e)
+  if e ~= nil then return 5
+  elseif d ~= nil then return 4
+  elseif c ~= nil then return 3
+  elseif b ~= nil then return 2
+  elseif a ~= nil then return 1
+  else return 0
+  end
+end
+assert(count_args(three()) == 3)
+assert(count_args(three(), three()) == 4)
+assert(count_args((three())) == 1)
+assert(count_args(none()) == 0)
+assert(count_args(1, none()) == 1)
+
+-- Expanding into table constructors
+local t = {three()}
+assert(t[1] == 1 and t[2] == 2 and t[3] == 3 and t[4] == nil)
+local t = {three(), three()}
+assert(t[1] == 1 and t[2] == 1 and t[3] == 2 and t[4] == 3)
+local t = {three(), x = 'x'}
+assert(t[1] == 1 and t[2] == nil)
+local t = {(three())}
+assert(t[1] == 1 and t[2] == nil)
+local t = {0, none()}
+assert(t[1] == 0 and t[2] == nil)
+
+-- Rust functions also return multiple values
+local t = {unpack({'a', 'b', 'c'})}
+assert(t[1] == 'a' and t[2] == 'b' and t[3] == 'c')
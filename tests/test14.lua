@@ -0,0 +1,70 @@
+-- Test variadic functions and `...`
+
+local function count(...)
+  return select('#', ...)
+end
+assert(count() == 0)
+assert(count(nil) == 1)
+assert(count(1, 2, 3) == 3)
+assert(count(nil, nil) == 2)
+
+-- Fixed parameters come first
+local function rest(a, ...)
+  return ...
+end
+local x, y, z = rest(1, 2, 3)
+assert(x == 2 and y == 3 and z == nil)
+assert(rest(1) == nil)
+
+-- Missing fixed parameters are still nil
+local function first(a, b, ...)
+  return b
+end
+assert(first(1) == nil)
+
+-- `...` in table constructors and argument lists
+local function pack(...)
+  return {...}
+end
+local t = pack('a', 'b', 'c')
+assert(t[1] == 'a' and t[2] == 'b' and t[3] == 'c' and t[4] == nil)
+local t = {0, ...}
+assert(t[1] == 0 and t[2] == nil)
+assert(count(rest(0, 1, 2, 3)) == 3)
+
+-- Parentheses truncate `...` to one value
+local function first_only(...)
+  return (...)
+end
+local a, b = first_only(5, 6)
+assert(a == 5 and b == nil)
+
+-- `...` is adjusted like a function call
+local function two(...)
+  local a, b = ...
+  return a, b
+end
+local a, b = two(7)
+assert(a == 7 and b == nil)
+local a, b = two(7, 8, 9)
+assert(a == 7 and b == 8)
+
+-- Closures see their own varargs
+local function outer(...)
+  local n = select('#', ...)
+  return function (...)
+    return n, select('#', ...)
+  end
+end
+local n, m = outer(1, 2)(3)
+assert(n == 2 and m == 1)
+
+-- select with an index
+assert(select(2, 'a', 'b', 'c') == 'b')
+local a, b = select(-2, 'a', 'b', 'c')
+assert(a == 'b' and b == 'c')
+assert(select(4, 'a', 'b', 'c') == nil)
+assert(count(select(2, 'a', 'b', 'c')) == 2)
+
+-- The main chunk is variadic
+assert(select('#', ...) == 0)
@@ -0,0 +1,78 @@
+-- Test `break`
+
+-- while
+local i = 0
+while true do
+  i = i + 1
+  if i == 5 then break end
+end
+assert(i == 5)
+
+-- repeat
+local i = 0
+repeat
+  i = i + 1
+  if i == 3 then
+    break
+  end
+until i == 10
+assert(i == 3)
+
+-- numeric for
+local last
+for j = 1, 10 do
+  last = j
+  if j == 4 then break end
+end
+assert(last == 4)
+
+-- Only the innermost loop is exited
+local count = 0
+for a = 1, 3 do
+  for b = 1, 3 do
+    if b == 2 then break end
+    count = count + 1
+  end
+end
+assert(count == 3)
+
+-- Statements can follow `break` in the same block
+while true do
+  break
+  assert(false)
+end
+
+-- Locals captured inside the loop are closed when breaking out
+local fns = {}
+local k = 0
+while true do
+  k = k + 1
+  local captured = k
+  fns[k] = function () return captured end
+  if k == 3 then break end
+end
+local overwrite = 'overwritten'
+assert(fns[1]() == 1)
+assert(fns[3]() == 3)
+
+local f
+for j = 1, 10 do
+  local x = j * 2
+  f = function () return x end
+  if j == 2 then break end
+end
+local a, b, c, d, e = 1, 2, 3, 4, 5
+assert(f() == 4)
+
+-- A loop inside a function inside a loop
+for j = 1, 2 do
+  local g = function ()
+    local n = 0
+    while true do
+      n = n + 1
+      if n == 7 then break end
+    end
+    return n
+  end
+  assert(g() == 7)
+end
@@ -0,0 +1,85 @@
+-- Test `goto` and labels
+
+-- The `continue` idiom
+local sum = 0
+for i = 1, 10 do
+  if i % 2 == 0 then goto continue end
+  local odd = i
+  sum = sum + odd
+  ::continue::
+end
+assert(sum == 25)
+
+local i = 0
+local evens = 0
+while i < 10 do
+  i = i + 1
+  if i % 2 == 1 then
+    goto continue
+  end
+  evens = evens + 1
+  ::continue::
+end
+assert(evens == 5)
+
+-- A backward `goto` makes a loop
+local n = 0
+::top::
+n = n + 1
+if n < 5 then goto top end
+assert(n == 5)
+
+-- Jumping out of nested loops
+local found
+for a = 1, 5 do
+  for b = 1, 5 do
+    if a * b == 12 then
+      found = a * 10 + b
+      goto done
+    end
+  end
+end
+::done::
+assert(found == 34)
+
+-- Labels in sibling blocks may share a name
+do
+  goto skip
+  assert(false)
+  ::skip::
+end
+do
+  goto skip
+  assert(false)
+  ::skip::
+end
+
+-- Labels in a nested function are separate
+local function f()
+  goto skip
+  do return 'not skipped' end
+  ::skip::
+  return 'skipped'
+end
+assert(f() == 'skipped')
+
+-- Each backward jump gets a fresh local, and captured locals are closed
+local fns = {}
+local k = 1
+::again::
+local captured = k
+fns[k] = function () return captured end
+k = k + 1
+if k <= 3 then goto again end
+assert(fns[1]() == 1 and fns[2]() == 2 and fns[3]() == 3)
+
+-- Leaving a block with a captured local closes it
+local g
+do
+  local x = 'inner'
+  g = function () return x end
+  goto out
+end
+::out::
+local overwrite = 'overwritten'
+assert(g() == 'inner')
@@ -0,0 +1,94 @@
+-- Test generic `for` loops
+
+-- pairs visits every key exactly once
+local t = {10, 20, 30, x = 'x', y = 'y'}
+local seen = {}
+local count = 0
+for k, v in pairs(t) do
+  assert(seen[k] == nil)
+  seen[k] = v
+  count = count + 1
+end
+assert(count == 5)
+assert(seen[1] == 10 and seen[3] == 30 and seen.x == 'x' and seen.y == 'y')
+
+-- Clearing fields during traversal is allowed
+for k in pairs(t) do
+  t[k] = nil
+end
+assert(next(t) == nil)
+
+-- next
+assert(next({}) == nil)
+local k, v = next({'only'})
+assert(k == 1 and v == 'only')
+
+-- A custom stateless iterator
+local function range(n)
+  return function (limit, i)
+    if i < limit then
+      return i + 1
+    end
+  end, n, 0
+end
+local sum = 0
+for i in range(4) do
+  sum = sum + i
+end
+assert(sum == 10)
+
+-- A stateful iterator made with a closure
+local function chars(s)
+  local i = 0
+  return function ()
+    i = i + 1
+    if i <= #s then
+      return i, s
+    end
+  end
+end
+local n = 0
+for i, s in chars('abc') do
+  n = i
+  assert(s == 'abc')
+end
+assert(n == 3)
+
+-- Any number of variables, extras are nil
+local function triples()
+  local done = false
+  return function ()
+    if not done then
+      done = true
+      return 1, 2, 3
+    end
+  end
+end
+for a, b, c, d in triples() do
+  assert(a == 1 and b == 2 and c == 3 and d == nil)
+end
+
+-- `break` and nested loops
+local pairs_seen = 0
+for i in range(5) do
+  for j in range(5) do
+    if j > i then break end
+    pairs_seen = pairs_seen + 1
+  end
+end
+assert(pairs_seen == 15)
+
+-- Each iteration gets fresh loop variables
+local fns = {}
+for i, v in ipairs({'a', 'b', 'c'}) do
+  fns[i] = function () return v end
+end
+assert(fns[1]() == 'a' and fns[2]() == 'b' and fns[3]() == 'c')
+
+-- Assigning to the loop variable doesn't affect the iteration
+local visited = 0
+for i in range(3) do
+  visited = visited + 1
+  i = 100
+end
+assert(visited == 3)
@@ -0,0 +1,61 @@
+-- Test method calls and method definitions
+
+local counter = {count = 0}
+function counter:increment(by)
+  self.count = self.count + (by or 1)
+  return self
+end
+function counter.get(self)
+  return self.count
+end
+
+counter:increment()
+counter:increment(5)
+assert(counter:get() == 6)
+assert(counter.get(counter) == 6)
+
+-- Method calls can be chained
+counter:increment():increment(2)
+assert(counter:get() == 9)
+
+-- The receiver is evaluated only once
+local evaluations = 0
+local function get_counter()
+  evaluations = evaluations + 1
+  return counter
+end
+get_counter():increment()
+assert(evaluations == 1)
+assert(counter.count == 10)
+
+-- Deeply nested names
+local a = {b = {c = {}}}
+function a.b.c.f(x)
+  return x * 2
+end
+function a.b.c:m(x)
+  return self == a.b.c, x
+end
+assert(a.b.c.f(4) == 8)
+local is_self, x = a.b.c:m(3)
+assert(is_self and x == 3)
+
+-- Methods with varargs and multiple returns
+local obj = {}
+function obj:count(...)
+  return select('#', ...), self
+end
+local n, self = obj:count(1, 2, 3)
+assert(n == 3 and self == obj)
+local t = {obj:count()}
+assert(t[1] == 0 and t[2] == obj)
+
+-- Methods on values in a table
+local list = {items = {n = 0}}
+function list.items:push(v)
+  self.n = self.n + 1
+  self[self.n] = v
+end
+list.items:push('a')
+list.items:push('b')
+assert(list.items[1] == 'a' and list.items[2] == 'b')
@@ -0,0 +1,48 @@
+-- Test long strings and long comments
+
+local s = [[hello]]
+assert(s == 'hello')
+
+-- A newline right after the opening bracket is skipped
+local s = [[
+line 1
+line 2]]
+assert(s == [[line 1
+line 2]])
+
+-- Only the first newline is skipped
+local s = [[
+
+x]]
+assert(#s == 2)
+
+-- Levels
+local s = [==[contains ]] and ]=] but not the end]==]
+assert(s == 'contains ]] and ]=] but not the end')
+local s = [=[]]=]
+assert(s == ']')
+assert([=[]=] == '')
+
+-- No escapes in long strings
+local s = [[\n]]
+assert(#s == 2)
+
+--[[ A long comment
+assert(false)
+]]
+
+--[==[
+  assert(false)
+  ]]
+  assert(false)
+]==]
+
+local x = 1 --[[ an inline comment ]] + 1
+assert(x == 2)
+
+-- A comment that only looks like a long comment
+--[ assert(false)
+--[= assert(false)
+assert(true)
+
+--[[ a long comment at the very end ]]
@@ -0,0 +1,45 @@
+-- Test escape sequences in short strings
+
+assert(#'\n' == 1)
+assert('\n' == [[
+
+]])
+assert('\t' ~= 't')
+assert("\"" == '"')
+assert('\'' == "'")
+assert('\\' == [[\]])
+assert(#'\a\b\f\n\r\t\v' == 7)
+
+-- Decimal escapes
+assert('\65\066\0677' == 'ABC7')
+assert(#'\0' == 1)
+assert('\1234' == '{4')
+
+-- Hexadecimal escapes
+assert('\x41\x62' == 'Ab')
+assert('\x7a' == '\x7A')
+-- Any byte can be written, even if the string isn't valid UTF-8
+local s = 'a\xFFb\x80'
+assert(#s == 4 and s == 'a\255b\128')
+assert(s ~= 'a\xFEb\x80')
+assert('\xFF' .. '1' == '\2551')
+
+-- Unicode escapes
+assert('\u{48}\u{069}' == 'Hi')
+assert('\u{3B1}' == 'α')
+assert(#'\u{10FFFF}' == 4)
+-- Surrogates and values up to 2^31 - 1 use Lua's extended UTF-8
+assert('\u{D800}' == '\xED\xA0\x80')
+assert('\u{7FFFFFFF}' == '\xFD\xBF\xBF\xBF\xBF\xBF')
+
+-- Escaped newlines
+local s = 'a\
+b'
+assert(s == 'a\nb')
+
+-- \z skips whitespace, including newlines
+local s = 'a\z
+           b'
+assert(s == 'ab')
+assert('a\z  ' == 'a')
+
@@ -0,0 +1,132 @@
+-- Test the integer and float subtypes of numbers
+
+assert(math.type(1) == 'integer')
+assert(math.type(1.0) == 'float')
+assert(math.type(1e2) == 'float')
+assert(math.type(0x10) == 'integer')
+assert(math.type('1') == nil)
+
+-- Integers and floats with the same value are equal
+assert(1 == 1.0)
+assert(-0.0 == 0)
+assert(2^53 == 9007199254740992)
+assert(math.maxinteger + 0.0 ~= math.maxinteger)
+assert(math.maxinteger < math.maxinteger + 0.0)
+assert(math.mininteger == -2^63)
+
+-- Arithmetic keeps integers when both operands are integers
+assert(math.type(3 + 4) == 'integer')
+assert(math.type(3 + 4.0) == 'float')
+assert(math.type(3 * 4) == 'integer')
+assert(math.type(-3) == 'integer')
+assert(math.type(6 / 2) == 'float')
+assert(6 / 2 == 3)
+assert(math.type(2^2) == 'float')
+
+-- Integer arithmetic wraps around
+assert(math.maxinteger + 1 == math.mininteger)
+assert(math.mininteger - 1 == math.maxinteger)
+assert(-math.mininteger == math.mininteger)
+assert(math.maxinteger * 2 == -2)
+assert(0xffffffffffffffff == -1)
+assert(0x7fffffffffffffff == math.maxinteger)
+
+-- Large decimal integers become floats
+assert(math.type(9223372036854775807) == 'integer')
+assert(math.type(9223372036854775808) == 'float')
+
+-- Floor division and modulo
+assert(7 // 2 == 3)
+assert(math.type(7 // 2) == 'integer')
+assert(-7 // 2 == -4)
+assert(7 // -2 == -4)
+assert(7.0 // 2 == 3.0)
+assert(math.type(7.0 // 2) == 'float')
+assert(-7 // 2.0 == -4)
+assert(1 // 0.0 == 1 / 0)
+assert(math.mininteger // -1 == math.mininteger)
+assert(7 % 3 == 1)
+assert(-7 % 3 == 2)
+assert(7 % -3 == -2)
+assert(-7 % -3 == -1)
+assert(5.5 % 2 == 1.5)
+assert(-5.5 % 2 == 0.5)
+assert(math.mininteger % -1 == 0)
+
+-- Integer and float keys
+local t = {}
+t[1] = 'a'
+assert(t[1.0] == 'a')
+t[2.0] = 'b'
+assert(t[2] == 'b')
+assert(math.type(next(t, 1)) == 'integer')
+t[2^53] = 'c'
+assert(t[9007199254740992] == 'c')
+t[1.5] = 'd'
+assert(t[1] == 'a' and t[1.5] == 'd')
+
+-- Numeric for loops
+local count = 0
+for i = 1, 3 do
+  assert(math.type(i) == 'integer')
+  count = count + 1
+end
+assert(count == 3)
+
+for i = 1.0, 3 do
+  assert(math.type(i) == 'float')
+end
+
+for i = 1, 3.5 do
+  assert(math.type(i) == 'integer')
+end
+
+-- Loops near the limits of integers don't overflow
+count = 0
+for i = math.maxinteger - 2, math.maxinteger do
+  count = count + 1
+end
+assert(count == 3)
+
+count = 0
+for i = math.mininteger, math.mininteger + 2 do
+  count = count + 1
+end
+assert(count == 3)
+
+count = 0
+for i = math.mininteger + 2, math.mininteger, -1 do
+  count = count + 1
+end
+assert(count == 3)
+
+count = 0
+for i = 1, 1e400 do
+  count = count + 1
+  if count == 5 then break end
+end
+assert(count == 5)
+
+count = 0
+for i = 10, 1, -3 do
+  count = count + 1
+end
+assert(count == 4)
+
+-- A float limit beyond every integer
+for i = math.maxinteger, 1e100, -1 do
+  assert(false)
+end
+
+-- tointeger
+assert(math.tointeger(3.0) == 3)
+assert(math.type(math.tointeger(3.0)) == 'integer')
+assert(math.tointeger(3.5) == nil)
+assert(math.tointeger('x') == nil)
+
+-- select and ipairs use integers
+assert(math.type(select('#', 1, 2)) == 'integer')
+for i, v in ipairs({'a'}) do
+  assert(math.type(i) == 'integer')
+end
+assert(select(2.0, 'a', 'b') == 'b')
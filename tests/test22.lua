@@ -0,0 +1,49 @@
+-- Test bitwise operators
+
+assert(0xF0 & 0x3C == 0x30)
+assert(0xF0 | 0x0F == 0xFF)
+assert(0xFF ~ 0x0F == 0xF0)
+assert(~0 == -1)
+assert(~5 == -6)
+assert(math.type(1 | 2) == 'integer')
+
+-- Shifts are logical, and negative shifts go the other way
+assert(1 << 4 == 16)
+assert(256 >> 4 == 16)
+assert(1 << -1 == 0)
+assert(16 << -2 == 4)
+assert(-1 >> 1 == math.maxinteger)
+assert(1 << 63 == math.mininteger)
+assert(1 << 64 == 0)
+assert(-1 >> 64 == 0)
+assert(-1 << 100 == 0)
+
+-- Floats with exact integer values are converted
+assert(3.0 | 0 == 3)
+assert(math.type(3.0 | 0) == 'integer')
+assert(2^53 | 0 == 9007199254740992)
+
+-- So are strings which contain numbers
+assert("3" & 1 == 1)
+assert(math.type("3" & 1) == 'integer')
+assert(" 0x10 " | "2.0" == 18)
+assert(~"0" == -1)
+assert(1 << "4" == 16)
+
+-- Precedence: shifts bind tighter than `&`, which is tighter than `~`,
+-- which is tighter than `|`. All of them are below concatenation and
+-- arithmetic, and above comparisons.
+assert(1 | 2 ~ 3 & 4 << 1 == 1 | (2 ~ (3 & (4 << 1))))
+assert(1 << 2 + 1 == 8)
+assert(6 & 3 == 2)
+assert(~0 + 1 == 0)
+assert(- ~0 == 1)
+assert(2 ^ ~-3 == 4.0)
+
+-- Flag masking
+local READ, WRITE, EXEC = 1, 2, 4
+local flags = READ | EXEC
+assert(flags & WRITE == 0)
+assert(flags & EXEC ~= 0)
+flags = flags & ~READ
+assert(flags == EXEC)
@@ -0,0 +1,94 @@
+-- Test metatables, `__index` and `__newindex`
+
+local t = {}
+assert(getmetatable(t) == nil)
+local mt = {}
+assert(setmetatable(t, mt) == t)
+assert(getmetatable(t) == mt)
+assert(setmetatable(t, nil) == t)
+assert(getmetatable(t) == nil)
+
+-- __index as a table
+local defaults = {color = 'red', size = 1}
+local obj = setmetatable({size = 2}, {__index = defaults})
+assert(obj.color == 'red')
+assert(obj.size == 2)
+assert(obj.missing == nil)
+assert(rawget(obj, 'color') == nil)
+
+-- __index chains
+local a = setmetatable({}, {__index = obj})
+assert(a.color == 'red')
+assert(a.size == 2)
+
+-- __index as a function
+local calls = 0
+local squares = setmetatable({}, {
+  __index = function(t, k)
+    calls = calls + 1
+    return k * k
+  end,
+})
+assert(squares[4] == 16)
+assert(squares[5] == 25)
+assert(calls == 2)
+squares[4] = 'stored'
+assert(squares[4] == 'stored')
+assert(calls == 2)
+
+-- __newindex as a function
+local log, num_logged = {}, 0
+local proxy = setmetatable({}, {
+  __newindex = function(t, k, v)
+    num_logged = num_logged + 1
+    log[num_logged] = k
+    rawset(t, k, v * 2)
+  end,
+})
+proxy.x = 1
+assert(proxy.x == 2)
+-- Existing keys are assigned directly
+proxy.x = 5
+assert(proxy.x == 5)
+assert(log[1] == 'x' and log[2] == nil)
+
+-- __newindex as a table
+local store = {}
+local front = setmetatable({}, {__newindex = store})
+front.y = 3
+assert(rawget(front, 'y') == nil)
+assert(store.y == 3)
+
+-- A class-like pattern
+local Point = {}
+Point.__index = Point
+function Point.new(x, y)
+  return setmetatable({x = x, y = y}, Point)
+end
+function Point:sum()
+  return self.x + self.y
+end
+local p = Point.new(3, 4)
+assert(p:sum() == 7)
+
+-- Inheritance
+local Point3 = setmetatable({}, {__index = Point})
+Point3.__index = Point3
+function Point3.new(x, y, z)
+  local p = Point.new(x, y)
+  p.z = z
+  return setmetatable(p, Point3)
+end
+function Point3:sum()
+  return Point.sum(self) + self.z
+end
+assert(Point3.new(1, 2, 3):sum() == 6)
+
+-- __metatable protects the metatable
+local protected = setmetatable({}, {__metatable = 'locked'})
+assert(getmetatable(protected) == 'locked')
+
+-- rawset returns the table
+local r = {}
+assert(rawset(r, 1, 'one') == r)
+assert(r[1] == 'one')
@@ -0,0 +1,110 @@
+-- Test arithmetic, concatenation and length metamethods
+
+local Vec = {}
+Vec.__index = Vec
+
+local function vec(x, y)
+  return setmetatable({x = x, y = y}, Vec)
+end
+
+Vec.__add = function(a, b) return vec(a.x + b.x, a.y + b.y) end
+Vec.__sub = function(a, b) return vec(a.x - b.x, a.y - b.y) end
+Vec.__mul = function(a, b)
+  -- Scalar multiplication works from either side
+  if type(a) == 'number' then
+    return vec(a * b.x, a * b.y)
+  elseif type(b) == 'number' then
+    return vec(a.x * b, a.y * b)
+  end
+  return a.x * b.x + a.y * b.y
+end
+Vec.__div = function(a, n) return vec(a.x / n, a.y / n) end
+Vec.__idiv = function(a, n) return vec(a.x // n, a.y // n) end
+Vec.__mod = function(a, n) return vec(a.x % n, a.y % n) end
+Vec.__pow = function(a, n) return 'pow ' .. n end
+Vec.__unm = function(a, b)
+  assert(a == b)
+  return vec(-a.x, -a.y)
+end
+Vec.__len = function(a) return 2 end
+Vec.__concat = function(a, b)
+  if getmetatable(a) == Vec then
+    return '(' .. a.x .. ', ' .. a.y .. ')' .. b
+  else
+    return a .. '(' .. b.x .. ', ' .. b.y .. ')'
+  end
+end
+
+local a, b = vec(1, 2), vec(3, 5)
+local c = a + b
+assert(c.x == 4 and c.y == 7)
+c = b - a
+assert(c.x == 2 and c.y == 3)
+assert(a * b == 13)
+c = 2 * a
+assert(c.x == 2 and c.y == 4)
+c = a * 3
+assert(c.x == 3 and c.y == 6)
+c = b / 2
+assert(c.x == 1.5 and c.y == 2.5)
+c = b // 2
+assert(c.x == 1 and c.y == 2)
+c = b % 2
+assert(c.x == 1 and c.y == 1)
+assert(a ^ 3 == 'pow 3')
+c = -a
+assert(c.x == -1 and c.y == -2)
+assert(#a == 2)
+assert(a .. '!' == '(1, 2)!')
+assert('v = ' .. a == 'v = (1, 2)')
+
+-- Only the second operand has a metamethod
+local plain = {}
+local counted = setmetatable({}, {__add = function(x, y)
+  assert(x == plain)
+  return 'right'
+end})
+assert(plain + counted == 'right')
+
+-- The first operand's metamethod takes priority
+local left = setmetatable({}, {__add = function() return 'left' end})
+assert(left + counted == 'left')
+
+-- Concatenation is right associative
+local log = ''
+local cat = setmetatable({}, {__concat = function(x, y)
+  if type(x) == 'table' then x = 'T' end
+  if type(y) == 'table' then y = 'T' end
+  log = log .. '[' .. x .. y .. ']'
+  return x .. y
+end})
+assert('a' .. cat .. 'b' == 'aTb')
+assert(log == '[Tb]')
+
+-- Numbers are converted to strings when concatenated
+assert(1 .. '' == '1')
+assert(1.5 .. '' == '1.5')
+assert(2.0 .. 'x' == '2.0x')
+assert(1 .. 2 == '12')
+
+-- Strings are converted to numbers in arithmetic
+assert('10' + 1 == 11)
+assert(math.type('10' + 1) == 'integer')
+assert('3.5' * 2 == 7.0)
+assert(' 0x10 ' - 0 == 16)
+assert(-'2' == -2)
+assert('1e1' / 1 == 10)
+assert('7' // '2' == 3)
+
+-- A money type
+local Money = {}
+Money.__index = Money
+local function money(cents) return setmetatable({cents = cents}, Money) end
+Money.__add = function(a, b) return money(a.cents + b.cents) end
+Money.__concat = function(a, b)
+  if type(a) == 'table' then a = '$' .. a.cents // 100 .. '.' .. a.cents % 100 end
+  if type(b) == 'table' then b = '$' .. b.cents // 100 .. '.' .. b.cents % 100 end
+  return a .. b
+end
+local total = money(1050) + money(225)
+assert('Total: ' .. total == 'Total: $12.75')
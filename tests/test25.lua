@@ -0,0 +1,99 @@
+-- Test comparisons, and the `__eq`, `__lt` and `__le` metamethods
+
+-- Strings are compared lexicographically
+assert('a' < 'b')
+assert('abc' < 'abd')
+assert('ab' < 'abc')
+assert(not ('abc' < 'ab'))
+assert('' < 'a')
+assert('Z' < 'a')
+assert('a' <= 'a')
+assert('b' > 'a')
+assert('b' >= 'b')
+assert(not ('a' > 'a'))
+assert('10' < '9')
+
+-- Mixed numbers
+assert(1 < 1.5)
+assert(1.5 < 2)
+assert(1 <= 1.0)
+assert(not (1 < 1.0))
+assert(math.maxinteger < 2^63)
+assert(math.mininteger <= -2^63)
+assert(not (0/0 < 1) and not (0/0 >= 1))
+
+-- __eq
+local Point = {}
+Point.__eq = function(a, b) return a.x == b.x and a.y == b.y end
+local function point(x, y) return setmetatable({x = x, y = y}, Point) end
+local p, q = point(1, 2), point(1, 2)
+assert(p == q)
+assert(not (p ~= q))
+assert(p ~= point(2, 1))
+assert(not rawequal(p, q))
+assert(rawequal(p, p))
+-- __eq isn't used for values of different types
+assert(p ~= 1)
+-- __eq is used even if only the second table has it
+local plain = {x = 1, y = 2}
+assert(plain == p)
+-- The result is converted to a boolean
+local truthy = setmetatable({}, {__eq = function() return 'yes' end})
+assert((truthy == {}) == true)
+
+-- __lt and __le
+local Version = {}
+Version.__lt = function(a, b)
+  if a.major ~= b.major then return a.major < b.major end
+  return a.minor < b.minor
+end
+Version.__le = function(a, b) return not (b < a) end
+local function version(major, minor)
+  return setmetatable({major = major, minor = minor}, Version)
+end
+local v1, v2, v3 = version(1, 2), version(1, 10), version(2, 0)
+assert(v1 < v2)
+assert(v2 < v3)
+assert(v3 > v1)
+assert(not (v2 < v1))
+assert(v1 <= v1)
+assert(v3 >= v2)
+assert(not (v3 <= v2))
+
+-- Without __le, `a <= b` is `not (b < a)`
+local OnlyLt = {__lt = function(a, b) return a.n < b.n end}
+local x = setmetatable({n = 1}, OnlyLt)
+local y = setmetatable({n = 2}, OnlyLt)
+assert(x <= y)
+assert(not (y <= x))
+assert(y >= x)
+
+-- Sorting records
+local records = {
+  version(3, 1), version(1, 0), version(2, 5), version(1, 7), version(2, 0),
+}
+local n = 5
+for i = 2, n do
+  local j = i
+  while j > 1 and records[j] < records[j - 1] do
+    records[j], records[j - 1] = records[j - 1], records[j]
+    j = j - 1
+  end
+end
+for i = 1, n - 1 do
+  assert(records[i] <= records[i + 1])
+end
+assert(records[1].major == 1 and records[1].minor == 0)
+assert(records[5].major == 3)
+
+-- Sorting strings
+local words = {'pear', 'apple', 'fig', 'banana'}
+for i = 2, 4 do
+  local j = i
+  while j > 1 and words[j] < words[j - 1] do
+    words[j], words[j - 1] = words[j - 1], words[j]
+    j = j - 1
+  end
+end
+assert(words[1] == 'apple' and words[2] == 'banana')
+assert(words[3] == 'fig' and words[4] == 'pear')
@@ -0,0 +1,83 @@
+-- Test the `__call` metamethod
+
+local Callable = {}
+Callable.__call = function(self, a, b)
+  return self, a, b
+end
+local c = setmetatable({}, Callable)
+local s, a, b = c(1, 2)
+assert(s == c and a == 1 and b == 2)
+
+-- No arguments, and method calls
+local obj = setmetatable({}, Callable)
+obj.f = c
+local s, a, b = obj:f('x')
+assert(s == c and a == obj and b == 'x')
+local s, a = c()
+assert(s == c and a == nil)
+
+-- Varargs and multiple results are passed through
+local function count(...) return select('#', ...) end
+local counter = setmetatable({}, {__call = function(self, ...) return count(...) end})
+local function three() return 1, 2, 3 end
+assert(counter(three()) == 3)
+assert(counter(three(), 10) == 2)
+
+-- Memoized functions
+local calls = 0
+local function memoize(f)
+  local cache = {}
+  return setmetatable(cache, {
+    __call = function(self, n)
+      local v = rawget(self, n)
+      if v == nil then
+        v = f(n)
+        rawset(self, n, v)
+      end
+      return v
+    end
+  })
+end
+local square = memoize(function(n) calls = calls + 1 return n * n end)
+assert(square(4) == 16)
+assert(square(4) == 16)
+assert(square(5) == 25)
+assert(calls == 2)
+assert(square[4] == 16)
+
+-- Memoized recursion
+local fib
+fib = memoize(function(n)
+  if n < 2 then return n end
+  return fib(n - 1) + fib(n - 2)
+end)
+assert(fib(80) == 23416728348467685)
+
+-- Constructors
+local Point = setmetatable({}, {
+  __call = function(cls, x, y)
+    return setmetatable({x = x, y = y}, cls)
+  end
+})
+Point.__index = Point
+function Point:sum() return self.x + self.y end
+local p = Point(3, -4)
+assert(p.x == 3 and p.y == -4)
+assert(p:sum() == -1)
+
+-- A `__call` metamethod may itself be callable
+local inner = setmetatable({}, {__call = function(...) return select('#', ...) end})
+local outer = setmetatable({}, {__call = inner})
+assert(outer('a') == 3)
+
+-- Callable values work as generic `for` iterators
+local n = 0
+local iter = setmetatable({}, {
+  __call = function(self, state, i)
+    if i < state then return i + 1 end
+  end
+})
+for i in iter, 3, 0 do
+  n = n + i
+end
+assert(n == 6)
@@ -0,0 +1,92 @@
+-- Test `__gc` finalizers
+
+-- Allocates enough garbage to make sure the collector runs.
+local function churn()
+  for _ = 1, 5000 do
+    local _ = {}
+  end
+end
+
+local count = 0
+local order = ''
+local Resource = {}
+Resource.__gc = function(r)
+  count = count + 1
+  order = order .. r.name
+end
+
+local function resource(name)
+  return setmetatable({name = name}, Resource)
+end
+
+-- A reachable object isn't finalized
+local kept = resource('k')
+churn()
+assert(count == 0)
+
+-- An unreachable one is
+resource('a')
+churn()
+assert(count == 1)
+assert(order == 'a')
+
+-- Finalizers from the same cycle run in reverse order of marking
+local function make_three()
+  local r1, r2, r3 = resource('1'), resource('2'), resource('3')
+end
+make_three()
+churn()
+assert(count == 4)
+assert(order == 'a321')
+
+-- Each object is only finalized once
+churn()
+assert(count == 4)
+
+-- `__gc` must be present when `setmetatable` is called
+local late = {}
+local late_count = 0
+setmetatable({}, late)
+late.__gc = function() late_count = late_count + 1 end
+churn()
+assert(late_count == 0)
+setmetatable({}, late)
+churn()
+assert(late_count == 1)
+
+-- Objects are resurrected while their finalizers run
+saved = nil
+local function make_saved()
+  local inner = {value = 42}
+  setmetatable({inner = inner}, {__gc = function(o) saved = o end})
+end
+make_saved()
+churn()
+assert(saved ~= nil)
+churn()
+assert(saved.inner.value == 42)
+
+-- A resurrected object is not finalized again unless marked again
+local revived = 0
+local revive_mt = {}
+revive_mt.__gc = function(o)
+  revived = revived + 1
+  if revived < 3 then
+    setmetatable(o, revive_mt)
+  end
+end
+setmetatable({}, revive_mt)
+churn()
+churn()
+churn()
+churn()
+assert(revived == 3)
+
+-- Errors in finalizers are ignored
+setmetatable({}, {__gc = function() local x = nil + 1 end})
+resource('e')
+churn()
+assert(count == 5)
+assert(order == 'a321e')
+
+assert(kept.name == 'k')
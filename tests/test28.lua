@@ -0,0 +1,144 @@
+-- Test weak tables
+
+-- Allocates enough garbage to make sure the collector runs.
+local function churn()
+  for _ = 1, 5000 do
+    local _ = {}
+  end
+end
+
+local function count(t)
+  local n = 0
+  for _ in pairs(t) do
+    n = n + 1
+  end
+  return n
+end
+
+-- Weak values
+local cache = setmetatable({}, {__mode = 'v'})
+local kept = {}
+local function fill_values()
+  cache[1] = {}
+  cache[2] = kept
+  cache[3] = 'a string'
+  cache[4] = 10
+  cache.f = function() end
+end
+fill_values()
+assert(count(cache) == 5)
+churn()
+assert(cache[1] == nil)
+assert(cache[2] == kept)
+assert(cache[3] == 'a string')
+assert(cache[4] == 10)
+assert(cache.f == nil)
+assert(count(cache) == 3)
+
+-- Weak keys
+local props = setmetatable({}, {__mode = 'k'})
+local key = {}
+local function fill_keys()
+  props[{}] = 'gone'
+  props[key] = 'stays'
+  props.name = {}
+end
+fill_keys()
+churn()
+assert(count(props) == 2)
+assert(props[key] == 'stays')
+assert(type(props.name) == 'table')
+
+-- Weak keys and values
+local both = setmetatable({}, {__mode = 'kv'})
+local function fill_both()
+  both[key] = {}
+  both[{}] = key
+  both[1] = key
+  both[2] = {}
+end
+fill_both()
+churn()
+assert(count(both) == 1)
+assert(both[1] == key)
+
+-- Ephemerons: a value which refers to its own key doesn't keep the key alive
+local ephemeron = setmetatable({}, {__mode = 'k'})
+local function fill_ephemeron()
+  for i = 1, 10 do
+    local k = {}
+    ephemeron[k] = {key = k}
+  end
+  -- A chain of entries, only reachable through a live key
+  local a, b, c = {}, {}, {}
+  ephemeron[key] = a
+  ephemeron[a] = b
+  ephemeron[b] = c
+  ephemeron[c] = 'end of chain'
+end
+fill_ephemeron()
+churn()
+assert(count(ephemeron) == 4)
+local a = ephemeron[key]
+local b = ephemeron[a]
+local c = ephemeron[b]
+assert(ephemeron[c] == 'end of chain')
+
+-- Once the first key dies, the whole chain goes with it
+key = nil
+props, both = nil, nil
+a, b, c = nil, nil, nil
+churn()
+assert(count(ephemeron) == 0)
+
+-- Without `__mode`, a table keeps everything alive
+local strong = setmetatable({}, {})
+local function fill_strong()
+  strong[{}] = {}
+end
+fill_strong()
+churn()
+assert(count(strong) == 1)
+
+-- Weak tables can be traversed while the collector runs
+local traversed = setmetatable({}, {__mode = 'k'})
+local live = {}
+for i = 1, 20 do
+  live[i] = {}
+  traversed[live[i]] = i
+end
+local sum = 0
+for k, v in pairs(traversed) do
+  churn()
+  sum = sum + v
+end
+assert(sum == 210)
+
+-- Objects being finalized are removed from weak values, but not weak keys
+local wk = setmetatable({}, {__mode = 'k'})
+local wv = setmetatable({}, {__mode = 'v'})
+finalized = nil
+local function fill_finalized()
+  local obj = setmetatable({}, {__gc = function(o) finalized = o end})
+  wk[obj] = true
+  wv[1] = obj
+end
+fill_finalized()
+churn()
+assert(finalized ~= nil)
+assert(wv[1] == nil)
+assert(wk[finalized] == true)
+
+-- A weak table only reachable through an ephemeron value is still cleared
+local outer = setmetatable({}, {__mode = 'k'})
+local outer_key = {}
+local function fill_nested()
+  local inner = setmetatable({}, {__mode = 'v'})
+  inner[1] = {}
+  inner[2] = outer_key
+  outer[outer_key] = inner
+end
+fill_nested()
+churn()
+assert(outer[outer_key][1] == nil)
+assert(outer[outer_key][2] == outer_key)
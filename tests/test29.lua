@@ -0,0 +1,74 @@
+-- Test the length operator on tables
+
+assert(#{} == 0)
+assert(#{1, 2, 3} == 3)
+assert(#{'a', 'b', nil} == 2)
+assert(#{n = 1, m = 2} == 0)
+assert(#{1, 2, 3, x = 'y'} == 3)
+
+-- Sequences built one element at a time
+local t = {}
+for i = 1, 1000 do
+  t[#t + 1] = i * 2
+end
+assert(#t == 1000)
+assert(t[#t] == 2000)
+
+local sum = 0
+for i = 1, #t do
+  sum = sum + t[i]
+end
+assert(sum == 1001000)
+
+-- Removing from the end
+for i = 1000, 501, -1 do
+  t[i] = nil
+end
+assert(#t == 500)
+t[#t] = nil
+assert(#t == 499)
+
+-- Keys stored as floats count too
+local f = {}
+f[1.0], f[2.0] = 'a', 'b'
+assert(#f == 2)
+
+-- With holes, any border may be returned
+local function is_border(t, n)
+  if n == 0 then
+    return t[1] == nil
+  end
+  return t[n] ~= nil and t[n + 1] == nil
+end
+local holes = {1, 2, nil, 4, nil, nil, 7}
+assert(is_border(holes, #holes))
+local sparse = {}
+sparse[1], sparse[2], sparse[10], sparse[11] = 1, 2, 10, 11
+assert(is_border(sparse, #sparse))
+local no_first = {}
+no_first[2] = 'x'
+assert(#no_first == 0)
+
+-- `__len` takes priority, and `rawlen` ignores it
+local mt = {__len = function() return 42 end}
+local with_len = setmetatable({1, 2}, mt)
+assert(#with_len == 42)
+assert(rawlen(with_len) == 2)
+assert(rawlen('hello') == 5)
+assert(#'hello' == 5)
+
+-- Tables with metatables but no `__len` use the raw length
+local plain = setmetatable({1, 2, 3}, {})
+assert(#plain == 3)
+
+-- Numeric for loops over `#t`
+local words = {'a', 'b', 'c'}
+local s = ''
+for i = 1, #words do
+  s = s .. words[i]
+end
+assert(s == 'abc')
+for i = #words, 1, -1 do
+  s = s .. words[i]
+end
+assert(s == 'abccba')
@@ -0,0 +1,143 @@
+-- Test tables with both an array part and a hash part
+
+local function count(t)
+  local n = 0
+  for _ in pairs(t) do
+    n = n + 1
+  end
+  return n
+end
+
+-- Positional items override keyed ones in constructors
+local t = {[1] = 'x', 'y', [2] = 'z'}
+assert(t[1] == 'y')
+assert(t[2] == 'z')
+assert(#t == 2)
+local t = {'a', 'b', [3] = 'c', [5] = 'e'}
+assert(#t == 3 or #t == 5)
+assert(t[3] == 'c' and t[5] == 'e')
+
+-- Keys filled in reverse order still end up as a sequence
+local rev = {}
+for i = 100, 1, -1 do
+  rev[i] = i
+end
+assert(#rev == 100)
+for i = 1, 100 do
+  assert(rev[i] == i)
+end
+assert(count(rev) == 100)
+
+-- Mixing integer, float, string and other keys
+local mixed = {}
+for i = 1, 50 do
+  mixed[i] = i
+  mixed['k' .. i] = i
+  mixed[i + 0.5] = -i
+end
+mixed[0] = 'zero'
+mixed[-1] = 'negative'
+mixed[true] = 'bool'
+assert(#mixed == 50)
+assert(count(mixed) == 153)
+assert(mixed[2.0] == 2)
+assert(mixed[2.5] == -2)
+assert(mixed.k50 == 50)
+assert(mixed[0] == 'zero' and mixed[-1] == 'negative')
+assert(mixed[true] == 'bool')
+
+-- Traversal visits the array part in order, and every key exactly once
+local seen = {}
+local last = 0
+local in_order = true
+for k, v in pairs(mixed) do
+  assert(seen[k] == nil)
+  seen[k] = true
+  if math.type(k) == 'integer' and k >= 1 then
+    in_order = in_order and k == last + 1
+    last = k
+  end
+end
+assert(in_order)
+assert(count(seen) == 153)
+
+-- Clearing fields during traversal is allowed
+for k in pairs(mixed) do
+  mixed[k] = nil
+end
+assert(count(mixed) == 0)
+assert(#mixed == 0)
+assert(next(mixed) == nil)
+
+-- Shrinking: a table which used to be a sequence
+local shrink = {}
+for i = 1, 64 do
+  shrink[i] = i
+end
+for i = 1, 64 do
+  shrink[i] = nil
+end
+for i = 1, 100 do
+  shrink['s' .. i] = i
+end
+shrink[1000] = 'far'
+assert(#shrink == 0)
+assert(shrink[1000] == 'far')
+assert(count(shrink) == 101)
+
+-- Holes in the middle
+local holes = {1, 2, 3, 4, 5, 6, 7, 8}
+holes[4] = nil
+assert(holes[5] == 5)
+local n = 0
+for i, v in ipairs(holes) do
+  n = n + 1
+end
+assert(n == 3)
+
+-- ipairs respects __index
+local proxy = setmetatable({}, {__index = function(_, i)
+  if i <= 3 then return i * 10 end
+end})
+local sum = 0
+for i, v in ipairs(proxy) do
+  sum = sum + v
+end
+assert(sum == 60)
+
+-- unpack
+local a, b, c = unpack({1, 2, 3})
+assert(a == 1 and b == 2 and c == 3)
+assert(select('#', unpack({})) == 0)
+local a, b, c = unpack({1, nil, 3})
+assert(a == 1 and b == nil and c == 3)
+
+-- A pseudo-random mix of insertions and deletions, checked against a
+-- plain count
+local seed = 12345
+local function random(n)
+  seed = (seed * 1103515245 + 12345) % 2147483648
+  return seed % n + 1
+end
+local r = {}
+local present = 0
+for _ = 1, 5000 do
+  local k = random(200)
+  if random(3) == 1 then
+    if r[k] ~= nil then
+      present = present - 1
+    end
+    r[k] = nil
+  else
+    if r[k] == nil then
+      present = present + 1
+    end
+    r[k] = k
+  end
+end
+assert(count(r) == present)
+for k, v in pairs(r) do
+  assert(k == v)
+end
+local border = #r
+assert(border == 0 and r[1] == nil or r[border] ~= nil and r[border + 1] == nil)
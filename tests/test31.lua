@@ -0,0 +1,69 @@
+-- Test string equality and strings as table keys
+
+-- Strings built at runtime equal literals
+local config = {name = 'lua', version = 54}
+local key = 'na' .. 'me'
+assert(key == 'name')
+assert(config[key] == 'lua')
+assert(config['ver' .. 'sion'] == 54)
+assert(rawequal(key, 'name'))
+
+-- Numbers converted to strings
+local t = {}
+t['10'] = 'ten'
+assert(t[10 .. ''] == 'ten')
+assert(t[10] == nil)
+
+-- Strings on either side of the length limit for interning
+local function build(n, c)
+  local s = ''
+  for _ = 1, n do
+    s = s .. c
+  end
+  return s
+end
+for _, n in ipairs({39, 40, 41, 100}) do
+  local s1, s2 = build(n, 'a'), build(n, 'a')
+  assert(#s1 == n)
+  assert(s1 == s2)
+  assert(rawequal(s1, s2))
+  assert(s1 ~= build(n, 'b'))
+  local keys = {}
+  keys[s1] = n
+  assert(keys[s2] == n)
+  assert(keys[build(n - 1, 'a') .. 'a'] == n)
+  assert(keys[build(n, 'b')] == nil)
+end
+
+-- Long strings which differ only at the end
+local prefix = build(60, 'p')
+local long = {}
+long[prefix .. '1'] = 1
+long[prefix .. '2'] = 2
+assert(long[prefix .. '1'] == 1)
+assert(long[prefix .. '2'] == 2)
+assert(long[prefix] == nil)
+
+-- Many short strings survive garbage collection while they're in use
+local names = {}
+for i = 1, 2000 do
+  names['key' .. i] = i
+end
+for i = 1, 2000 do
+  local _ = {}
+  assert(names['key' .. i] == i)
+end
+local count = 0
+for k, v in pairs(names) do
+  assert(k == 'key' .. v)
+  count = count + 1
+end
+assert(count == 2000)
+
+-- Strings compare by contents, not identity, even after being collected
+local function make() return 'temp' .. 'orary' end
+local first = make()
+for _ = 1, 5000 do
+  local _ = 'garbage' .. _
+end
+assert(make() == first)
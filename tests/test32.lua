@@ -0,0 +1,159 @@
+-- Test coroutines
+
+-- Values are passed in both directions
+local co = coroutine.create(function(a, b)
+  assert(a == 1 and b == 2)
+  local c = coroutine.yield(a + b)
+  assert(c == 'c')
+  local d, e = coroutine.yield()
+  assert(d == nil and e == 'e')
+  return 'done', 10
+end)
+assert(type(co) == 'thread')
+assert(coroutine.status(co) == 'suspended')
+local ok, sum = coroutine.resume(co, 1, 2)
+assert(ok and sum == 3)
+assert(coroutine.status(co) == 'suspended')
+assert(select('#', coroutine.resume(co, 'c')) == 1)
+local ok, x, y = coroutine.resume(co, nil, 'e')
+assert(ok and x == 'done' and y == 10)
+assert(coroutine.status(co) == 'dead')
+local ok, msg = coroutine.resume(co)
+assert(not ok and type(msg) == 'string')
+
+-- Yielding from nested Lua calls
+local function walk(t)
+  for _, v in ipairs(t) do
+    if type(v) == 'table' then
+      walk(v)
+    else
+      coroutine.yield(v)
+    end
+  end
+end
+local tree = {1, {2, 3, {4}}, {}, 5}
+local sum = 0
+for v in coroutine.wrap(function() walk(tree) end) do
+  sum = sum + v
+end
+assert(sum == 15)
+
+-- A generator which keeps its state in locals
+local function range(n)
+  return coroutine.wrap(function()
+    for i = 1, n do
+      coroutine.yield(i)
+    end
+  end)
+end
+local gen = range(3)
+assert(gen() == 1 and gen() == 2 and gen() == 3)
+assert(gen() == nil)
+
+-- Statuses
+local main_co, is_main = coroutine.running()
+assert(type(main_co) == 'thread' and is_main)
+assert(not coroutine.isyieldable())
+local outer
+local inner = coroutine.create(function()
+  assert(coroutine.status(outer) == 'normal')
+  local running, is_main = coroutine.running()
+  assert(not is_main and coroutine.status(running) == 'running')
+  coroutine.yield()
+end)
+outer = coroutine.create(function()
+  assert(coroutine.isyieldable())
+  assert(coroutine.status(outer) == 'running')
+  assert(coroutine.resume(inner))
+  assert(coroutine.status(inner) == 'suspended')
+  -- A running coroutine can't be resumed again
+  local ok = coroutine.resume(outer)
+  assert(not ok)
+end)
+assert(coroutine.resume(outer))
+assert(coroutine.status(outer) == 'dead')
+
+-- Errors kill the coroutine
+local bad = coroutine.create(function()
+  coroutine.yield(1)
+  local x = nil + 1
+end)
+assert(coroutine.resume(bad))
+local ok, msg = coroutine.resume(bad)
+assert(not ok and type(msg) == 'string')
+assert(coroutine.status(bad) == 'dead')
+local ok, msg = coroutine.close(bad)
+assert(not ok and type(msg) == 'string')
+
+-- Closing a suspended coroutine
+local suspended = coroutine.create(function() coroutine.yield() end)
+coroutine.resume(suspended)
+assert(coroutine.close(suspended) == true)
+assert(coroutine.status(suspended) == 'dead')
+
+-- Yielding across a metamethod isn't allowed
+local mt = {__index = function(t, k) return coroutine.yield(k) end}
+local across = coroutine.create(function()
+  return setmetatable({}, mt).x
+end)
+local ok, msg = coroutine.resume(across)
+assert(not ok and type(msg) == 'string')
+
+-- Closures share upvalues with suspended coroutines
+local get, set
+local shared = coroutine.create(function()
+  local n = 1
+  get = function() return n end
+  set = function(v) n = v end
+  coroutine.yield()
+  assert(n == 2)
+  n = 3
+  coroutine.yield()
+end)
+coroutine.resume(shared)
+assert(get() == 1)
+set(2)
+coroutine.resume(shared)
+assert(get() == 3)
+coroutine.resume(shared)
+assert(coroutine.status(shared) == 'dead')
+assert(get() == 3)
+
+-- Producer and consumer
+local function producer()
+  return coroutine.create(function()
+    for _, word in ipairs({'a', 'b', 'c'}) do
+      coroutine.yield(word)
+    end
+    return nil
+  end)
+end
+local function consumer(prod)
+  local s = ''
+  while true do
+    local _, word = coroutine.resume(prod)
+    if word == nil then
+      return s
+    end
+    s = s .. word
+  end
+end
+assert(consumer(producer()) == 'abc')
+
+-- Many coroutines alive at once
+local cos = {}
+for i = 1, 100 do
+  cos[i] = coroutine.wrap(function(x)
+    while true do
+      x = x + coroutine.yield(x * i)
+    end
+  end)
+end
+local total = 0
+for i = 1, 100 do
+  total = total + cos[i](1)
+end
+for i = 1, 100 do
+  total = total + cos[i](1)
+end
+assert(total == 5050 * 3)
@@ -0,0 +1,97 @@
+-- Test proper tail calls
+
+-- Tail recursion runs in constant space
+local function count(n, acc)
+  if n == 0 then
+    return acc
+  end
+  return count(n - 1, acc + 1)
+end
+assert(count(500000, 0) == 500000)
+
+-- Mutual recursion
+local is_even, is_odd
+function is_even(n)
+  if n == 0 then return true end
+  return is_odd(n - 1)
+end
+function is_odd(n)
+  if n == 0 then return false end
+  return is_even(n - 1)
+end
+assert(is_even(300000))
+assert(is_odd(300001))
+
+-- All results are returned
+local function three() return 1, 2, 3 end
+local function tail() return three() end
+local a, b, c = tail()
+assert(a == 1 and b == 2 and c == 3)
+assert(select('#', tail()) == 3)
+
+-- Varargs, methods and multiple arguments
+local function sum(...)
+  local total = 0
+  for _, v in ipairs({...}) do
+    total = total + v
+  end
+  return total
+end
+local function forward(...) return sum(...) end
+assert(forward(1, 2, 3, 4) == 10)
+local function forward_more(x, ...) return sum(x * 10, ...) end
+assert(forward_more(1, 2, 3) == 15)
+local obj = {n = 5}
+function obj:get(k) return self.n + k end
+local function method() return obj:get(2) end
+assert(method() == 7)
+
+-- Tail calls to Rust functions, and through `__call`
+local function to_select(...) return select('#', ...) end
+assert(to_select(nil, nil) == 2)
+local callable = setmetatable({}, {__call = function(self, x) return x * 2 end})
+local function call_it(x) return callable(x) end
+assert(call_it(21) == 42)
+
+-- Parentheses limit the results to one value, so this isn't a tail call
+local function one() return (three()) end
+assert(select('#', one()) == 1)
+
+-- Upvalues of the replaced frame are closed first
+local function make(n)
+  local x = n
+  local function get() return x end
+  return (function(f) return f end)(get)
+end
+local getters = {}
+for i = 1, 3 do
+  getters[i] = make(i)
+end
+assert(getters[1]() == 1 and getters[2]() == 2 and getters[3]() == 3)
+
+-- Continuation-passing style, where every call is a tail call
+local function walk(list, i, k)
+  if list[i] == nil then
+    return k(i - 1)
+  end
+  return walk(list, i + 1, function(n) return k(n) end)
+end
+local long = {}
+for i = 1, 1000 do
+  long[i] = i
+end
+assert(walk(long, 1, function(n) return n end) == 1000)
+
+-- Yielding in a tail call
+local co = coroutine.wrap(function(x)
+  local function loop(n)
+    if n == 3 then
+      return 'done'
+    end
+    return loop(coroutine.yield(n) + n)
+  end
+  return loop(x)
+end)
+assert(co(1) == 1)
+assert(co(1) == 2)
+assert(co(1) == 'done')
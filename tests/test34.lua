@@ -0,0 +1,43 @@
+-- Test deep calls
+
+-- Deep recursion which isn't a tail call
+local function depth(n)
+  if n == 0 then
+    return 0
+  end
+  return 1 + depth(n - 1)
+end
+assert(depth(100000) == 100000)
+
+-- Unbounded recursion raises an error which can be caught
+local function forever(n)
+  return 1 + forever(n + 1)
+end
+local ok, msg = coroutine.resume(coroutine.create(forever), 1)
+assert(not ok and type(msg) == 'string')
+
+-- The state is still usable afterwards
+assert(depth(10) == 10)
+
+-- So is unbounded recursion through metamethods
+local t = setmetatable({}, {})
+getmetatable(t).__index = function(t, k) return t[k] end
+local ok, msg = coroutine.resume(coroutine.create(function() return t.x end))
+assert(not ok and type(msg) == 'string')
+
+-- Generic for iterators written in Lua can yield
+local function iter(_, i)
+  if i < 3 then
+    coroutine.yield(i)
+    return i + 1
+  end
+end
+local gen = coroutine.wrap(function()
+  local sum = 0
+  for i in iter, nil, 0 do
+    sum = sum + i
+  end
+  return sum
+end)
+assert(gen() == 0 and gen() == 1 and gen() == 2)
+assert(gen() == 6)
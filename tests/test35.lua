@@ -0,0 +1,110 @@
+-- Test local attributes
+
+-- Constants
+local n <const> = 10
+local s <const>, t = 'a', {}
+t.x = n
+assert(t.x == 10 and s == 'a')
+local function get_n() return n end
+assert(get_n() == 10)
+
+-- To-be-closed variables are closed in reverse order when their block ends
+local log = {}
+local function closable(name)
+  return setmetatable({}, {__close = function(self, err)
+    log[#log + 1] = name
+    if err ~= nil then
+      log[#log + 1] = 'error'
+    end
+  end})
+end
+local function logged()
+  local s = ''
+  for i = 1, #log do
+    s = s .. log[i] .. ' '
+  end
+  log = {}
+  return s
+end
+
+do
+  local a <close> = closable('a')
+  local b <close> = closable('b')
+  local c <close> = nil
+  assert(#log == 0)
+end
+assert(logged() == 'b a ')
+
+-- `break`
+for i = 1, 3 do
+  local x <close> = closable(i)
+  if i == 2 then
+    break
+  end
+end
+assert(logged() == '1 2 ')
+
+-- `while` and `goto`
+local i = 0
+while true do
+  local x <close> = closable('w' .. i)
+  i = i + 1
+  if i == 2 then
+    goto done
+  end
+end
+::done::
+assert(logged() == 'w0 w1 ')
+
+-- `return`, after the values are computed
+local function f()
+  local x <close> = closable('f')
+  return #log
+end
+assert(f() == 0)
+assert(logged() == 'f ')
+
+-- A return in the scope of a to-be-closed variable isn't a tail call
+local function g()
+  local x <close> = closable('g')
+  return f()
+end
+assert(g() == 0)
+assert(logged() == 'f g ')
+
+-- Errors close the variables and pass them the error
+local co = coroutine.create(function()
+  local x <close> = closable('x')
+  local y = nil + 1
+end)
+local ok = coroutine.resume(co)
+assert(not ok)
+assert(logged() == 'x error ')
+
+-- Closing a suspended coroutine closes its variables
+local co = coroutine.create(function()
+  local x <close> = closable('outer')
+  do
+    local y <close> = closable('inner')
+    coroutine.yield()
+  end
+end)
+coroutine.resume(co)
+assert(#log == 0)
+assert(coroutine.close(co))
+assert(logged() == 'inner outer ')
+
+-- A value without a `__close` metamethod is an error
+local ok, msg = coroutine.resume(coroutine.create(function()
+  local x <close> = {}
+end))
+assert(not ok and type(msg) == 'string')
+
+-- An error in `__close` is raised once the other variables are closed
+local co = coroutine.create(function()
+  local a <close> = closable('a')
+  local b <close> = setmetatable({}, {__close = function() local z = nil + 1 end})
+end)
+local ok = coroutine.resume(co)
+assert(not ok)
+assert(logged() == 'a error ')
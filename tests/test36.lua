@@ -0,0 +1,35 @@
+-- Test function calls without parentheses
+
+local function id(x) return x end
+assert(id'a' == 'a')
+assert(id"b" == 'b')
+assert(id[[c]] == 'c')
+assert(type(id{}) == 'table')
+assert(id{1, 2, 3}[2] == 2)
+
+-- A configuration DSL
+local points = {}
+local function Point(p)
+  points[#points + 1] = p
+  return p
+end
+Point{x = 1, y = 2}
+Point {x = 3, y = 4}
+assert(#points == 2 and points[2].y == 4)
+
+-- Chained calls
+local function concat(s)
+  return function(t)
+    if type(t) == 'string' then
+      return concat(s .. t)
+    end
+    return s
+  end
+end
+assert(concat"a""b"'c'{} == 'abc')
+
+-- Methods
+local obj = {n = 10}
+function obj:add(t) return self.n + #t end
+assert(obj:add{1, 2} == 12)
+assert(obj:add'abc' == 13)